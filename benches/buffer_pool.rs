@@ -0,0 +1,34 @@
+// Copying a UDP query and serializing its response, into pooled buffers and into new ones for
+// every packet
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use fusiondns::bench_hooks::{buffer_pool, copy_and_encode};
+use trust_dns_proto::op::{Message, MessageType, Query};
+use trust_dns_proto::rr::rdata::A;
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+fn packets(c: &mut Criterion) {
+    let name = Name::from_str("host.bench.test.").unwrap();
+    let mut request = Message::new();
+    request.set_recursion_desired(true);
+    request.add_query(Query::query(name.clone(), RecordType::A));
+    let request = request.to_vec().unwrap();
+    // A response with a few addresses, as round-robin names have
+    let mut response = Message::new();
+    response.set_message_type(MessageType::Response);
+    response.add_query(Query::query(name.clone(), RecordType::A));
+    for i in 1..=4 {
+        response.add_answer(Record::from_rdata(name.clone(), 300, RData::A(A(Ipv4Addr::new(192, 0, 2, i)))));
+    }
+
+    let mut group = c.benchmark_group("copy and encode");
+    let buffers = buffer_pool(256);
+    group.bench_function("pooled", |b| b.iter(|| copy_and_encode(Some(&buffers), &request, &response).unwrap()));
+    group.bench_function("per packet", |b| b.iter(|| copy_and_encode(None, &request, &response).unwrap()));
+    group.finish();
+}
+
+criterion_group!(benches, packets);
+criterion_main!(benches);
@@ -0,0 +1,37 @@
+// Caching one more entry and writing it to disk, with the file backend (the whole cache as JSON)
+// and the sled backend (that entry alone), in caches of 10k and 100k entries
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use fusiondns::bench_hooks::{insert_and_write, json_store, sled_store};
+
+fn insert(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let directory = std::env::temp_dir().join(format!("fusiondns-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let mut group = c.benchmark_group("insert and write");
+    group.sample_size(10);
+    for entries in [10_000, 100_000] {
+        let path = directory.join(format!("{}.json", entries));
+        let store = json_store(path.to_str().unwrap(), entries);
+        let mut inserted = 0;
+        group.bench_function(BenchmarkId::new("json", entries), |b| {
+            b.iter(|| {
+                inserted += 1;
+                runtime.block_on(insert_and_write(&store, &format!("new{}.bench.test", inserted))).unwrap()
+            })
+        });
+
+        let path = directory.join(format!("{}.sled", entries));
+        let store = sled_store(path.to_str().unwrap(), entries).unwrap();
+        group.bench_function(BenchmarkId::new("sled", entries), |b| {
+            b.iter(|| {
+                inserted += 1;
+                runtime.block_on(insert_and_write(&store, &format!("new{}.bench.test", inserted))).unwrap()
+            })
+        });
+    }
+    group.finish();
+    let _ = std::fs::remove_dir_all(&directory);
+}
+
+criterion_group!(benches, insert);
+criterion_main!(benches);
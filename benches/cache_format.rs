@@ -0,0 +1,19 @@
+// Saving a 50k-entry cache file in each cache_format
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use fusiondns::bench_hooks::{file_cache, save};
+
+fn save_cache(c: &mut Criterion) {
+    let cache = file_cache(50_000);
+    let path = std::env::temp_dir().join(format!("fusiondns-bench-{}.cache", std::process::id()));
+    let path = path.to_str().unwrap();
+    let mut group = c.benchmark_group("save 50k entries");
+    group.sample_size(20);
+    for (format, binary) in [("json", false), ("binary", true)] {
+        group.bench_function(BenchmarkId::from_parameter(format), |b| b.iter(|| save(&cache, path, binary).unwrap()));
+    }
+    group.finish();
+    let _ = std::fs::remove_file(path);
+}
+
+criterion_group!(benches, save_cache);
+criterion_main!(benches);
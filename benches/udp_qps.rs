@@ -0,0 +1,127 @@
+// Queries per second over UDP, answered from the cache, with one worker and with four; eight
+// clients keep a query each in flight
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use fusiondns::{Config, DataSource, DbFuture, DnsRecord, Server};
+use serde_json::json;
+use trust_dns_proto::op::{Message, Query};
+use trust_dns_proto::rr::{Name, RecordType};
+
+// Queries answered per iteration, spread over the clients
+const QUERIES: usize = 4000;
+const CLIENTS: usize = 8;
+// Distinct names asked for, all cached after the first round
+const NAMES: usize = 1000;
+
+// Every name has the same address
+struct Addresses;
+
+impl DataSource for Addresses {
+    fn lookup<'a>(&'a self, _name: &'a str, _record_type: Option<&'a str>) -> DbFuture<'a, Vec<DnsRecord>> {
+        Box::pin(async { Ok(vec![DnsRecord::new("A", "192.0.2.1", 300)]) })
+    }
+}
+
+// A server with `workers` UDP workers on a free port, on a thread and runtime of its own
+fn start_server(workers: usize) -> SocketAddr {
+    let port = loop {
+        let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        if std::net::TcpListener::bind(("127.0.0.1", port)).is_ok() {
+            break port;
+        }
+    };
+    let config: Config = serde_json::from_value(json!({
+        "log_level": "off",
+        "upstream_dns": "127.0.0.1:9",
+        "bind_address": "127.0.0.1",
+        "port": port,
+        "cache_backend": "memory",
+        "workers": workers,
+    }))
+    .unwrap();
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        let cache_file = std::env::temp_dir().join(format!("fusiondns-bench-{}.json", port));
+        let server = Server::with_source(config, cache_file.to_str().unwrap(), Box::new(Addresses)).unwrap();
+        runtime.block_on(server.run()).unwrap();
+    });
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    // A query without a question is answered with FORMERR right away; until the server is
+    // bound, the client's socket reports the port unreachable at once instead
+    let client = client(addr);
+    for _ in 0..50 {
+        if ask(&client, &Message::new()).is_some() {
+            return addr;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    panic!("server on {} didn't start", addr);
+}
+
+fn client(server: SocketAddr) -> UdpSocket {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    socket.connect(server).unwrap();
+    socket
+}
+
+// Send `request` and wait for the response to it; None if it doesn't come
+fn ask(socket: &UdpSocket, request: &Message) -> Option<Message> {
+    socket.send(&request.to_vec().unwrap()).ok()?;
+    let mut buffer = [0u8; 4096];
+    loop {
+        let len = socket.recv(&mut buffer).ok()?;
+        let response = Message::from_vec(&buffer[..len]).unwrap();
+        if response.id() == request.id() {
+            return Some(response);
+        }
+    }
+}
+
+fn queries() -> Vec<Message> {
+    (0..NAMES)
+        .map(|i| {
+            let mut message = Message::new();
+            message.set_id(i as u16);
+            message.set_recursion_desired(true);
+            message.add_query(Query::query(Name::from_ascii(format!("host{}.bench.test.", i)).unwrap(), RecordType::A));
+            message
+        })
+        .collect()
+}
+
+fn qps(c: &mut Criterion) {
+    let queries = queries();
+    let mut group = c.benchmark_group("udp queries");
+    group.throughput(Throughput::Elements(QUERIES as u64));
+    group.sample_size(20);
+    for workers in [1, 4] {
+        let server = start_server(workers);
+        let clients: Vec<UdpSocket> = (0..CLIENTS).map(|_| client(server)).collect();
+        // Cache every name first
+        for query in &queries {
+            ask(&clients[0], query).expect("no response");
+        }
+        group.bench_function(BenchmarkId::new("workers", workers), |b| {
+            b.iter(|| {
+                std::thread::scope(|scope| {
+                    for (index, client) in clients.iter().enumerate() {
+                        let queries = &queries;
+                        scope.spawn(move || {
+                            for query in queries.iter().cycle().skip(index).step_by(CLIENTS).take(QUERIES / CLIENTS) {
+                                // A lost query is asked again
+                                while ask(client, query).is_none() {}
+                            }
+                        });
+                    }
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, qps);
+criterion_main!(benches);
@@ -0,0 +1,69 @@
+// Internals measured by the benchmarks in benches/; not part of the API
+use super::*;
+
+// A cache as the file backend holds it
+pub struct FileCache(Cache);
+
+// `entries` names with an address each
+pub fn file_cache(entries: usize) -> FileCache {
+    let mut cache = Cache::with_limits(usize::MAX, 0);
+    cache.extend((0..entries).map(|i| {
+        let address = format!("10.{}.{}.{}", i >> 16 & 255, i >> 8 & 255, i & 255);
+        (cache_key(&format!("host{}.bench.test", i), "A"), vec![DnsRecord::new("A", &address, 300)])
+    }));
+    FileCache(cache)
+}
+
+// Write the cache to `path` as the file backend saves it
+pub fn save(cache: &FileCache, path: &str, binary: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let format = if binary { CacheFormat::Binary } else { CacheFormat::Json };
+    write_atomically(path, format.encode(&cache.0)?)?;
+    Ok(())
+}
+
+// A cache backend that keeps its entries on disk
+pub struct Store(Box<dyn CacheStore>);
+
+// The file backend with `entries` entries, writing them to `path` as JSON
+pub fn json_store(path: &str, entries: usize) -> Store {
+    let FileCache(cache) = file_cache(entries);
+    Store(Box::new(FileJsonCache::new(cache, path, CacheFormat::Json)))
+}
+
+// The sled backend with `entries` entries, its database at `path`
+pub fn sled_store(path: &str, entries: usize) -> Result<Store, Box<dyn std::error::Error>> {
+    let json_path = format!("{}.json", path);
+    save(&file_cache(entries), &json_path, false)?;
+    let store = SledCache::load(path, &json_path, usize::MAX, 0)?;
+    fs::remove_file(format!("{}.imported", json_path))?;
+    Ok(Store(Box::new(store)))
+}
+
+// Cache one more entry and write it to disk, as a database answer is
+pub async fn insert_and_write(store: &Store, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    store.0.insert(cache_key(name, "A"), vec![DnsRecord::new("A", "192.0.2.1", 300)]).await;
+    store.0.write().await
+}
+
+// The packet buffers UDP queries use, keeping up to `size` of them
+pub struct Buffers(BufferPool);
+
+pub fn buffer_pool(size: usize) -> Buffers {
+    Buffers(BufferPool::new(size))
+}
+
+// Copy `request` and serialize `response` as a UDP query does: into buffers from `buffers`,
+// which get them back after, or into new ones without it. Returns the bytes encoded
+pub fn copy_and_encode(buffers: Option<&Buffers>, request: &[u8], response: &Message) -> Result<usize, Box<dyn std::error::Error>> {
+    let Some(Buffers(pool)) = buffers else {
+        let copy = request.to_vec();
+        return Ok(copy.len() + response.to_vec()?.len());
+    };
+    let mut copy = pool.take();
+    copy.extend_from_slice(request);
+    let encoded = pool.encode(response)?;
+    let len = copy.len() + encoded.len();
+    pool.give(encoded);
+    pool.give(copy);
+    Ok(len)
+}
@@ -0,0 +1,1550 @@
+use super::*;
+
+// Layout of the cache file, stored in it so a later change can migrate older files rather than
+// discard them. Files from before the field existed read as version 0
+pub(crate) const CACHE_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Cache {
+    #[serde(default)]
+    schema_version: u32,
+    // Every record of a name and type under one key, so a cache hit answers in full
+    #[serde(deserialize_with = "records_by_key")]
+    records: HashMap<String, Vec<DnsRecord>>,
+    // The keys of each name, so a name's types are found without going through every key
+    #[serde(skip)]
+    names: HashMap<String, HashSet<String>>,
+    // How each key has been used since it was inserted or loaded; atomics so reads under the
+    // shared lock can update them. Entries loaded from the file start out least recent
+    #[serde(skip)]
+    usage: HashMap<String, Usage>,
+    #[serde(skip)]
+    clock: AtomicU64,
+    // Most keys kept before the least recently used are evicted
+    #[serde(skip)]
+    max_entries: usize,
+    // Approximate size of all entries (see entry_size) and the most kept, 0 for no limit
+    #[serde(skip)]
+    bytes: usize,
+    #[serde(skip)]
+    max_bytes: usize,
+    #[serde(skip)]
+    evictions: u64,
+    // Keys removed since they were last taken, for a backend that keeps the cache elsewhere too
+    // (see SledCache); None when none does
+    #[serde(skip)]
+    removed: Option<Vec<String>>,
+}
+
+// Bytes counted for each key and each record on top of their strings. Enough to cover the map and
+// list overhead in memory as well as the field names and indentation in the pretty-printed file
+pub(crate) const CACHE_KEY_OVERHEAD: usize = 64;
+
+pub(crate) const CACHE_RECORD_OVERHEAD: usize = 160;
+
+// Approximate bytes a cache entry takes, in memory and in the cache file
+pub(crate) fn entry_size(key: &str, records: &[DnsRecord]) -> usize {
+    CACHE_KEY_OVERHEAD
+        + key.len()
+        + records
+            .iter()
+            .map(|record| CACHE_RECORD_OVERHEAD + record.record_type.len() + record.value.len())
+            .sum::<usize>()
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Usage {
+    // When the key was last inserted or answered from, as a tick of Cache::clock
+    last_used: AtomicU64,
+    hits: AtomicU64,
+    // Set once the key has been queued for prefetching
+    prefetching: AtomicBool,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache {
+            schema_version: CACHE_SCHEMA_VERSION,
+            records: HashMap::new(),
+            names: HashMap::new(),
+            usage: HashMap::new(),
+            clock: AtomicU64::new(0),
+            max_entries: 0,
+            bytes: 0,
+            max_bytes: 0,
+            evictions: 0,
+            removed: None,
+        }
+    }
+}
+
+impl Cache {
+    // An empty cache that keeps at most `max_entries` keys and `max_bytes` bytes (0 for no limit)
+    pub(crate) fn with_limits(max_entries: usize, max_bytes: usize) -> Self {
+        Cache { max_entries, max_bytes, ..Cache::default() }
+    }
+
+    // The file may be JSON or binary (see CacheFormat), told apart by CACHE_MAGIC. A file that
+    // doesn't parse is moved aside to `<path>.corrupt-<unix time>` rather than
+    // overwritten, and the entries that can still be read from it are kept; the cache then fills
+    // again from the database. A file from a newer version is moved aside the same way
+    fn load(path: &str, max_entries: usize, max_bytes: usize) -> Self {
+        let mut cache = match fs::read(path) {
+            Ok(content) if content.starts_with(CACHE_MAGIC) => match decode_cache(&content) {
+                (version, _, _) if version > CACHE_SCHEMA_VERSION => {
+                    error!("Cache file {} is in format version {}, newer than this version's {}", path, version, CACHE_SCHEMA_VERSION);
+                    move_aside_corrupt(path);
+                    Cache::default()
+                }
+                (version, records, Ok(())) => Cache { schema_version: version, records, ..Cache::default() }.migrate(),
+                (_, records, Err(e)) => {
+                    error!("Cache file {} is corrupt: {}", path, e);
+                    move_aside_corrupt(path);
+                    if !records.is_empty() {
+                        warn!("Recovered {} cache entries from the corrupt file {}", records.len(), path);
+                    }
+                    Cache { records, ..Cache::default() }
+                }
+            },
+            Ok(content) => match serde_json::from_slice::<Cache>(&content) {
+                Ok(cache) if cache.schema_version > CACHE_SCHEMA_VERSION => {
+                    error!(
+                        "Cache file {} is in format version {}, newer than this version's {}",
+                        path, cache.schema_version, CACHE_SCHEMA_VERSION
+                    );
+                    move_aside_corrupt(path);
+                    Cache::default()
+                }
+                Ok(cache) => cache.migrate(),
+                Err(e) => {
+                    error!("Cache file {} is corrupt: {}", path, e);
+                    move_aside_corrupt(path);
+                    let records = recover_cache_entries(&String::from_utf8_lossy(&content));
+                    if !records.is_empty() {
+                        warn!("Recovered {} cache entries from the corrupt file {}", records.len(), path);
+                    }
+                    Cache { records, ..Cache::default() }
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Cache::default(),
+            Err(e) => {
+                warn!("Can't read cache file {}, starting with an empty cache: {}", path, e);
+                Cache::default()
+            }
+        };
+        cache.usage = cache.records.keys().map(|key| (key.clone(), Usage::default())).collect();
+        let keys: Vec<String> = cache.records.keys().cloned().collect();
+        keys.into_iter().for_each(|key| cache.index(key));
+        cache.bytes = cache.records.iter().map(|(key, records)| entry_size(key, records)).sum();
+        cache.max_entries = max_entries;
+        cache.max_bytes = max_bytes;
+        cache.evict();
+        cache
+    }
+
+    // Bring a cache read from an older file up to CACHE_SCHEMA_VERSION
+    fn migrate(mut self) -> Self {
+        // Version 0 files, from before the version was stored, have the same layout as version 1;
+        // single records they hold under a key were read as one-entry lists
+        self.schema_version = CACHE_SCHEMA_VERSION;
+        self
+    }
+
+    fn get(&self, key: &str) -> Option<Vec<DnsRecord>> {
+        let now = now_secs();
+        let records = self
+            .records
+            .get(key)
+            .filter(|records| !records.iter().any(|record| record.is_expired(now)))
+            .cloned()?;
+        if let Some(usage) = self.usage.get(key) {
+            usage.last_used.store(self.clock.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+            usage.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        Some(records)
+    }
+
+    // Whether `key` should be refreshed before it expires: it was answered from more than
+    // `min_hits` times since it was cached, is in the last 10% of its TTL and isn't queued yet.
+    // Claims it, so it is only queued once per insert
+    fn claim_prefetch(&self, key: &str, min_hits: u64) -> bool {
+        let (Some(records), Some(usage)) = (self.records.get(key), self.usage.get(key)) else { return false };
+        let now = now_secs();
+        let due = records.iter().any(|record| {
+            record.expires_at().is_some_and(|expires_at| now < expires_at && (expires_at - now) * 10 <= record.ttl as u64)
+        });
+        due && usage.hits.load(Ordering::Relaxed) > min_hits && !usage.prefetching.swap(true, Ordering::Relaxed)
+    }
+
+    fn insert(&mut self, key: String, records: Vec<DnsRecord>) {
+        self.extend([(key, records)]);
+    }
+
+    pub(crate) fn extend(&mut self, entries: impl IntoIterator<Item = (String, Vec<DnsRecord>)>) {
+        for (key, records) in entries {
+            let tick = self.clock.fetch_add(1, Ordering::Relaxed);
+            self.usage.insert(key.clone(), Usage { last_used: AtomicU64::new(tick), ..Usage::default() });
+            if let Some(replaced) = self.records.get(&key) {
+                self.bytes -= entry_size(&key, replaced);
+            }
+            self.bytes += entry_size(&key, &records);
+            self.index(key.clone());
+            self.records.insert(key, records);
+        }
+        self.evict();
+    }
+
+    // What the cache file holds, with the order the keys were used in, so the file can be encoded
+    // and kept within max_bytes without holding the cache lock. Keys it evicts are recorded in
+    // `removed`
+    fn snapshot(&self) -> Cache {
+        let usage = self
+            .usage
+            .iter()
+            .map(|(key, usage)| (key.clone(), Usage { last_used: AtomicU64::new(usage.last_used.load(Ordering::Relaxed)), ..Usage::default() }))
+            .collect();
+        Cache {
+            schema_version: self.schema_version,
+            records: self.records.clone(),
+            usage,
+            bytes: self.bytes,
+            max_bytes: self.max_bytes,
+            removed: Some(Vec::new()),
+            ..Cache::default()
+        }
+    }
+
+    fn over(&self, max_entries: usize, max_bytes: usize) -> bool {
+        self.records.len() > max_entries || (self.max_bytes > 0 && self.bytes > max_bytes)
+    }
+
+    // Over max_entries or max_bytes, drop expired entries first and then the least recently used.
+    // Enough go at once to get 1/16 below the limits, so a full cache doesn't search for a victim
+    // on every insert
+    fn evict(&mut self) {
+        if !self.over(self.max_entries, self.max_bytes) {
+            return;
+        }
+        let expired = self.remove_expired(0);
+        let unused = self.remove_least_recent(self.max_entries - self.max_entries / 16, self.max_bytes - self.max_bytes / 16);
+        self.evictions += (expired + unused) as u64;
+        info!(
+            "Cache over {} entries or {} bytes: evicted {} expired and {} least recently used, now {} entries of {} bytes ({} evictions since startup)",
+            self.max_entries,
+            self.max_bytes,
+            expired,
+            unused,
+            self.records.len(),
+            self.bytes,
+            self.evictions
+        );
+    }
+
+    // Remove the least recently used keys until at most `max_entries` and `max_bytes` are left;
+    // returns how many went
+    fn remove_least_recent(&mut self, max_entries: usize, max_bytes: usize) -> usize {
+        if !self.over(max_entries, max_bytes) {
+            return 0;
+        }
+        let mut by_use: Vec<(u64, String)> = self
+            .records
+            .keys()
+            .map(|key| (self.usage.get(key).map_or(0, |usage| usage.last_used.load(Ordering::Relaxed)), key.clone()))
+            .collect();
+        by_use.sort_unstable();
+        let mut removed = 0;
+        for (_, key) in by_use {
+            if !self.over(max_entries, max_bytes) {
+                break;
+            }
+            self.remove_key(&key);
+            removed += 1;
+        }
+        removed
+    }
+
+    fn index(&mut self, key: String) {
+        self.names.entry(name_of(&key).to_string()).or_default().insert(key);
+    }
+
+    fn unindex(&mut self, key: &str) {
+        let name = name_of(key);
+        if let Some(keys) = self.names.get_mut(name) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.names.remove(name);
+            }
+        }
+    }
+
+    fn remove_key(&mut self, key: &str) -> bool {
+        self.usage.remove(key);
+        self.unindex(key);
+        match self.records.remove(key) {
+            Some(records) => {
+                self.bytes -= entry_size(key, &records);
+                if let Some(removed) = &mut self.removed {
+                    removed.push(key.to_string());
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Keep the entries `keep` picks; returns how many keys went
+    fn retain(&mut self, keep: impl Fn(&str, &[DnsRecord]) -> bool) -> usize {
+        let mut removed = Vec::new();
+        self.records.retain(|key, records| {
+            let kept = keep(key, records);
+            if !kept {
+                removed.push((key.clone(), entry_size(key, records)));
+            }
+            kept
+        });
+        for (key, size) in &removed {
+            self.bytes -= size;
+            self.usage.remove(key);
+            self.unindex(key);
+        }
+        let count = removed.len();
+        if let Some(tracked) = &mut self.removed {
+            tracked.extend(removed.into_iter().map(|(key, _)| key));
+        }
+        count
+    }
+
+    // Entries under `key` even when they have expired, as long as that was at most `max_age`
+    // seconds ago
+    fn get_stale(&self, key: &str, max_age: u64) -> Option<Vec<DnsRecord>> {
+        let then = now_secs().saturating_sub(max_age);
+        self.records
+            .get(key)
+            .filter(|records| !records.iter().any(|record| record.is_expired(then)))
+            .cloned()
+    }
+
+    // Whether the name is cached as existing (any live entry other than NXDOMAIN)
+    fn has_name(&self, name: &str) -> bool {
+        let now = now_secs();
+        self.names.get(name).into_iter().flatten().filter_map(|key| self.records.get(key)).any(|records| {
+            records
+                .iter()
+                .any(|record| record.record_type != "NXDOMAIN" && !record.is_expired(now))
+        })
+    }
+
+    // Remove every cached type for a name
+    fn remove_name(&mut self, name: &str) {
+        for key in self.names.get(name).cloned().unwrap_or_default() {
+            self.remove_key(&key);
+        }
+    }
+
+    // Remove every key whose name `flushed` picks; returns how many went
+    fn remove_matching(&mut self, flushed: impl Fn(&str) -> bool) -> usize {
+        self.retain(|key, _| !flushed(key.rsplit_once(':').map_or(key, |(name, _)| name)))
+    }
+
+    // Drop entries that expired more than `grace` seconds ago; returns how many keys went
+    fn remove_expired(&mut self, grace: u64) -> usize {
+        let then = now_secs().saturating_sub(grace);
+        self.retain(|_, records| !records.iter().any(|record| record.is_expired(then)))
+    }
+}
+
+// Entries under a cache key as stored in the file: a list, or a single record in files written
+// when a key held one record
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum StoredRecords {
+    One(DnsRecord),
+    More(Vec<DnsRecord>),
+}
+
+impl StoredRecords {
+    fn into_vec(self) -> Vec<DnsRecord> {
+        match self {
+            StoredRecords::One(record) => vec![record],
+            StoredRecords::More(records) => records,
+        }
+    }
+}
+
+pub(crate) fn records_by_key<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<HashMap<String, Vec<DnsRecord>>, D::Error> {
+    let stored = HashMap::<String, StoredRecords>::deserialize(deserializer)?;
+    Ok(stored.into_iter().map(|(key, records)| (key, records.into_vec())).collect())
+}
+
+// Rename a cache file that can't be used to `<path>.corrupt-<unix time>`, so it is kept for a
+// look but not overwritten
+pub(crate) fn move_aside_corrupt(path: &str) {
+    let corrupt = format!("{}.corrupt-{}", path, now_secs());
+    match fs::rename(path, &corrupt) {
+        Ok(()) => warn!("Moved cache file {} to {}", path, corrupt),
+        Err(e) => warn!("Couldn't move cache file {} to {}: {}", path, corrupt, e),
+    }
+}
+
+// The entries of a damaged cache file that still parse. Goes by the layout the cache is written
+// in: each key starts a line indented four spaces, and its list (or single record) ends on that
+// line or on a line "    ]" (or "    }"). Entries cut short or garbled, and keys from before entries were keyed by name and
+// type, are skipped
+pub(crate) fn recover_cache_entries(content: &str) -> HashMap<String, Vec<DnsRecord>> {
+    let mut recovered = HashMap::new();
+    let mut current: Option<(String, String)> = None;
+    for line in content.lines() {
+        if line.starts_with("    \"") {
+            current = line[4..].split_once("\": ").and_then(|(key, value)| {
+                let key = serde_json::from_str::<String>(&format!("{}\"", key)).ok()?;
+                key.contains(':').then(|| (key, value.to_string()))
+            });
+        } else if let Some((_, value)) = &mut current {
+            value.push_str(line);
+        }
+        let complete = |(_, value): &mut (String, String)| {
+            value.trim_end().trim_end_matches(',') == "[]" || line.starts_with("    ]") || line.starts_with("    }")
+        };
+        if let Some((key, value)) = current.take_if(complete) {
+            if let Ok(records) = serde_json::from_str::<StoredRecords>(value.trim_end().trim_end_matches(',')) {
+                recovered.insert(key, records.into_vec());
+            }
+        }
+    }
+    recovered
+}
+
+pub(crate) type CacheFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// Where the cache of database entries lives, shared by all handlers. Entries carry their own
+// inserted_at and TTL, so every backend can expire and evict them by its own rules; lookups only
+// ever see this trait. Keys are cache_key() strings, names are lookup names.
+pub(crate) trait CacheStore: Send + Sync {
+    // Live entries under `key`; a hit counts as a use of the key for eviction and prefetching
+    fn get<'a>(&'a self, key: &'a str) -> CacheFuture<'a, Option<Vec<DnsRecord>>>;
+
+    // Entries under `key` even when they have expired, as long as that was at most `max_age`
+    // seconds ago
+    fn get_stale<'a>(&'a self, key: &'a str, max_age: u64) -> CacheFuture<'a, Option<Vec<DnsRecord>>>;
+
+    fn insert(&self, key: String, records: Vec<DnsRecord>) -> CacheFuture<'_, ()>;
+
+    // Replace every cached type for a name at once, so no reader sees it half updated
+    fn replace_name<'a>(&'a self, name: &'a str, entries: HashMap<String, Vec<DnsRecord>>) -> CacheFuture<'a, ()>;
+
+    // Remove every cached type for a name
+    fn remove_name<'a>(&'a self, name: &'a str) -> CacheFuture<'a, ()>;
+
+    // Whether the name is cached as existing (any live entry other than NXDOMAIN)
+    fn has_name<'a>(&'a self, name: &'a str) -> CacheFuture<'a, bool>;
+
+    // Remove every key whose name `flushed` picks; returns how many went
+    fn remove_matching<'a>(&'a self, flushed: &'a (dyn Fn(&str) -> bool + Sync)) -> CacheFuture<'a, usize>;
+
+    // Drop entries that expired more than `grace` seconds ago; returns how many keys went
+    fn remove_expired(&self, grace: u64) -> CacheFuture<'_, usize>;
+
+    // The first of `keys` that is cached, if it is due for prefetching (see Cache::claim_prefetch)
+    fn claim_prefetch<'a>(&'a self, keys: &'a [String], min_hits: u64) -> CacheFuture<'a, bool>;
+
+    // Every key with its entries and hit count, or only the keys of one name
+    fn entries<'a>(&'a self, _name: Option<&'a str>) -> CacheFuture<'a, Vec<(String, Vec<DnsRecord>, u64)>> {
+        Box::pin(async { Vec::new() })
+    }
+
+    // Entries and their approximate bytes, for backends that keep them in this process
+    fn size(&self) -> CacheFuture<'_, Option<(usize, usize)>> {
+        Box::pin(async { None })
+    }
+
+    // Note that the cache changed, for backends that persist it
+    fn save(&self) -> CacheFuture<'_, ()> {
+        Box::pin(async {})
+    }
+
+    // Persist the cache now
+    fn write(&self) -> CacheFuture<'_, Result<(), Box<dyn std::error::Error>>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    // Background work of the backend, run for as long as the server is
+    fn write_back(&self, _interval: Duration) -> CacheFuture<'_, Result<(), Box<dyn std::error::Error>>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    // Entries under the first of `keys` that is cached
+    fn get_any<'a>(&'a self, keys: &'a [String]) -> CacheFuture<'a, Option<Vec<DnsRecord>>> {
+        Box::pin(async move {
+            for key in keys {
+                if let Some(records) = self.get(key).await {
+                    return Some(records);
+                }
+            }
+            None
+        })
+    }
+
+    // Like get_any, but entries that expired at most `max_age` seconds ago count too
+    fn get_any_stale<'a>(&'a self, keys: &'a [String], max_age: u64) -> CacheFuture<'a, Option<Vec<DnsRecord>>> {
+        Box::pin(async move {
+            for key in keys {
+                if let Some(records) = self.get_stale(key, max_age).await {
+                    return Some(records);
+                }
+            }
+            None
+        })
+    }
+
+    // Drop entries expired more than `grace` seconds ago and save the cache if any went
+    fn sweep(&self, grace: u64) -> CacheFuture<'_, ()> {
+        Box::pin(async move {
+            let removed = self.remove_expired(grace).await;
+            if removed > 0 {
+                debug!("Removed {} expired cache entries", removed);
+                self.save().await;
+            }
+        })
+    }
+}
+
+// How the file backend writes dns_cache.json; either is read back whatever this says
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CacheFormat {
+    // Pretty-printed JSON
+    #[default]
+    Json,
+    // CACHE_MAGIC followed by bincode (see encode_cache)
+    Binary,
+}
+
+impl CacheFormat {
+    pub(crate) fn encode(self, cache: &Cache) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        match self {
+            CacheFormat::Json => Ok(serde_json::to_vec_pretty(cache)?),
+            CacheFormat::Binary => Ok(encode_cache(cache)?),
+        }
+    }
+}
+
+// Start of a binary cache file; JSON files start with "{"
+pub(crate) const CACHE_MAGIC: &[u8; 8] = b"FDNSBIN\x01";
+
+// A record in the binary cache file. bincode can't read back the fields DnsRecord leaves out of
+// JSON when they are empty, so every field is written
+#[derive(Serialize, Deserialize)]
+pub(crate) struct BinaryRecord {
+    record_type: String,
+    value: String,
+    ttl: u32,
+    weight: Option<u32>,
+    inserted_at: Option<u64>,
+}
+
+impl From<&DnsRecord> for BinaryRecord {
+    fn from(record: &DnsRecord) -> Self {
+        BinaryRecord {
+            record_type: record.record_type.clone(),
+            value: record.value.clone(),
+            ttl: record.ttl,
+            weight: record.weight,
+            inserted_at: record.inserted_at,
+        }
+    }
+}
+
+impl From<BinaryRecord> for DnsRecord {
+    fn from(record: BinaryRecord) -> Self {
+        DnsRecord {
+            record_type: record.record_type,
+            value: record.value,
+            ttl: record.ttl,
+            weight: record.weight,
+            inserted_at: record.inserted_at,
+        }
+    }
+}
+
+// The binary cache file: CACHE_MAGIC, then in bincode the schema version and the number of keys,
+// and each key with its records after that. Entries are encoded one at a time, so those before
+// any damage can still be read
+pub(crate) fn encode_cache(cache: &Cache) -> bincode::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(cache.bytes / 2);
+    out.extend_from_slice(CACHE_MAGIC);
+    bincode::serialize_into(&mut out, &(CACHE_SCHEMA_VERSION, cache.records.len() as u64))?;
+    for (key, records) in &cache.records {
+        let records: Vec<BinaryRecord> = records.iter().map(BinaryRecord::from).collect();
+        bincode::serialize_into(&mut out, &(key, records))?;
+    }
+    Ok(out)
+}
+
+// Read a binary cache file: its schema version and entries. An error comes with the entries
+// read before it
+pub(crate) fn decode_cache(data: &[u8]) -> (u32, HashMap<String, Vec<DnsRecord>>, Result<(), String>) {
+    use bincode::Options;
+    // The same encoding as bincode::serialize, with lengths bounded by the file so a damaged one
+    // can't ask for more memory than that
+    let options = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(data.len() as u64);
+    let mut reader = data.get(CACHE_MAGIC.len()..).unwrap_or_default();
+    let mut records = HashMap::new();
+    let (version, count) = match options.deserialize_from::<_, (u32, u64)>(&mut reader) {
+        Ok(header) => header,
+        Err(e) => return (0, records, Err(e.to_string())),
+    };
+    if version > CACHE_SCHEMA_VERSION {
+        return (version, records, Ok(()));
+    }
+    for _ in 0..count {
+        match options.deserialize_from::<_, (String, Vec<BinaryRecord>)>(&mut reader) {
+            Ok((key, entries)) => {
+                records.insert(key, entries.into_iter().map(DnsRecord::from).collect());
+            }
+            Err(e) => return (version, records, Err(e.to_string())),
+        }
+    }
+    let result = if reader.is_empty() { Ok(()) } else { Err("data after the last entry".to_string()) };
+    (version, records, result)
+}
+
+// Which CacheStore holds the cache
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CacheBackend {
+    // In memory, saved to dns_cache.json and loaded from it at startup
+    #[default]
+    File,
+    // In memory only, empty at every start
+    Memory,
+    // In Redis, shared by every instance using the same server and key prefix
+    Redis,
+    // In memory, with each change written to the sled database dns_cache.sled
+    Sled,
+}
+
+// The cache in memory. Every call holds the lock for the map operation alone, never across a
+// database or upstream round trip
+pub(crate) struct MemoryCache {
+    cache: RwLock<Cache>,
+}
+
+impl MemoryCache {
+    pub(crate) fn new(cache: Cache) -> Self {
+        MemoryCache { cache: RwLock::new(cache) }
+    }
+}
+
+impl CacheStore for MemoryCache {
+    fn get<'a>(&'a self, key: &'a str) -> CacheFuture<'a, Option<Vec<DnsRecord>>> {
+        Box::pin(async move { self.cache.read().await.get(key) })
+    }
+
+    fn get_stale<'a>(&'a self, key: &'a str, max_age: u64) -> CacheFuture<'a, Option<Vec<DnsRecord>>> {
+        Box::pin(async move { self.cache.read().await.get_stale(key, max_age) })
+    }
+
+    fn insert(&self, key: String, records: Vec<DnsRecord>) -> CacheFuture<'_, ()> {
+        Box::pin(async move { self.cache.write().await.insert(key, records) })
+    }
+
+    fn replace_name<'a>(&'a self, name: &'a str, entries: HashMap<String, Vec<DnsRecord>>) -> CacheFuture<'a, ()> {
+        Box::pin(async move {
+            let mut cache = self.cache.write().await;
+            cache.remove_name(name);
+            cache.extend(entries);
+        })
+    }
+
+    fn remove_name<'a>(&'a self, name: &'a str) -> CacheFuture<'a, ()> {
+        Box::pin(async move { self.cache.write().await.remove_name(name) })
+    }
+
+    fn has_name<'a>(&'a self, name: &'a str) -> CacheFuture<'a, bool> {
+        Box::pin(async move { self.cache.read().await.has_name(name) })
+    }
+
+    fn remove_matching<'a>(&'a self, flushed: &'a (dyn Fn(&str) -> bool + Sync)) -> CacheFuture<'a, usize> {
+        Box::pin(async move { self.cache.write().await.remove_matching(flushed) })
+    }
+
+    fn remove_expired(&self, grace: u64) -> CacheFuture<'_, usize> {
+        Box::pin(async move { self.cache.write().await.remove_expired(grace) })
+    }
+
+    fn size(&self) -> CacheFuture<'_, Option<(usize, usize)>> {
+        Box::pin(async move {
+            let cache = self.cache.read().await;
+            Some((cache.records.len(), cache.bytes))
+        })
+    }
+
+    fn entries<'a>(&'a self, name: Option<&'a str>) -> CacheFuture<'a, Vec<(String, Vec<DnsRecord>, u64)>> {
+        Box::pin(async move {
+            let cache = self.cache.read().await;
+            cache
+                .records
+                .iter()
+                .filter(|(key, _)| name.is_none_or(|name| key.rsplit_once(':').map(|(n, _)| n) == Some(name)))
+                .map(|(key, records)| {
+                    let hits = cache.usage.get(key).map_or(0, |usage| usage.hits.load(Ordering::Relaxed));
+                    (key.clone(), records.clone(), hits)
+                })
+                .collect()
+        })
+    }
+
+    fn claim_prefetch<'a>(&'a self, keys: &'a [String], min_hits: u64) -> CacheFuture<'a, bool> {
+        Box::pin(async move {
+            let cache = self.cache.read().await;
+            let now = now_secs();
+            keys.iter()
+                .find(|key| cache.records.get(*key).is_some_and(|records| !records.iter().any(|record| record.is_expired(now))))
+                .is_some_and(|key| cache.claim_prefetch(key, min_hits))
+        })
+    }
+}
+
+// The cache in memory, written back to a JSON (or binary) file by a single writer
+pub(crate) struct FileJsonCache {
+    memory: MemoryCache,
+    path: Arc<str>,
+    format: CacheFormat,
+    // Signals write_back that the cache changed since it last wrote the file
+    changed: Notify,
+}
+
+impl FileJsonCache {
+    pub(crate) fn load(path: &str, format: CacheFormat, max_entries: usize, max_bytes: usize) -> Self {
+        Self::new(Cache::load(path, max_entries, max_bytes), path, format)
+    }
+
+    // `cache` written back to `path`, whatever the file holds now
+    pub(crate) fn new(cache: Cache, path: &str, format: CacheFormat) -> Self {
+        FileJsonCache { memory: MemoryCache::new(cache), path: path.into(), format, changed: Notify::new() }
+    }
+}
+
+impl CacheStore for FileJsonCache {
+    fn get<'a>(&'a self, key: &'a str) -> CacheFuture<'a, Option<Vec<DnsRecord>>> {
+        self.memory.get(key)
+    }
+
+    fn get_stale<'a>(&'a self, key: &'a str, max_age: u64) -> CacheFuture<'a, Option<Vec<DnsRecord>>> {
+        self.memory.get_stale(key, max_age)
+    }
+
+    fn insert(&self, key: String, records: Vec<DnsRecord>) -> CacheFuture<'_, ()> {
+        self.memory.insert(key, records)
+    }
+
+    fn replace_name<'a>(&'a self, name: &'a str, entries: HashMap<String, Vec<DnsRecord>>) -> CacheFuture<'a, ()> {
+        self.memory.replace_name(name, entries)
+    }
+
+    fn remove_name<'a>(&'a self, name: &'a str) -> CacheFuture<'a, ()> {
+        self.memory.remove_name(name)
+    }
+
+    fn has_name<'a>(&'a self, name: &'a str) -> CacheFuture<'a, bool> {
+        self.memory.has_name(name)
+    }
+
+    fn remove_matching<'a>(&'a self, flushed: &'a (dyn Fn(&str) -> bool + Sync)) -> CacheFuture<'a, usize> {
+        self.memory.remove_matching(flushed)
+    }
+
+    fn remove_expired(&self, grace: u64) -> CacheFuture<'_, usize> {
+        self.memory.remove_expired(grace)
+    }
+
+    fn claim_prefetch<'a>(&'a self, keys: &'a [String], min_hits: u64) -> CacheFuture<'a, bool> {
+        self.memory.claim_prefetch(keys, min_hits)
+    }
+
+    fn size(&self) -> CacheFuture<'_, Option<(usize, usize)>> {
+        self.memory.size()
+    }
+
+    fn entries<'a>(&'a self, name: Option<&'a str>) -> CacheFuture<'a, Vec<(String, Vec<DnsRecord>, u64)>> {
+        self.memory.entries(name)
+    }
+
+    // Have write_back write the cache to its file
+    fn save(&self) -> CacheFuture<'_, ()> {
+        self.changed.notify_one();
+        Box::pin(async {})
+    }
+
+    // Write the cache to its file now, off the async worker threads. The cache is only copied
+    // under its lock; encoding it and keeping the file within max_bytes happen on a blocking thread
+    fn write(&self) -> CacheFuture<'_, Result<(), Box<dyn std::error::Error>>> {
+        Box::pin(async move {
+            let mut snapshot = self.memory.cache.read().await.snapshot();
+            let format = self.format;
+            let encoded = tokio::task::spawn_blocking(move || -> Result<_, String> {
+                let mut content = format.encode(&snapshot).map_err(|e| e.to_string())?;
+                while snapshot.max_bytes > 0 && content.len() > snapshot.max_bytes {
+                    // entry_size fell short of what the entries take in the file; evict the
+                    // difference so the file stays within max_bytes
+                    let target = snapshot.bytes.saturating_sub(content.len() - snapshot.max_bytes);
+                    let removed = snapshot.remove_least_recent(usize::MAX, target);
+                    warn!("Cache file would take {} bytes, over {}; evicting {} entries", content.len(), snapshot.max_bytes, removed);
+                    if removed == 0 {
+                        return Ok(None);
+                    }
+                    content = format.encode(&snapshot).map_err(|e| e.to_string())?;
+                }
+                Ok(Some((content, snapshot.removed.unwrap_or_default())))
+            });
+            let Some((content, evicted)) = encoded.await?? else { return Ok(()) };
+            if !evicted.is_empty() {
+                let mut cache = self.memory.cache.write().await;
+                for key in &evicted {
+                    cache.remove_key(key);
+                }
+            }
+            let path = self.path.clone();
+            if let Err(e) = tokio::task::spawn_blocking(move || write_atomically(&path, &content)).await? {
+                warn!("Failed to save cache to {}: {}", self.path, e);
+            }
+            Ok(())
+        })
+    }
+
+    // The single writer of the cache file: writes it after a save(), then waits `interval` before
+    // the next write, so saves made meanwhile (one per database lookup under load) are folded
+    // into one write and writes never interleave
+    fn write_back(&self, interval: Duration) -> CacheFuture<'_, Result<(), Box<dyn std::error::Error>>> {
+        Box::pin(async move {
+            loop {
+                self.changed.notified().await;
+                self.write().await?;
+                tokio::time::sleep(interval).await;
+            }
+        })
+    }
+}
+
+// The cache in memory, with every change also written to a sled database (an embedded,
+// crash-safe key-value store) under its cache key, the records encoded with bincode. A write
+// touches only the keys that changed since the last one, however large the cache is, and keys
+// that are evicted or expire go from the database as they go from memory.
+pub(crate) struct SledCache {
+    memory: MemoryCache,
+    db: sled::Db,
+    path: Arc<str>,
+    // What every key changed since the last write now holds, None once removed. A failed write
+    // puts its changes back, unless the key changed again meanwhile
+    pending: std::sync::Mutex<HashMap<String, Option<Vec<DnsRecord>>>>,
+    changed: Notify,
+    // Held by the write running, so writes don't overtake each other
+    writing: Mutex<()>,
+}
+
+impl SledCache {
+    // Open the database at `path` and load its entries. While it is empty, the entries of the JSON
+    // cache file `json_path` are imported once and the file renamed to `<json_path>.imported`
+    pub(crate) fn load(path: &str, json_path: &str, max_entries: usize, max_bytes: usize) -> Result<Self, Box<dyn std::error::Error>> {
+        // Writes flush themselves, so sled runs no flusher thread, which would also keep the
+        // database locked for a while after it is closed
+        let db = sled::Config::new()
+            .path(path)
+            .flush_every_ms(None)
+            .open()
+            .map_err(|e| format!("can't open the cache database {}: {}", path, e))?;
+        let imported = db.is_empty() && std::path::Path::new(json_path).exists();
+        let mut cache = if imported {
+            Cache::load(json_path, max_entries, max_bytes)
+        } else {
+            let mut records = HashMap::new();
+            for item in db.iter() {
+                let (key, value) = item?;
+                match (std::str::from_utf8(&key), bincode::deserialize::<Vec<BinaryRecord>>(&value)) {
+                    (Ok(key), Ok(entries)) => {
+                        records.insert(key.to_string(), entries.into_iter().map(DnsRecord::from).collect());
+                    }
+                    _ => {
+                        warn!("Dropping an unreadable entry from the cache database {}", path);
+                        db.remove(&key)?;
+                    }
+                }
+            }
+            let mut cache = Cache { max_entries, max_bytes, removed: Some(Vec::new()), ..Cache::default() };
+            cache.extend(records);
+            cache
+        };
+
+        // Imported entries go into the database, and entries evicted on the way in out of it
+        let keys: Vec<String> = if imported { cache.records.keys().cloned().collect() } else { Vec::new() };
+        cache.removed.get_or_insert_with(Vec::new);
+        Self::apply(&db, Self::changes(&mut cache, keys))?;
+        db.flush()?;
+        let loaded = cache.records.len();
+
+        // An unreadable file was already moved aside by Cache::load
+        if imported && std::path::Path::new(json_path).exists() {
+            let done = format!("{}.imported", json_path);
+            fs::rename(json_path, &done)?;
+            info!("Imported {} cache entries from {} into {}, moved it to {}", loaded, json_path, path, done);
+        }
+        info!("Loaded {} cache entries from the cache database {}", loaded, path);
+        Ok(SledCache {
+            memory: MemoryCache::new(cache),
+            db,
+            path: path.into(),
+            pending: std::sync::Mutex::new(HashMap::new()),
+            changed: Notify::new(),
+            writing: Mutex::new(()),
+        })
+    }
+
+    // What `keys` and the keys the cache removed on its own since the last call now hold, None
+    // for the ones that are gone
+    fn changes(cache: &mut Cache, keys: impl IntoIterator<Item = String>) -> HashMap<String, Option<Vec<DnsRecord>>> {
+        let removed = cache.removed.as_mut().map(std::mem::take).unwrap_or_default();
+        keys.into_iter()
+            .chain(removed)
+            .map(|key| {
+                let records = cache.records.get(&key).cloned();
+                (key, records)
+            })
+            .collect()
+    }
+
+    // Write changes to the database all at once
+    fn apply(db: &sled::Db, changes: HashMap<String, Option<Vec<DnsRecord>>>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut batch = sled::Batch::default();
+        for (key, records) in changes {
+            match records {
+                Some(records) => {
+                    let records: Vec<BinaryRecord> = records.iter().map(BinaryRecord::from).collect();
+                    batch.insert(key.as_bytes(), bincode::serialize(&records)?);
+                }
+                None => batch.remove(key.as_bytes()),
+            }
+        }
+        Ok(db.apply_batch(batch)?)
+    }
+
+    // Run `change` on the cache in memory and queue what it did to `keys` and any it removed;
+    // queued with `memory` locked, so the database ends up as memory is
+    async fn change<T>(&self, keys: impl IntoIterator<Item = String>, change: impl FnOnce(&mut Cache) -> T) -> T {
+        let mut cache = self.memory.cache.write().await;
+        let result = change(&mut cache);
+        let changes = Self::changes(&mut cache, keys);
+        self.pending.lock().unwrap_or_else(|e| e.into_inner()).extend(changes);
+        result
+    }
+}
+
+pub(crate) fn name_of(key: &str) -> &str {
+    key.rsplit_once(':').map_or(key, |(name, _)| name)
+}
+
+impl CacheStore for SledCache {
+    fn get<'a>(&'a self, key: &'a str) -> CacheFuture<'a, Option<Vec<DnsRecord>>> {
+        self.memory.get(key)
+    }
+
+    fn get_stale<'a>(&'a self, key: &'a str, max_age: u64) -> CacheFuture<'a, Option<Vec<DnsRecord>>> {
+        self.memory.get_stale(key, max_age)
+    }
+
+    fn insert(&self, key: String, records: Vec<DnsRecord>) -> CacheFuture<'_, ()> {
+        Box::pin(self.change([key.clone()], |cache| cache.insert(key, records)))
+    }
+
+    fn replace_name<'a>(&'a self, name: &'a str, entries: HashMap<String, Vec<DnsRecord>>) -> CacheFuture<'a, ()> {
+        let keys: Vec<String> = entries.keys().cloned().collect();
+        Box::pin(self.change(keys, move |cache| {
+            cache.remove_name(name);
+            cache.extend(entries);
+        }))
+    }
+
+    fn remove_name<'a>(&'a self, name: &'a str) -> CacheFuture<'a, ()> {
+        Box::pin(self.change([], |cache| cache.remove_name(name)))
+    }
+
+    fn has_name<'a>(&'a self, name: &'a str) -> CacheFuture<'a, bool> {
+        self.memory.has_name(name)
+    }
+
+    fn remove_matching<'a>(&'a self, flushed: &'a (dyn Fn(&str) -> bool + Sync)) -> CacheFuture<'a, usize> {
+        Box::pin(self.change([], move |cache| cache.remove_matching(flushed)))
+    }
+
+    fn remove_expired(&self, grace: u64) -> CacheFuture<'_, usize> {
+        Box::pin(self.change([], move |cache| cache.remove_expired(grace)))
+    }
+
+    fn claim_prefetch<'a>(&'a self, keys: &'a [String], min_hits: u64) -> CacheFuture<'a, bool> {
+        self.memory.claim_prefetch(keys, min_hits)
+    }
+
+    fn size(&self) -> CacheFuture<'_, Option<(usize, usize)>> {
+        self.memory.size()
+    }
+
+    fn entries<'a>(&'a self, name: Option<&'a str>) -> CacheFuture<'a, Vec<(String, Vec<DnsRecord>, u64)>> {
+        self.memory.entries(name)
+    }
+
+    fn save(&self) -> CacheFuture<'_, ()> {
+        self.changed.notify_one();
+        Box::pin(async {})
+    }
+
+    // Write the queued changes to the database and flush it to disk
+    fn write(&self) -> CacheFuture<'_, Result<(), Box<dyn std::error::Error>>> {
+        Box::pin(async move {
+            let _writing = self.writing.lock().await;
+            let changes = std::mem::take(&mut *self.pending.lock().unwrap_or_else(|e| e.into_inner()));
+            if changes.is_empty() {
+                return Ok(());
+            }
+            let applied = Self::apply(&self.db, changes.clone()).map_err(|e| e.to_string());
+            let written = match applied {
+                Ok(()) => self.db.flush_async().await.map(drop).map_err(|e| e.to_string()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = written {
+                warn!("Failed to write {} cache changes to {}, keeping them for the next write: {}", changes.len(), self.path, e);
+                let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+                for (key, records) in changes {
+                    pending.entry(key).or_insert(records);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    // The single writer of the database, like FileJsonCache::write_back
+    fn write_back(&self, interval: Duration) -> CacheFuture<'_, Result<(), Box<dyn std::error::Error>>> {
+        Box::pin(async move {
+            loop {
+                self.changed.notified().await;
+                self.write().await?;
+                tokio::time::sleep(interval).await;
+            }
+        })
+    }
+}
+
+// Longest a Redis command, connecting included, may take before the lookup goes to the database
+// instead
+pub(crate) const REDIS_TIMEOUT: Duration = Duration::from_millis(500);
+
+// How long Redis is left alone after it couldn't be reached, so lookups don't each wait for it to
+// time out
+pub(crate) const REDIS_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// Hash fields starting with this hold how an entry is used rather than records; record types
+// never start with it
+pub(crate) const REDIS_USAGE_MARK: &str = "+";
+
+// Set a field and start counting its hits afresh, then raise the key's expiry to ARGV[3] seconds
+// if one is given, but never lower it, since other fields of the name may live longer. TTL is -1
+// for a key without an expiry yet
+pub(crate) const REDIS_INSERT_SCRIPT: &str = "redis.call('HSET', KEYS[1], ARGV[1], ARGV[2]) \
+    redis.call('HDEL', KEYS[1], '+hits:' .. ARGV[1], '+prefetch:' .. ARGV[1]) \
+    if ARGV[3] ~= '' and redis.call('TTL', KEYS[1]) < tonumber(ARGV[3]) then redis.call('EXPIRE', KEYS[1], ARGV[3]) end";
+
+// Count a hit on a field that still exists, and claim it for prefetching once it is due (ARGV[3]
+// is 1) and has more than ARGV[2] hits; 1 if this call claimed it, so only one instance refreshes it
+pub(crate) const REDIS_PREFETCH_SCRIPT: &str = "if redis.call('HEXISTS', KEYS[1], ARGV[1]) == 0 then return 0 end \
+    local hits = redis.call('HINCRBY', KEYS[1], '+hits:' .. ARGV[1], 1) \
+    if ARGV[3] == '1' and hits > tonumber(ARGV[2]) then return redis.call('HSETNX', KEYS[1], '+prefetch:' .. ARGV[1], 1) end \
+    return 0";
+
+// The cache in Redis, so several instances share it and a flush on one applies to all. Each name is
+// a hash under key_prefix + name, with a JSON list of entries per record type, and the hits of
+// each type for prefetching. Commands go over one multiplexed connection that reconnects by
+// itself. When Redis can't be reached or is slow, reads miss and writes are dropped, so lookups go
+// to the database as if nothing was cached, and it is tried again after REDIS_RETRY_DELAY; a
+// command Redis rejects only fails that command. Redis' own maxmemory policy bounds its size
+// rather than cache_max_entries.
+pub(crate) struct RedisCache {
+    client: redis::Client,
+    // Connected on first use
+    connection: tokio::sync::OnceCell<redis::aio::ConnectionManager>,
+    prefix: String,
+    // Seconds past expiry Redis keeps a name, when expire is on
+    expire: Option<u64>,
+    insert_script: redis::Script,
+    prefetch_script: redis::Script,
+    retry_at: std::sync::Mutex<Option<Instant>>,
+}
+
+impl RedisCache {
+    pub(crate) fn new(config: &RedisConfig, stale_max_age: u64) -> Result<Self, Box<dyn std::error::Error>> {
+        let client = redis::Client::open(config.url.as_str())?;
+        info!("Caching in Redis at {} under {}", client.get_connection_info().addr, config.key_prefix);
+        Ok(RedisCache {
+            client,
+            connection: tokio::sync::OnceCell::new(),
+            prefix: config.key_prefix.clone(),
+            expire: config.expire.then_some(stale_max_age),
+            insert_script: redis::Script::new(REDIS_INSERT_SCRIPT),
+            prefetch_script: redis::Script::new(REDIS_PREFETCH_SCRIPT),
+            retry_at: std::sync::Mutex::new(None),
+        })
+    }
+
+    // Hash key and field of a cache key
+    fn hash_field<'a>(&self, key: &'a str) -> (String, &'a str) {
+        let (name, record_type) = key.rsplit_once(':').unwrap_or((key, ""));
+        (format!("{}{}", self.prefix, name), record_type)
+    }
+
+    // Leave Redis alone for REDIS_RETRY_DELAY after it couldn't be reached
+    fn unreachable(&self, reason: &str) {
+        warn!(
+            "Redis cache at {} unreachable ({}), using the database directly for {:?}",
+            self.client.get_connection_info().addr,
+            reason,
+            REDIS_RETRY_DELAY
+        );
+        *self.retry_at.lock().unwrap_or_else(|e| e.into_inner()) = Some(Instant::now() + REDIS_RETRY_DELAY);
+    }
+
+    // Run a command, pipeline or script on the shared connection. None when Redis can't be reached
+    // or couldn't be within the last REDIS_RETRY_DELAY, doesn't answer within REDIS_TIMEOUT, or
+    // rejects the command
+    async fn run<T, F>(&self, call: impl FnOnce(redis::aio::ConnectionManager) -> F) -> Option<T>
+    where
+        F: Future<Output = redis::RedisResult<T>>,
+    {
+        if self.retry_at.lock().unwrap_or_else(|e| e.into_inner()).is_some_and(|retry_at| Instant::now() < retry_at) {
+            return None;
+        }
+        let run = async {
+            // Not retried within a command; REDIS_RETRY_DELAY spaces the attempts instead
+            let connect = || redis::aio::ConnectionManager::new_with_backoff(self.client.clone(), 2, 100, 0);
+            let connection = self.connection.get_or_try_init(connect).await?;
+            call(connection.clone()).await
+        };
+        match tokio::time::timeout(REDIS_TIMEOUT, run).await {
+            Ok(Ok(result)) => Some(result),
+            Ok(Err(e)) if e.is_io_error() || e.is_connection_dropped() || e.is_connection_refusal() || e.is_timeout() => {
+                self.unreachable(&e.to_string());
+                None
+            }
+            Ok(Err(e)) => {
+                warn!("Redis cache command failed: {}", e);
+                None
+            }
+            Err(_) => {
+                self.unreachable("timed out");
+                None
+            }
+        }
+    }
+
+    async fn query<T: redis::FromRedisValue>(&self, command: &redis::Cmd) -> Option<T> {
+        self.run(|mut connection| async move { command.query_async(&mut connection).await }).await
+    }
+
+    // Seconds Redis should keep a name holding `records`
+    fn expire_after(&self, records: &[DnsRecord]) -> Option<u64> {
+        let ttl = records.iter().map(|record| record.ttl as u64).max().unwrap_or(0);
+        self.expire.map(|stale_max_age| ttl + stale_max_age + 1)
+    }
+
+    // Every name hash under the prefix, by SCAN
+    async fn scan_names(&self) -> Vec<String> {
+        let pattern: String = self
+            .prefix
+            .chars()
+            .flat_map(|c| if "*?[]\\".contains(c) { vec!['\\', c] } else { vec![c] })
+            .chain(['*'])
+            .collect();
+        let mut keys = Vec::new();
+        let mut cursor = 0u64;
+        loop {
+            let command = redis::cmd("SCAN").arg(cursor).arg("MATCH").arg(&pattern).arg("COUNT").arg(1000).clone();
+            let Some((next, batch)) = self.query::<(u64, Vec<String>)>(&command).await else { return keys };
+            keys.extend(batch);
+            if next == 0 {
+                return keys;
+            }
+            cursor = next;
+        }
+    }
+
+    // The record fields of a name hash, parsed, with their hits
+    async fn fields(&self, hash: &str) -> Vec<(String, Vec<DnsRecord>, u64)> {
+        let Some(all) = self.query::<HashMap<String, Vec<u8>>>(redis::cmd("HGETALL").arg(hash)).await else { return Vec::new() };
+        let hits = |field: &str| {
+            let value = all.get(&format!("{}hits:{}", REDIS_USAGE_MARK, field));
+            value.and_then(|hits| std::str::from_utf8(hits).ok()?.parse().ok()).unwrap_or(0)
+        };
+        all.iter()
+            .filter(|(field, _)| !field.starts_with(REDIS_USAGE_MARK))
+            .filter_map(|(field, value)| Some((field.clone(), serde_json::from_slice(value).ok()?, hits(field))))
+            .collect()
+    }
+}
+
+impl CacheStore for RedisCache {
+    fn get<'a>(&'a self, key: &'a str) -> CacheFuture<'a, Option<Vec<DnsRecord>>> {
+        self.get_stale(key, 0)
+    }
+
+    fn get_stale<'a>(&'a self, key: &'a str, max_age: u64) -> CacheFuture<'a, Option<Vec<DnsRecord>>> {
+        Box::pin(async move {
+            let (hash, field) = self.hash_field(key);
+            let value: Vec<u8> = self.query::<Option<Vec<u8>>>(redis::cmd("HGET").arg(&hash).arg(field)).await??;
+            let records: Vec<DnsRecord> = serde_json::from_slice(&value).ok()?;
+            let then = now_secs().saturating_sub(max_age);
+            (!records.iter().any(|record| record.is_expired(then))).then_some(records)
+        })
+    }
+
+    fn insert(&self, key: String, records: Vec<DnsRecord>) -> CacheFuture<'_, ()> {
+        Box::pin(async move {
+            let (hash, field) = self.hash_field(&key);
+            let Ok(value) = serde_json::to_vec(&records) else { return };
+            let expire = self.expire_after(&records).map(|expire| expire.to_string()).unwrap_or_default();
+            let mut invocation = self.insert_script.key(&hash);
+            invocation.arg(field).arg(value).arg(expire);
+            self.run::<(), _>(|mut connection| async move { invocation.invoke_async(&mut connection).await }).await;
+        })
+    }
+
+    fn replace_name<'a>(&'a self, name: &'a str, entries: HashMap<String, Vec<DnsRecord>>) -> CacheFuture<'a, ()> {
+        Box::pin(async move {
+            let hash = format!("{}{}", self.prefix, name);
+            let fields: Vec<(&str, Vec<u8>)> = entries
+                .iter()
+                .filter_map(|(key, records)| Some((self.hash_field(key).1, serde_json::to_vec(records).ok()?)))
+                .collect();
+            // Readers see the old or the new entries, never a mix
+            let mut pipeline = redis::pipe();
+            pipeline.atomic().del(&hash).ignore();
+            if !fields.is_empty() {
+                pipeline.hset_multiple(&hash, &fields).ignore();
+                let records: Vec<DnsRecord> = entries.values().flatten().cloned().collect();
+                if let Some(expire) = self.expire_after(&records) {
+                    pipeline.cmd("EXPIRE").arg(&hash).arg(expire).ignore();
+                }
+            }
+            self.run::<(), _>(|mut connection| async move { pipeline.query_async(&mut connection).await }).await;
+        })
+    }
+
+    fn remove_name<'a>(&'a self, name: &'a str) -> CacheFuture<'a, ()> {
+        Box::pin(async move {
+            let hash = format!("{}{}", self.prefix, name);
+            self.query::<()>(redis::cmd("DEL").arg(&hash)).await;
+        })
+    }
+
+    fn has_name<'a>(&'a self, name: &'a str) -> CacheFuture<'a, bool> {
+        Box::pin(async move {
+            let now = now_secs();
+            self.fields(&format!("{}{}", self.prefix, name)).await.iter().any(|(_, records, _)| {
+                records.iter().any(|record| record.record_type != "NXDOMAIN" && !record.is_expired(now))
+            })
+        })
+    }
+
+    fn remove_matching<'a>(&'a self, flushed: &'a (dyn Fn(&str) -> bool + Sync)) -> CacheFuture<'a, usize> {
+        Box::pin(async move {
+            let hashes: Vec<String> = self
+                .scan_names()
+                .await
+                .into_iter()
+                .filter(|hash| hash.strip_prefix(&self.prefix).is_some_and(flushed))
+                .collect();
+            let mut removed = 0;
+            for batch in hashes.chunks(100) {
+                let mut pipeline = redis::pipe();
+                for hash in batch {
+                    pipeline.cmd("HKEYS").arg(hash).cmd("DEL").arg(hash).ignore();
+                }
+                let Some(fields) = self
+                    .run::<Vec<Vec<String>>, _>(|mut connection| async move { pipeline.query_async(&mut connection).await })
+                    .await
+                else {
+                    break;
+                };
+                removed += fields.iter().flatten().filter(|field| !field.starts_with(REDIS_USAGE_MARK)).count();
+            }
+            removed
+        })
+    }
+
+    // Redis deletes names itself when expire is on; otherwise expired fields are found by a scan
+    fn remove_expired(&self, grace: u64) -> CacheFuture<'_, usize> {
+        Box::pin(async move {
+            if self.expire.is_some() {
+                return 0;
+            }
+            let then = now_secs().saturating_sub(grace);
+            let mut removed = 0;
+            for hash in self.scan_names().await {
+                let expired: Vec<String> = self
+                    .fields(&hash)
+                    .await
+                    .into_iter()
+                    .filter(|(_, records, _)| records.iter().any(|record| record.is_expired(then)))
+                    .map(|(field, _, _)| field)
+                    .collect();
+                if expired.is_empty() {
+                    continue;
+                }
+                let mut command = redis::cmd("HDEL");
+                command.arg(&hash);
+                for field in &expired {
+                    command.arg(field).arg(format!("{}hits:{}", REDIS_USAGE_MARK, field)).arg(format!("{}prefetch:{}", REDIS_USAGE_MARK, field));
+                }
+                if self.query::<()>(&command).await.is_some() {
+                    removed += expired.len();
+                }
+            }
+            removed
+        })
+    }
+
+    // Counts a hit on the first live key, and claims it when it is in the last 10% of its TTL and
+    // had more than `min_hits` hits since it was cached, as the memory cache does
+    fn claim_prefetch<'a>(&'a self, keys: &'a [String], min_hits: u64) -> CacheFuture<'a, bool> {
+        Box::pin(async move {
+            let now = now_secs();
+            for key in keys {
+                let Some(records) = self.get(key).await else { continue };
+                let due = records.iter().any(|record| {
+                    record.expires_at().is_some_and(|expires_at| now < expires_at && (expires_at - now) * 10 <= record.ttl as u64)
+                });
+                let (hash, field) = self.hash_field(key);
+                let mut invocation = self.prefetch_script.key(&hash);
+                invocation.arg(field).arg(min_hits).arg(u8::from(due));
+                let claimed = self.run::<i64, _>(|mut connection| async move { invocation.invoke_async(&mut connection).await });
+                return claimed.await == Some(1);
+            }
+            false
+        })
+    }
+
+    fn entries<'a>(&'a self, name: Option<&'a str>) -> CacheFuture<'a, Vec<(String, Vec<DnsRecord>, u64)>> {
+        Box::pin(async move {
+            let hashes = match name {
+                Some(name) => vec![format!("{}{}", self.prefix, name)],
+                None => self.scan_names().await,
+            };
+            let mut entries = Vec::new();
+            for hash in hashes {
+                let name = hash.strip_prefix(&self.prefix).unwrap_or(&hash).to_string();
+                for (field, records, hits) in self.fields(&hash).await {
+                    entries.push((cache_key(&name, &field), records, hits));
+                }
+            }
+            entries
+        })
+    }
+}
+
+// Replace `path` by writing a temporary file next to it and renaming that over it, so a crash
+// mid-write leaves either the old or the new file and never a truncated one
+pub(crate) fn write_atomically(path: &str, content: impl AsRef<[u8]>) -> std::io::Result<()> {
+    let temporary = format!("{}.tmp", path);
+    let mut file = fs::File::create(&temporary)?;
+    std::io::Write::write_all(&mut file, content.as_ref())?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)
+}
+
+// Cache keys include the record type so A and AAAA overrides for the same name don't collide
+pub(crate) fn cache_key(name: &str, record_type: &str) -> String {
+    format!("{}:{}", name, record_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn record(record_type: &str, value: &str) -> DnsRecord {
+        DnsRecord::new(record_type, value, 300)
+    }
+
+    #[test]
+    fn has_name_follows_inserts_and_removals() {
+        let mut cache = Cache { max_entries: 2, ..Cache::default() };
+        cache.insert(cache_key("a.test", "A"), vec![record("A", "192.0.2.1")]);
+        cache.insert(cache_key("a.test", "TXT"), vec![record("TXT", "hello")]);
+        cache.insert(nxdomain_key("b.test", None), vec![record("NXDOMAIN", "")]);
+        assert!(cache.has_name("a.test"));
+        assert!(!cache.has_name("b.test"));
+        assert!(!cache.has_name("a"));
+
+        // Over max_entries, the least recently used keys of a.test are evicted; the name goes with them
+        cache.insert(cache_key("c.test", "A"), vec![record("A", "192.0.2.3")]);
+        assert!(!cache.has_name("a.test"));
+        assert!(cache.has_name("c.test"));
+
+        cache.remove_name("c.test");
+        assert!(!cache.has_name("c.test"));
+        assert_eq!(cache.names.values().map(HashSet::len).sum::<usize>(), cache.records.len());
+    }
+
+    // A fresh path under the temporary directory for one test
+    fn temporary_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("fusiondns-{}-{}-{}", name, std::process::id(), rand::random::<u32>()));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn caa_records_read_back_from_the_cache_file_parse_the_same() {
+        let values = ["128 issue \"letsencrypt.org; validationmethods=dns-01\"", "0 issuewild \";\"", "0 iodef \"mailto:security@example.com\""];
+        let mut cache = Cache { max_entries: 10, ..Cache::default() };
+        cache.insert(cache_key("ca.test", "CAA"), values.iter().map(|value| record("CAA", value)).collect());
+
+        for format in [CacheFormat::Json, CacheFormat::Binary] {
+            let path = temporary_path("caa");
+            fs::write(&path, format.encode(&cache).unwrap()).unwrap();
+            let loaded = Cache::load(&path, 10, 0);
+            fs::remove_file(&path).unwrap();
+
+            let records = &loaded.records[&cache_key("ca.test", "CAA")];
+            assert_eq!(records.iter().map(|record| record.value.as_str()).collect::<Vec<_>>(), values);
+            for (record, value) in records.iter().zip(values) {
+                assert_eq!(parse_rdata(RecordType::CAA, &record.value), parse_rdata(RecordType::CAA, value));
+            }
+        }
+
+        let Some(RData::CAA(issue)) = parse_rdata(RecordType::CAA, values[0]) else { panic!("{} didn't parse", values[0]) };
+        assert!(issue.issuer_critical());
+        assert_eq!(issue.tag(), &Property::Issue);
+        let Value::Issuer(Some(issuer), options) = issue.value() else { panic!("no issuer in {}", values[0]) };
+        assert_eq!(issuer.to_string(), "letsencrypt.org");
+        assert_eq!(options.len(), 1);
+        let Some(RData::CAA(wildcard)) = parse_rdata(RecordType::CAA, values[1]) else { panic!("{} didn't parse", values[1]) };
+        assert_eq!(wildcard.value(), &Value::Issuer(None, Vec::new()));
+        assert!(parse_rdata(RecordType::CAA, "0 issue").is_none());
+        assert!(parse_rdata(RecordType::CAA, "300 issue \"ca.test\"").is_none());
+    }
+
+    #[test]
+    fn cache_keeps_each_type_of_a_name_apart() {
+        let mut cache = Cache { max_entries: 10, ..Cache::default() };
+        cache.insert(cache_key("both.test", "A"), vec![record("A", "192.0.2.1")]);
+        cache.insert(cache_key("both.test", "TXT"), vec![record("TXT", "hello")]);
+        assert_eq!(cache.get(&cache_key("both.test", "A")), Some(vec![record("A", "192.0.2.1")]));
+        assert_eq!(cache.get(&cache_key("both.test", "TXT")), Some(vec![record("TXT", "hello")]));
+
+        // A file keyed by name alone still loads, but answers no query by type
+        let path = temporary_path("json");
+        fs::write(&path, r#"{"records":{"both.test":{"record_type":"A","value":"192.0.2.1","ttl":300}}}"#).unwrap();
+        let cache = Cache::load(&path, 10, 0);
+        assert_eq!(cache.get(&cache_key("both.test", "A")), None);
+        fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn file_cache_writes_within_max_bytes_and_keeps_what_it_wrote() {
+        let path = temporary_path("json");
+        let cache = FileJsonCache::load(&path, CacheFormat::Json, 1000, 0);
+        for index in 0..100 {
+            cache.insert(cache_key(&format!("host{}.test", index), "TXT"), vec![record("TXT", &"x".repeat(index))]).await;
+        }
+        // A limit the entries already cached are far over, which only writing the file enforces
+        cache.memory.cache.write().await.max_bytes = 4000;
+        cache.write().await.unwrap();
+
+        assert!(fs::metadata(&path).unwrap().len() <= 4000);
+        let mut written: Vec<String> = Cache::load(&path, 1000, 0).records.into_keys().collect();
+        let mut kept: Vec<String> = cache.entries(None).await.into_iter().map(|(key, _, _)| key).collect();
+        written.sort();
+        kept.sort();
+        assert!(!kept.is_empty() && kept.len() < 100);
+        assert_eq!(written, kept);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sled_cache_keeps_what_memory_keeps() {
+        let path = temporary_path("sled");
+        let json_path = temporary_path("json");
+        {
+            let cache = SledCache::load(&path, &json_path, 2, 0).unwrap();
+            for name in ["a.test", "b.test", "c.test"] {
+                cache.insert(cache_key(name, "A"), vec![record("A", "192.0.2.1")]).await;
+            }
+            cache.remove_name("c.test").await;
+            cache.insert(cache_key("d.test", "A"), vec![record("A", "192.0.2.4")]).await;
+            cache.write().await.unwrap();
+        }
+
+        // a.test was evicted and c.test removed, in the database as in memory
+        let cache = SledCache::load(&path, &json_path, 10, 0).unwrap();
+        let mut keys: Vec<String> = cache.entries(None).await.into_iter().map(|(key, _, _)| key).collect();
+        keys.sort();
+        assert_eq!(keys, ["b.test:A", "d.test:A"]);
+        drop(cache);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sled_cache_imports_the_json_file_once() {
+        let path = temporary_path("sled");
+        let json_path = temporary_path("json");
+        let address = record("A", "192.0.2.1");
+        let mut file = Cache::default();
+        file.records.insert(cache_key("a.test", "A"), vec![address.clone()]);
+        fs::write(&json_path, serde_json::to_vec(&file).unwrap()).unwrap();
+
+        let cache = SledCache::load(&path, &json_path, 10, 0).unwrap();
+        assert_eq!(cache.get("a.test:A").await, Some(vec![address]));
+        assert!(!std::path::Path::new(&json_path).exists());
+        drop(cache);
+        let cache = SledCache::load(&path, &json_path, 10, 0).unwrap();
+        assert_eq!(cache.size().await.map(|(entries, _)| entries), Some(1));
+        drop(cache);
+        fs::remove_dir_all(&path).unwrap();
+        fs::remove_file(format!("{}.imported", json_path)).unwrap();
+    }
+
+    fn any_records() -> impl Strategy<Value = HashMap<String, Vec<DnsRecord>>> {
+        let record = (".*", ".*", any::<u32>(), any::<Option<u32>>(), any::<Option<u64>>())
+            .prop_map(|(record_type, value, ttl, weight, inserted_at)| DnsRecord { record_type, value, ttl, weight, inserted_at });
+        proptest::collection::hash_map(".*", proptest::collection::vec(record, 0..4), 0..32)
+    }
+
+    proptest! {
+        #[test]
+        fn binary_cache_file_round_trips(records in any_records()) {
+            let cache = Cache { records: records.clone(), ..Cache::default() };
+            let (version, decoded, result) = decode_cache(&encode_cache(&cache).unwrap());
+            prop_assert_eq!(version, CACHE_SCHEMA_VERSION);
+            prop_assert_eq!(result, Ok(()));
+            prop_assert_eq!(decoded, records);
+        }
+
+        #[test]
+        fn binary_cache_file_cut_short_keeps_whole_entries(records in any_records(), cut in any::<prop::sample::Index>()) {
+            let cache = Cache { records: records.clone(), ..Cache::default() };
+            let content = encode_cache(&cache).unwrap();
+            let (_, decoded, result) = decode_cache(&content[..cut.index(content.len())]);
+            prop_assert!(result.is_err());
+            for (key, entries) in decoded {
+                prop_assert_eq!(Some(&entries), records.get(&key));
+            }
+        }
+
+        #[test]
+        fn any_inserted_at_read_from_a_file_gives_a_ttl(records in any_records(), floor in 0u32..3600) {
+            let now = now_secs();
+            for record in records.into_values().flatten() {
+                let left = record.remaining_ttl(floor);
+                prop_assert!(left <= record.ttl.max(floor));
+                prop_assert!(record.is_expired(now) || record.inserted_at.is_none() || left > 0);
+            }
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use super::*;
+
+// Longest the benchmark waits for an answer before counting the query as unanswered
+pub(crate) const BENCH_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Options of the `bench` subcommand
+pub(crate) struct BenchOptions {
+    // Server to measure; without one the proxy is started in this process and measured
+    target: Option<SocketAddr>,
+    qps: u32,
+    duration: Duration,
+    queries: Vec<Query>,
+}
+
+impl BenchOptions {
+    // `bench [--target ip:port] [--qps n] [--duration seconds] [--names file]`. The names file has
+    // one "name [type]" per line, as dnsperf takes them; without one, 1000 names under bench.test
+    // are asked for their A records
+    fn parse(args: &[String]) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut options = BenchOptions { target: None, qps: 1000, duration: Duration::from_secs(10), queries: Vec::new() };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+            match arg.as_str() {
+                "--target" => options.target = Some(value.parse()?),
+                "--qps" => options.qps = value.parse()?,
+                "--duration" => options.duration = Duration::from_secs(value.parse()?),
+                "--names" => {
+                    for line in fs::read_to_string(value)?.lines().map(str::trim) {
+                        let mut fields = line.split_whitespace();
+                        let Some(name) = fields.next().filter(|name| !name.starts_with('#')) else { continue };
+                        let record_type = fields.next().map_or(Ok(RecordType::A), RecordType::from_str)?;
+                        options.queries.push(Query::query(Name::from_utf8(name)?, record_type));
+                    }
+                }
+                _ => return Err(format!("unknown bench option {}", arg).into()),
+            }
+        }
+        if options.queries.is_empty() {
+            for i in 0..1000 {
+                options.queries.push(Query::query(Name::from_ascii(format!("host{}.bench.test.", i))?, RecordType::A));
+            }
+        }
+        Ok(options)
+    }
+}
+
+// Options of the `cache dump` subcommand
+pub(crate) struct CacheDumpOptions {
+    name: Option<String>,
+    json: bool,
+    // Control socket of the running proxy; control_socket from config.json without one
+    socket: Option<String>,
+}
+
+impl CacheDumpOptions {
+    // `cache dump [--name name] [--json] [--socket path]`
+    fn parse(args: &[String]) -> Result<Self, Box<dyn std::error::Error>> {
+        if args.first().map(String::as_str) != Some("dump") {
+            return Err("usage: cache dump [--name name] [--json] [--socket path]".into());
+        }
+        let mut options = CacheDumpOptions { name: None, json: false, socket: None };
+        let mut args = args[1..].iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--json" => options.json = true,
+                "--name" => options.name = Some(args.next().ok_or("--name needs a value")?.clone()),
+                "--socket" => options.socket = Some(args.next().ok_or("--socket needs a value")?.clone()),
+                _ => return Err(format!("unknown cache dump option {}", arg).into()),
+            }
+        }
+        Ok(options)
+    }
+}
+
+// Ask the running proxy for its cache entries over the control socket and print them, as a
+// table or as JSON
+#[cfg(unix)]
+pub(crate) async fn cache_dump(options: &CacheDumpOptions, socket: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let stream = tokio::net::UnixStream::connect(socket).await.map_err(|e| format!("can't connect to {}: {}", socket, e))?;
+    let (reader, mut writer) = stream.into_split();
+    let command = match &options.name {
+        Some(name) => format!("dump {}\n", name),
+        None => "dump\n".to_string(),
+    };
+    writer.write_all(command.as_bytes()).await?;
+
+    let mut entries: Vec<DumpedEntry> = Vec::new();
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+    loop {
+        let line = lines.next_line().await?.ok_or("the control socket closed before the dump ended")?;
+        if line.starts_with("OK") {
+            break;
+        }
+        if let Some(e) = line.strip_prefix("ERR ") {
+            return Err(e.to_string().into());
+        }
+        entries.push(serde_json::from_str(&line)?);
+    }
+
+    if options.json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
+    }
+    println!("{:<40} {:<8} {:<40} {:>8} {:>6}  SOURCE", "NAME", "TYPE", "VALUE", "TTL", "HITS");
+    for entry in &entries {
+        let ttl = if entry.ttl > 0 { entry.ttl.to_string() } else { "expired".to_string() };
+        println!("{:<40} {:<8} {:<40} {:>8} {:>6}  {}", entry.name, entry.record_type, entry.value, ttl, entry.hits, entry.source);
+    }
+    println!("{} entries", entries.len());
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn cache_dump(_options: &CacheDumpOptions, _socket: &str) -> Result<(), Box<dyn std::error::Error>> {
+    Err("cache dump needs the control socket, which is only available on Unix".into())
+}
+
+// The value below which `percent` of the sorted `values` lie
+pub(crate) fn percentile(values: &[Duration], percent: usize) -> Duration {
+    values.get((values.len() * percent / 100).min(values.len().saturating_sub(1))).copied().unwrap_or_default()
+}
+
+// Send queries to `target` over UDP at the configured rate and print how it kept up: answers,
+// response codes and latency percentiles
+pub(crate) async fn bench(options: &BenchOptions, target: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
+    let socket = UdpSocket::bind(if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+    let total = (f64::from(options.qps) * options.duration.as_secs_f64()) as usize;
+    println!("Sending {} queries to {} at {} per second", total, target, options.qps);
+
+    // Queries waiting for an answer by ID. IDs are reused once their query is answered, so
+    // more than 65536 queries can be measured
+    let mut sent_at: HashMap<u16, (Instant, Query)> = HashMap::new();
+    let mut next_id = 0u16;
+    let mut latencies = Vec::with_capacity(total);
+    let mut response_codes: HashMap<ResponseCode, u64> = HashMap::new();
+    let mut sent = 0;
+    let mut send_errors = 0u64;
+    let mut buf = [0u8; 4096];
+    let started = Instant::now();
+    let sending_ends = tokio::time::Instant::from_std(started + options.duration + BENCH_TIMEOUT);
+    let mut ticker = tokio::time::interval(Duration::from_millis(1));
+    loop {
+        tokio::select! {
+            _ = ticker.tick(), if sent < total => {
+                // Catch up with the schedule, so a late tick sends a burst instead of lowering the rate
+                let due = ((started.elapsed().as_secs_f64() * f64::from(options.qps)) as usize).min(total);
+                while sent < due {
+                    // With every ID waiting, queries waited on for BENCH_TIMEOUT are given up as
+                    // unanswered; if that frees none, the rest is sent on a later tick
+                    if sent_at.len() > usize::from(u16::MAX) {
+                        sent_at.retain(|_, (at, _)| at.elapsed() < BENCH_TIMEOUT);
+                        if sent_at.len() > usize::from(u16::MAX) {
+                            break;
+                        }
+                    }
+                    while sent_at.contains_key(&next_id) {
+                        next_id = next_id.wrapping_add(1);
+                    }
+                    let id = next_id;
+                    next_id = next_id.wrapping_add(1);
+                    let query = options.queries[sent % options.queries.len()].clone();
+                    let mut message = Message::new();
+                    message.set_id(id);
+                    message.set_message_type(MessageType::Query);
+                    message.set_op_code(OpCode::Query);
+                    message.set_recursion_desired(true);
+                    message.add_query(query.clone());
+                    match socket.send_to(&message.to_vec()?, target).await {
+                        Ok(_) => {
+                            sent_at.insert(id, (Instant::now(), query));
+                        }
+                        Err(_) => send_errors += 1,
+                    }
+                    sent += 1;
+                }
+            }
+            received = socket.recv_from(&mut buf) => {
+                let (len, _) = received?;
+                let Ok(response) = Message::from_vec(&buf[..len]) else { continue };
+                // A late answer to a query given up on may carry an ID reused since; the question
+                // tells them apart
+                let Some((at, query)) = sent_at.get(&response.id()) else { continue };
+                if response.queries() != std::slice::from_ref(query) {
+                    continue;
+                }
+                latencies.push(at.elapsed());
+                *response_codes.entry(response.response_code()).or_insert(0) += 1;
+                sent_at.remove(&response.id());
+            }
+            _ = tokio::time::sleep_until(sending_ends) => break,
+        }
+        if sent >= total && sent_at.is_empty() {
+            break;
+        }
+    }
+    let elapsed = started.elapsed();
+
+    latencies.sort();
+    let answered = latencies.len();
+    let unanswered = sent - answered;
+    let failed: u64 = response_codes
+        .iter()
+        .filter(|(code, _)| !matches!(code, ResponseCode::NoError | ResponseCode::NXDomain))
+        .map(|(_, count)| count)
+        .sum();
+    println!("Sent {} queries in {:.1} s ({:.0} per second), {} could not be sent", sent, elapsed.as_secs_f64(), sent as f64 / elapsed.as_secs_f64(), send_errors);
+    println!("Answered {} ({:.1}%), {} unanswered", answered, answered as f64 * 100.0 / sent.max(1) as f64, unanswered);
+    let mut response_codes: Vec<_> = response_codes.into_iter().collect();
+    response_codes.sort_by_key(|(code, _)| u16::from(*code));
+    for (code, count) in response_codes {
+        println!("  {}: {}", code, count);
+    }
+    println!("Errors (unanswered or not NOERROR/NXDOMAIN): {:.2}%", (unanswered as u64 + failed) as f64 * 100.0 / sent.max(1) as f64);
+    let ms = |latency: Duration| latency.as_secs_f64() * 1000.0;
+    println!(
+        "Latency: p50 {:.2} ms, p90 {:.2} ms, p99 {:.2} ms, max {:.2} ms",
+        ms(percentile(&latencies, 50)),
+        ms(percentile(&latencies, 90)),
+        ms(percentile(&latencies, 99)),
+        ms(latencies.last().copied().unwrap_or_default())
+    );
+    Ok(())
+}
+
+// The FusionDNS command line, without the program name: the proxy itself on config.json in the
+// working directory, or one of the subcommands
+pub async fn run_cli(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    // `FusionDNS bench ...` measures a server instead of (or besides) running one
+    let bench_options = match args.first().map(String::as_str) {
+        Some("bench") => Some(BenchOptions::parse(&args[1..])?),
+        _ => None,
+    };
+    if let Some(options @ BenchOptions { target: Some(target), .. }) = &bench_options {
+        return bench(options, *target).await;
+    }
+    // `FusionDNS cache dump ...` shows the cache of the running proxy
+    if args.first().map(String::as_str) == Some("cache") {
+        let options = CacheDumpOptions::parse(&args[1..])?;
+        let socket = match &options.socket {
+            Some(socket) => socket.clone(),
+            None => load_config("config.json")?.control_socket.ok_or("no --socket given and no control_socket in config.json")?,
+        };
+        return cache_dump(&options, &socket).await;
+    }
+
+    let config = load_config("config.json")?;
+    env_logger::Builder::new().parse_filters(&config.log_level).init();
+
+    let cache_file = "dns_cache.json";
+        //"SELECT `type`, `value` FROM `dns_override` WHERE `address` = ?";
+
+    // Without a target, the benchmark measures the proxy started below and exits when done,
+    // with status 1 if it couldn't run
+    if let Some(options) = bench_options {
+        let ip: IpAddr = config.bind_address.parse()?;
+        let ip = match ip {
+            IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ip => ip,
+        };
+        let target = SocketAddr::new(ip, config.port);
+        tokio::spawn(async move {
+            // Give the proxy time to bind its sockets
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            let status = match bench(&options, target).await {
+                Ok(()) => 0,
+                Err(e) => {
+                    error!("Benchmark failed: {}", e);
+                    1
+                }
+            };
+            std::process::exit(status);
+        });
+    }
+
+    Server::new(config, cache_file)?.run().await
+}
@@ -0,0 +1,380 @@
+use super::*;
+
+// Signing keys of a zone for on-the-fly DNSSEC. The keys only live in memory; signatures are
+// made per response and never reach the cache file
+pub(crate) struct ZoneSigner {
+    pub(crate) origin: Name,
+    ksk: KeyPair<Private>,
+    zsk: KeyPair<Private>,
+    ksk_dnskey: DNSKEY,
+    zsk_dnskey: DNSKEY,
+    ttl: u32,
+}
+
+impl ZoneSigner {
+    const ALGORITHM: Algorithm = Algorithm::ECDSAP256SHA256;
+
+    pub(crate) fn load(zone: &ZoneConfig, ksk_path: &str, zsk_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let origin = zone.origin_name().ok_or_else(|| format!("invalid zone origin '{}'", zone.origin))?;
+        let load_key = |path: &str| -> Result<KeyPair<Private>, Box<dyn std::error::Error>> {
+            let key = PrivateKeyDer::from_pem_file(path)?;
+            Ok(KeyFormat::Pkcs8.decode_key(key.secret_der(), None, Self::ALGORITHM)?)
+        };
+        let ksk = load_key(ksk_path)?;
+        let zsk = load_key(zsk_path)?;
+        let ksk_dnskey = DNSKEY::new(true, true, false, Self::ALGORITHM, ksk.to_public_bytes()?);
+        let zsk_dnskey = DNSKEY::new(true, false, false, Self::ALGORITHM, zsk.to_public_bytes()?);
+
+        let digest = ksk_dnskey.to_digest(&origin, DigestType::SHA256)?;
+        info!(
+            "Signing zone {}; DS for the parent: {} {} {} 2 {}",
+            origin,
+            origin,
+            ksk_dnskey.calculate_key_tag()?,
+            u8::from(Self::ALGORITHM),
+            hex::encode_upper(digest.as_ref())
+        );
+
+        Ok(ZoneSigner { origin, ksk, zsk, ksk_dnskey, zsk_dnskey, ttl: zone.ttl })
+    }
+
+    pub(crate) fn dnskey_records(&self) -> Vec<Record> {
+        [&self.ksk_dnskey, &self.zsk_dnskey]
+            .into_iter()
+            .map(|key| Record::from_rdata(self.origin.clone(), self.ttl, RData::DNSSEC(DNSSECRData::DNSKEY(key.clone()))))
+            .collect()
+    }
+
+    // RRSIG over one RRset: the DNSKEY set is signed with the KSK, everything else with the ZSK
+    fn sign(&self, name: &Name, record_type: RecordType, rrset: &[Record]) -> Result<Record, Box<dyn std::error::Error>> {
+        let (key, dnskey) = if record_type == RecordType::DNSKEY {
+            (&self.ksk, &self.ksk_dnskey)
+        } else {
+            (&self.zsk, &self.zsk_dnskey)
+        };
+        let ttl = rrset.iter().map(|record| record.ttl()).min().unwrap_or(self.ttl);
+        let now = now_secs() as u32;
+        let inception = now.saturating_sub(3600);
+        let expiration = now + 7 * 86400;
+        let key_tag = dnskey.calculate_key_tag()?;
+
+        let tbs = tbs::rrset_tbs(
+            name,
+            DNSClass::IN,
+            name.num_labels(),
+            record_type,
+            Self::ALGORITHM,
+            ttl,
+            expiration,
+            inception,
+            key_tag,
+            &self.origin,
+            rrset,
+        )?;
+        let signature = key.sign(Self::ALGORITHM, &tbs)?;
+        let rrsig = RRSIG::new(
+            record_type,
+            Self::ALGORITHM,
+            name.num_labels(),
+            ttl,
+            expiration,
+            inception,
+            key_tag,
+            self.origin.clone(),
+            signature,
+        );
+        Ok(Record::from_rdata(name.clone(), ttl, RData::DNSSEC(DNSSECRData::RRSIG(rrsig))))
+    }
+
+    // Denial of existence without a zone walk ("black lies"): an NSEC at the queried name whose
+    // next name is its immediate successor and whose bitmap lacks the queried type. Names that
+    // don't exist are therefore answered as NODATA rather than NXDOMAIN
+    pub(crate) fn denial(&self, name: &Name, ttl: u32) -> Option<Record> {
+        let mut next = Name::from_labels(std::iter::once(&[0u8][..]).chain(name.iter())).ok()?;
+        next.set_fqdn(true);
+        let mut types = vec![RecordType::RRSIG, RecordType::NSEC];
+        if name == &self.origin {
+            types.extend([RecordType::SOA, RecordType::DNSKEY]);
+        }
+        Some(Record::from_rdata(name.clone(), ttl, RData::DNSSEC(DNSSECRData::NSEC(NSEC::new(next, types)))))
+    }
+}
+
+// The signer of the most specific signed zone holding a name
+pub(crate) fn find_signer<'a>(signers: &'a [ZoneSigner], name: &Name) -> Option<&'a ZoneSigner> {
+    signers
+        .iter()
+        .filter(|signer| signer.origin.zone_of(name))
+        .max_by_key(|signer| signer.origin.num_labels())
+}
+
+// Add RRSIGs to every RRset that falls in a signed zone
+pub(crate) fn sign_records(records: Vec<Record>, signers: &[ZoneSigner]) -> Vec<Record> {
+    let mut rrsets: Vec<(Name, RecordType)> = Vec::new();
+    for record in records.iter().filter(|record| record.record_type() != RecordType::RRSIG) {
+        if !rrsets.contains(&(record.name().clone(), record.record_type())) {
+            rrsets.push((record.name().clone(), record.record_type()));
+        }
+    }
+
+    let mut signatures = Vec::new();
+    for (name, record_type) in rrsets {
+        let Some(signer) = find_signer(signers, &name) else { continue };
+        let rrset: Vec<Record> = records
+            .iter()
+            .filter(|record| record.name() == &name && record.record_type() == record_type)
+            .cloned()
+            .collect();
+        match signer.sign(&name, record_type, &rrset) {
+            Ok(rrsig) => signatures.push(rrsig),
+            Err(e) => warn!("Could not sign {} {}: {}", name, record_type, e),
+        }
+    }
+    records.into_iter().chain(signatures).collect()
+}
+
+// Most zone cuts we walk up on the way to a trust anchor
+pub(crate) const MAX_TRUST_CHAIN_DEPTH: usize = 16;
+
+// Outcome of DNSSEC validation for an upstream answer
+pub(crate) enum Validation {
+    Secure,
+    Insecure,
+    Bogus(String),
+}
+
+// Check every answer RRset's signatures against keys that chain up to a trust anchor (RFC 4035).
+// Only a signer that is the owner's zone or one above it counts. An unsigned RRset is insecure
+// when its zone has no chain of trust and bogus when it has one, as the RRSIGs were stripped.
+// The zone is taken from the SOA upstream gives for the name; proving a zone unsigned through
+// NSEC/NSEC3 records on the missing DS isn't implemented
+pub(crate) async fn validate_answers(response: &Message, ctx: &LookupContext<'_>) -> Validation {
+    let answers = response.answers();
+    let mut rrsets: Vec<(&Name, RecordType)> = Vec::new();
+    for record in answers.iter().filter(|record| record.record_type() != RecordType::RRSIG) {
+        if !rrsets.contains(&(record.name(), record.record_type())) {
+            rrsets.push((record.name(), record.record_type()));
+        }
+    }
+    if rrsets.is_empty() {
+        return Validation::Insecure;
+    }
+
+    let mut secure = true;
+    for (name, record_type) in rrsets {
+        let rrset: Vec<Record> = answers
+            .iter()
+            .filter(|record| record.name() == name && record.record_type() == record_type)
+            .cloned()
+            .collect();
+        let sigs = rrsigs_covering(answers, name, record_type);
+        if let Some(foreign) = sigs.iter().find(|sig| !sig.signer_name().zone_of(name)) {
+            return Validation::Bogus(format!("{} {} is signed by {}, which is not its zone", name, record_type, foreign.signer_name()));
+        }
+        let signer = sigs.first().map(|sig| sig.signer_name().clone());
+        let sigs: Vec<RRSIG> = sigs.into_iter().filter(|sig| Some(sig.signer_name()) == signer.as_ref()).collect();
+        let keys = match signer {
+            Some(signer) => zone_keys(signer, ctx, 0).await,
+            None => Ok(None),
+        };
+        match keys {
+            Ok(Some(keys)) => {
+                if let Err(reason) = check_signatures(name, &rrset, &sigs, &keys) {
+                    return Validation::Bogus(reason);
+                }
+            }
+            // Unsigned, or signed by keys nothing vouches for: fine in an unsigned zone only
+            Ok(None) => match expects_signatures(name, ctx).await {
+                Ok(false) => secure = false,
+                Ok(true) => return Validation::Bogus(format!("{} {} lacks a trusted signature in a signed zone", name, record_type)),
+                Err(reason) => return Validation::Bogus(reason),
+            },
+            Err(reason) => return Validation::Bogus(reason),
+        }
+    }
+
+    if secure {
+        Validation::Secure
+    } else {
+        Validation::Insecure
+    }
+}
+
+// Whether the zone holding `name` has a chain of trust, so its records must come signed. The zone
+// is the owner of the SOA upstream answers with for the name, or puts in the authority section
+pub(crate) async fn expects_signatures(name: &Name, ctx: &LookupContext<'_>) -> Result<bool, String> {
+    let response = upstream_query(Query::query(name.clone(), RecordType::SOA), ctx, true)
+        .await
+        .map_err(|e| format!("SOA lookup for {} failed: {}", name, e))?;
+    let zone = response
+        .answers()
+        .iter()
+        .chain(response.name_servers())
+        .find(|record| record.record_type() == RecordType::SOA && record.name().zone_of(name))
+        .map_or_else(Name::root, |record| record.name().clone());
+    Ok(zone_keys(zone, ctx, 0).await?.is_some())
+}
+
+// RRSIGs in `records` that cover the RRset `name`/`record_type`
+pub(crate) fn rrsigs_covering(records: &[Record], name: &Name, record_type: RecordType) -> Vec<RRSIG> {
+    records
+        .iter()
+        .filter(|record| record.name() == name)
+        .filter_map(|record| match record.data() {
+            Some(RData::DNSSEC(DNSSECRData::RRSIG(sig))) if sig.type_covered() == record_type => Some(sig.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+// Succeeds when one of the signatures is current and verifies with one of the keys
+pub(crate) fn check_signatures(name: &Name, rrset: &[Record], sigs: &[RRSIG], keys: &[DNSKEY]) -> Result<(), String> {
+    let now = now_secs() as u32;
+    let mut reason = format!("no trusted key signed {}", name);
+    for sig in sigs {
+        if sig.sig_expiration() < now || sig.sig_inception() > now {
+            reason = format!("signature over {} by {} is outside its validity period", name, sig.signer_name());
+            continue;
+        }
+        let candidates = keys
+            .iter()
+            .filter(|key| key.algorithm() == sig.algorithm() && key.calculate_key_tag().ok() == Some(sig.key_tag()));
+        for key in candidates {
+            match key.verify_rrsig(name, DNSClass::IN, sig, rrset) {
+                Ok(()) => return Ok(()),
+                Err(e) => reason = format!("signature over {} by {} does not verify: {}", name, sig.signer_name(), e),
+            }
+        }
+    }
+    Err(reason)
+}
+
+pub(crate) type ZoneKeysFuture<'a> = Pin<Box<dyn Future<Output = Result<Option<Vec<DNSKEY>>, String>> + Send + 'a>>;
+
+// The validated zone-signing keys of `zone`: its DNSKEY RRset must be signed by a key matching a
+// trust anchor or a DS record validated in the parent zone. Ok(None) means the zone has no chain
+// of trust (no DS at the parent), Err means the chain is broken
+pub(crate) fn zone_keys<'a>(
+    zone: Name,
+    ctx: &'a LookupContext<'a>,
+    depth: usize,
+) -> ZoneKeysFuture<'a> {
+    Box::pin(async move {
+        if depth > MAX_TRUST_CHAIN_DEPTH {
+            return Err(format!("chain of trust for {} is too long", zone));
+        }
+        if let Some((keys, expires)) = ctx.dnssec_keys.lock().unwrap_or_else(|e| e.into_inner()).get(&zone) {
+            if Instant::now() < *expires {
+                return Ok(Some(keys.clone()));
+            }
+        }
+
+        // What vouches for the keys: trust anchors, the built-in root keys, or DS records validated
+        // in the parent zone. A zone without any of them is unsigned, and its DNSKEYs aren't needed
+        let anchors: Vec<&DS> = ctx.trust_anchors.iter().filter(|(name, _)| name == &zone).map(|(_, ds)| ds).collect();
+        let mut ds_set: Vec<DS> = Vec::new();
+        if anchors.is_empty() && zone.is_root() && !ctx.trust_anchors.is_empty() {
+            return Ok(None);
+        }
+        if anchors.is_empty() && !zone.is_root() {
+            let response = upstream_query(Query::query(zone.clone(), RecordType::DS), ctx, true)
+                .await
+                .map_err(|e| format!("DS lookup for {} failed: {}", zone, e))?;
+            let ds_records: Vec<Record> = response
+                .answers()
+                .iter()
+                .filter(|record| record.name() == &zone && record.record_type() == RecordType::DS)
+                .cloned()
+                .collect();
+            if ds_records.is_empty() {
+                return Ok(None);
+            }
+            let ds_sigs = rrsigs_covering(response.answers(), &zone, RecordType::DS);
+            let Some(parent) = ds_sigs.first().map(|sig| sig.signer_name().clone()) else {
+                return Err(format!("DS records for {} are unsigned", zone));
+            };
+            // The DS set belongs to the parent side of the cut, so only a zone above this one may sign it
+            if parent == zone || !parent.zone_of(&zone) {
+                return Err(format!("DS records for {} are signed by {}, which is not a parent zone", zone, parent));
+            }
+            let Some(parent_keys) = zone_keys(parent.clone(), ctx, depth + 1).await? else {
+                return Ok(None);
+            };
+            let ds_sigs: Vec<RRSIG> = ds_sigs.into_iter().filter(|sig| sig.signer_name() == &parent).collect();
+            check_signatures(&zone, &ds_records, &ds_sigs, &parent_keys)?;
+            ds_set = ds_records
+                .iter()
+                .filter_map(|record| match record.data() {
+                    Some(RData::DNSSEC(DNSSECRData::DS(ds))) => Some(ds.clone()),
+                    _ => None,
+                })
+                .collect();
+        }
+
+        let response = upstream_query(Query::query(zone.clone(), RecordType::DNSKEY), ctx, true)
+            .await
+            .map_err(|e| format!("DNSKEY lookup for {} failed: {}", zone, e))?;
+        let dnskey_records: Vec<Record> = response
+            .answers()
+            .iter()
+            .filter(|record| record.name() == &zone && record.record_type() == RecordType::DNSKEY)
+            .cloned()
+            .collect();
+        let keys: Vec<DNSKEY> = dnskey_records
+            .iter()
+            .filter_map(|record| match record.data() {
+                Some(RData::DNSSEC(DNSSECRData::DNSKEY(key))) => Some(key.clone()),
+                _ => None,
+            })
+            .collect();
+        if keys.is_empty() {
+            return Err(format!("{} signs records but publishes no DNSKEY", zone));
+        }
+        let key_sigs = rrsigs_covering(response.answers(), &zone, RecordType::DNSKEY);
+
+        // Keys allowed to sign the DNSKEY RRset
+        let entry_keys: Vec<DNSKEY> = if !anchors.is_empty() {
+            keys.iter().filter(|key| ds_matches(&anchors, &zone, key)).cloned().collect()
+        } else if zone.is_root() {
+            let root_anchor = TrustAnchor::default();
+            keys.iter().filter(|key| root_anchor.contains_dnskey_bytes(key.public_key())).cloned().collect()
+        } else {
+            let ds_set: Vec<&DS> = ds_set.iter().collect();
+            keys.iter().filter(|key| ds_matches(&ds_set, &zone, key)).cloned().collect()
+        };
+        if entry_keys.is_empty() {
+            return Err(format!("no DNSKEY of {} matches its trust anchor or DS", zone));
+        }
+        check_signatures(&zone, &dnskey_records, &key_sigs, &entry_keys)?;
+
+        let zone_signing_keys: Vec<DNSKEY> = keys.into_iter().filter(|key| key.zone_key() && !key.revoke()).collect();
+        let ttl = dnskey_records.iter().map(|record| record.ttl()).min().unwrap_or(0);
+        ctx.dnssec_keys
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(zone, (zone_signing_keys.clone(), Instant::now() + Duration::from_secs(ttl as u64)));
+        Ok(Some(zone_signing_keys))
+    })
+}
+
+pub(crate) fn ds_matches(ds_set: &[&DS], zone: &Name, key: &DNSKEY) -> bool {
+    ds_set
+        .iter()
+        .any(|ds| ds.algorithm() == key.algorithm() && ds.covers(zone, key).unwrap_or(false))
+}
+
+// Parse a trust anchor given as a DS record: `<zone> <key tag> <algorithm> <digest type> <digest>`
+pub(crate) fn parse_trust_anchor(anchor: &str) -> Result<(Name, DS), Box<dyn std::error::Error>> {
+    let fields: Vec<&str> = anchor.split_whitespace().collect();
+    let [zone, key_tag, algorithm, digest_type, digest] = fields[..] else {
+        return Err(format!("trust anchor '{}' is not '<zone> <key tag> <algorithm> <digest type> <digest>'", anchor).into());
+    };
+    let zone = parse_target_name(zone).ok_or_else(|| format!("invalid trust anchor zone '{}'", zone))?;
+    let ds = DS::new(
+        key_tag.parse()?,
+        Algorithm::from_u8(algorithm.parse()?),
+        DigestType::from_u8(digest_type.parse()?)?,
+        hex::decode(digest)?,
+    );
+    Ok((zone, ds))
+}
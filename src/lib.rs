@@ -0,0 +1,3886 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Mutex, Notify, RwLock, Semaphore, SemaphorePermit};
+use futures::stream::{FuturesUnordered, StreamExt};
+use trust_dns_proto::op::{Edns, Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns_proto::rr::{DNSClass, Name, RData, Record, RecordType};
+use trust_dns_proto::serialize::binary::{BinEncodable, BinEncoder};
+use std::str::FromStr;
+use trust_dns_proto::rr::rdata::opt::{ClientSubnet, EdnsCode, EdnsOption};
+use trust_dns_proto::rr::rdata::{A, AAAA, CAA, CNAME, HINFO, HTTPS, MX, NS, NULL, PTR, SOA, SVCB, TLSA, TXT};
+use trust_dns_proto::rr::rdata::caa::{self, Property, Value};
+use trust_dns_proto::rr::rdata::svcb::{Alpn, IpHint, Mandatory, SvcParamKey, SvcParamValue};
+use trust_dns_proto::rr::rdata::tlsa::{CertUsage, Matching, Selector};
+use trust_dns_proto::rr::dnssec::rdata::tsig::{self, TsigAlgorithm, TSIG};
+use trust_dns_proto::rr::dnssec::rdata::{DNSSECRData, DNSKEY, DS, NSEC, RRSIG};
+use trust_dns_proto::rr::dnssec::tsig::TSigner;
+use trust_dns_proto::rr::dnssec::{tbs, Algorithm, DigestType, KeyFormat, KeyPair, Private, TrustAnchor, Verifier};
+use mysql_async::{ClientIdentity, Opts, OptsBuilder, Pool, Row, SslOpts, TxOpts, prelude::*};
+use log::{debug, error, info, warn};
+use rand::Rng;
+use siphasher::sip::SipHasher24;
+use std::hash::Hasher;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::convert::Infallible;
+use std::sync::Arc;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::HeaderValue;
+use hyper::service::service_fn;
+use hyper::{Method, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+mod cache;
+mod cli;
+mod dnssec;
+mod listeners;
+mod sources;
+mod sql;
+mod upstream;
+
+#[doc(hidden)]
+pub mod bench_hooks;
+
+use cache::*;
+pub use cli::run_cli;
+use dnssec::*;
+use listeners::*;
+use sources::*;
+pub use sources::{DataSource, DbError, DbFuture};
+use sql::*;
+use upstream::*;
+
+// Configuration struct, as read from config.json by load_config
+#[derive(Deserialize)]
+pub struct Config {
+    log_level: String,
+    // One database URL, or several tried in order (see FailoverSource). Unused by a server
+    // built with Server::with_source
+    #[serde(default, deserialize_with = "one_or_more")]
+    db_settings: Vec<String>,
+    // Spread reads over every database that is up instead of using the first
+    #[serde(default)]
+    db_round_robin: bool,
+    // With an sqlite:// database, create the dns_override table if it doesn't exist
+    #[serde(default)]
+    init_schema: bool,
+    // TLS to a MySQL or MariaDB server
+    db_tls: Option<DbTlsConfig>,
+    #[serde(default)]
+    sql_query: String,
+    // Look names up in the database per query, or answer from every override loaded into memory
+    #[serde(default)]
+    mode: SourceMode,
+    #[serde(default = "default_preload_sql")]
+    preload_sql: String,
+    // Seconds between reloads in preload mode; 0 reloads on SIGHUP only
+    #[serde(default = "default_preload_interval")]
+    preload_interval: u64,
+    // Database lookups running at once, and how long a lookup waits for its turn
+    #[serde(default = "default_max_db_concurrency")]
+    max_db_concurrency: usize,
+    #[serde(default = "default_db_wait_ms")]
+    db_wait_ms: u64,
+    // Connection failures in a row after which the database is taken for down
+    #[serde(default = "default_db_failure_threshold")]
+    db_failure_threshold: usize,
+    // Longest a lookup may take, connecting included, and how long one may take before it is
+    // logged as slow
+    #[serde(default = "default_db_timeout_ms")]
+    db_timeout_ms: u64,
+    #[serde(default = "default_db_slow_ms")]
+    db_slow_ms: u64,
+    #[serde(deserialize_with = "one_or_more")]
+    upstream_dns: Vec<String>,
+    bind_address: String,
+    port: u16,
+    // UDP receive loops, each on a thread of its own
+    #[serde(default = "default_workers")]
+    workers: usize,
+    // Packet buffers kept for reuse between UDP queries
+    #[serde(default = "default_buffer_pool_size")]
+    buffer_pool_size: usize,
+    #[serde(default)]
+    synthesize_ptr: bool,
+    #[serde(default = "default_ptr_sql_query")]
+    ptr_sql_query: String,
+    #[serde(default)]
+    zones: Vec<ZoneConfig>,
+    #[serde(default)]
+    weighted_selection: WeightedSelection,
+    #[serde(default = "default_wildcard_depth")]
+    wildcard_depth: usize,
+    #[serde(default = "default_max_chain_depth")]
+    max_chain_depth: usize,
+    // Look names up in their Unicode form instead of the xn-- form clients send
+    #[serde(default)]
+    idn_unicode: bool,
+    #[serde(default)]
+    refuse_any: bool,
+    #[serde(default)]
+    multiple_questions: MultipleQuestions,
+    version_string: Option<String>,
+    server_id: Option<String>,
+    #[serde(default)]
+    authoritative_zones: Vec<String>,
+    #[serde(default = "default_true")]
+    local_names_authoritative: bool,
+    #[serde(default = "default_negative_ttl")]
+    negative_ttl: u32,
+    // TTL of database records whose row has no ttl column, or a NULL one
+    #[serde(default = "default_ttl")]
+    default_ttl: u32,
+    // Lowest TTL given in answers from the cache, however little of it is left
+    #[serde(default = "default_min_answer_ttl")]
+    min_answer_ttl: u32,
+    #[serde(default = "default_tcp_idle_timeout")]
+    tcp_idle_timeout: u64,
+    #[serde(default = "default_true")]
+    upstream_tcp_retry: bool,
+    #[serde(default = "default_upstream_timeout_ms")]
+    upstream_timeout_ms: u64,
+    #[serde(default = "default_upstream_probe_interval")]
+    upstream_probe_interval: u64,
+    #[serde(default = "default_stats_interval")]
+    stats_interval: u64,
+    // Unix socket taking cache flush commands
+    control_socket: Option<String>,
+    #[serde(default = "default_cache_sweep_interval")]
+    cache_sweep_interval: u64,
+    #[serde(default)]
+    cache_backend: CacheBackend,
+    #[serde(default)]
+    cache_format: CacheFormat,
+    redis: Option<RedisConfig>,
+    #[serde(default = "default_cache_max_entries")]
+    cache_max_entries: usize,
+    #[serde(default = "default_cache_max_bytes")]
+    cache_max_bytes: usize,
+    #[serde(default = "default_cache_save_interval")]
+    cache_save_interval: u64,
+    #[serde(default = "default_stale_max_age")]
+    stale_max_age: u64,
+    #[serde(default = "default_prefetch_min_hits")]
+    prefetch_min_hits: u64,
+    #[serde(default = "default_prefetch_per_second")]
+    prefetch_per_second: u32,
+    #[serde(default = "default_upstream_cache_size")]
+    upstream_cache_size: usize,
+    #[serde(default)]
+    upstream_cache_min_ttl: u32,
+    #[serde(default = "default_upstream_cache_max_ttl")]
+    upstream_cache_max_ttl: u32,
+    #[serde(default)]
+    upstream_strategy: UpstreamStrategy,
+    upstream_race_count: Option<usize>,
+    // Domain suffixes whose queries go to their own upstream server instead of upstream_dns
+    #[serde(default)]
+    forward_zones: HashMap<String, String>,
+    #[serde(default)]
+    upstream_tls_insecure: bool,
+    upstream_bootstrap: Option<String>,
+    dot_listen: Option<DotListenConfig>,
+    doh_listen: Option<DohListenConfig>,
+    #[serde(default)]
+    dnssec_validation: bool,
+    #[serde(default)]
+    trust_anchors: Vec<String>,
+    dns_cookies: Option<DnsCookiesConfig>,
+    rate_limit: Option<RateLimitConfig>,
+    #[serde(default)]
+    upstream_0x20: bool,
+    #[serde(default)]
+    ecs_mode: EcsMode,
+    #[serde(default = "default_ecs_prefix_v4")]
+    ecs_prefix_v4: u8,
+    #[serde(default = "default_ecs_prefix_v6")]
+    ecs_prefix_v6: u8,
+    #[serde(default = "default_update_insert_sql")]
+    update_insert_sql: String,
+    #[serde(default = "default_update_delete_sql")]
+    update_delete_sql: String,
+    // Sources allowed to send unsigned dynamic updates, as addresses or CIDR prefixes
+    #[serde(default)]
+    update_allowed: Vec<String>,
+    #[serde(default)]
+    tsig_keys: Vec<TsigKeyConfig>,
+    #[serde(default = "default_axfr_sql")]
+    axfr_sql: String,
+    zone_serial_sql: Option<String>,
+    #[serde(default = "default_notify_interval")]
+    notify_interval: u64,
+}
+
+// Shared secret for signing dynamic updates with TSIG (RFC 8945)
+#[derive(Deserialize)]
+struct TsigKeyConfig {
+    name: String,
+    #[serde(default = "default_tsig_algorithm")]
+    algorithm: String,
+    // Base64, as in a BIND key file
+    secret: String,
+}
+
+fn default_tsig_algorithm() -> String {
+    "hmac-sha256".to_string()
+}
+
+fn default_notify_interval() -> u64 {
+    60
+}
+
+// Every row of the table, laid out like axfr_sql
+fn default_preload_sql() -> String {
+    "SELECT `address`, `type`, `value` FROM `dns_override`".to_string()
+}
+
+fn default_preload_interval() -> u64 {
+    300
+}
+
+// Where lookups get their records
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum SourceMode {
+    // From the database, one query per name not in the cache
+    #[default]
+    PerQuery,
+    // From memory, with the whole table loaded by preload_sql (see PreloadedSource)
+    Preload,
+}
+
+// Every row of a zone: `address`, `type` and `value` for the names at and below :origin
+fn default_axfr_sql() -> String {
+    "SELECT `address`, `type`, `value` FROM `dns_override` WHERE `address` = :origin OR `address` LIKE :suffix".to_string()
+}
+
+fn default_update_insert_sql() -> String {
+    "INSERT INTO `dns_override` (`address`, `type`, `value`) VALUES (:address, :type, :value)".to_string()
+}
+
+// `type` and `value` are NULL when a whole name or a whole RRset is deleted
+fn default_update_delete_sql() -> String {
+    "DELETE FROM `dns_override` WHERE `address` = :address AND (:type IS NULL OR `type` = :type) AND (:value IS NULL OR `value` = :value)".to_string()
+}
+
+// Server-side DNS cookies (RFC 7873) against spoofed-source floods
+#[derive(Deserialize)]
+struct DnsCookiesConfig {
+    // Seconds between secret changes; cookies made with the previous secret stay valid until the next one
+    #[serde(default = "default_cookie_secret_rotation")]
+    secret_rotation: u64,
+    // UDP queries per second and source IP allowed without a valid server cookie
+    rate_limit: Option<u32>,
+}
+
+fn default_cookie_secret_rotation() -> u64 {
+    3600
+}
+
+// TLS settings for the MySQL connection, beyond what db_settings can say
+#[derive(Deserialize)]
+struct DbTlsConfig {
+    #[serde(default = "default_true")]
+    enabled: bool,
+    // CA certificates the server's certificate must chain to, PEM or DER; the system's when unset
+    ca_cert: Option<String>,
+    // Client certificate and key as a PKCS#12 archive, for servers that ask for one
+    client_pkcs12: Option<String>,
+    client_pkcs12_password: Option<String>,
+    // Check that the certificate is for the host in db_settings
+    #[serde(default = "default_true")]
+    verify_hostname: bool,
+}
+
+// Where the redis cache backend keeps its entries
+#[derive(Deserialize)]
+struct RedisConfig {
+    // redis://[[user]:password@]host[:port][/db]
+    url: String,
+    #[serde(default = "default_redis_key_prefix")]
+    key_prefix: String,
+    // Have Redis delete names once their entries are stale_max_age past expiry; without it the
+    // cache sweeper deletes expired entries
+    #[serde(default = "default_true")]
+    expire: bool,
+}
+
+fn default_redis_key_prefix() -> String {
+    "fusiondns:".to_string()
+}
+
+// Response rate limiting (RRL) for UDP answers, so spoofed queries can't turn the server into an amplifier
+#[derive(Deserialize)]
+struct RateLimitConfig {
+    // Answers per second allowed for one source prefix, name and response code
+    responses_per_second: u32,
+    // Seconds of excess answers that are remembered, so an abusing source stays limited until it
+    // has been quiet for up to that long
+    #[serde(default = "default_rate_limit_window")]
+    window: u64,
+    // Every slip-th limited answer goes out empty and truncated, so real clients retry over TCP;
+    // the rest are dropped. 0 drops them all
+    #[serde(default = "default_rate_limit_slip")]
+    slip: u32,
+    #[serde(default = "default_rate_limit_prefix_v4")]
+    prefix_v4: u8,
+    #[serde(default = "default_rate_limit_prefix_v6")]
+    prefix_v6: u8,
+    // Addresses or CIDR prefixes that are never limited
+    #[serde(default)]
+    exempt_clients: Vec<String>,
+}
+
+fn default_rate_limit_window() -> u64 {
+    15
+}
+
+fn default_rate_limit_slip() -> u32 {
+    2
+}
+
+fn default_rate_limit_prefix_v4() -> u8 {
+    24
+}
+
+fn default_rate_limit_prefix_v6() -> u8 {
+    56
+}
+
+fn default_ecs_prefix_v4() -> u8 {
+    24
+}
+
+fn default_ecs_prefix_v6() -> u8 {
+    56
+}
+
+// What happens to EDNS Client Subnet (RFC 7871) on forwarded queries. With `forward` or `add`,
+// upstream answers depend on the client's network, so a cache of them would have to be keyed by
+// the ECS scope as well
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum EcsMode {
+    // Remove the client's ECS option so its address never leaves this server
+    Strip,
+    // Pass the client's ECS option on unchanged
+    #[default]
+    Forward,
+    // Send the client's source address, cut to ecs_prefix_v4/ecs_prefix_v6 bits
+    Add,
+}
+
+// How a query with more than one question is answered; hardly any server supports them
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum MultipleQuestions {
+    #[default]
+    Formerr,
+    Refused,
+    // Answer every question in one response, asking upstream one question at a time
+    Answer,
+}
+
+// How queries are spread over the configured upstream servers
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum UpstreamStrategy {
+    // One server at a time, moving on to the next when it fails
+    #[default]
+    Failover,
+    // Every server (or the first upstream_race_count) at once, taking the first answer
+    Fastest,
+}
+
+// DNS-over-TLS listener for clients such as Android's Private DNS
+#[derive(Deserialize)]
+struct DotListenConfig {
+    address: String,
+    #[serde(default = "default_dot_port")]
+    port: u16,
+    cert_path: String,
+    key_path: String,
+    #[serde(default = "default_max_queries_per_connection")]
+    max_queries_per_connection: usize,
+}
+
+// DNS-over-HTTPS listener; without cert_path and key_path it uses the dot_listen certificate
+#[derive(Deserialize)]
+struct DohListenConfig {
+    address: String,
+    #[serde(default = "default_doh_port")]
+    port: u16,
+    #[serde(default = "default_doh_path")]
+    path: String,
+    cert_path: Option<String>,
+    key_path: Option<String>,
+}
+
+fn default_doh_port() -> u16 {
+    443
+}
+
+fn default_doh_path() -> String {
+    "/dns-query".to_string()
+}
+
+fn default_dot_port() -> u16 {
+    853
+}
+
+fn default_max_queries_per_connection() -> usize {
+    100
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_negative_ttl() -> u32 {
+    60
+}
+
+fn default_ttl() -> u32 {
+    3600
+}
+
+fn default_min_answer_ttl() -> u32 {
+    1
+}
+
+fn default_tcp_idle_timeout() -> u64 {
+    10
+}
+
+fn default_wildcard_depth() -> usize {
+    3
+}
+
+fn default_upstream_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_max_db_concurrency() -> usize {
+    32
+}
+
+fn default_db_wait_ms() -> u64 {
+    500
+}
+
+fn default_db_failure_threshold() -> usize {
+    3
+}
+
+fn default_db_timeout_ms() -> u64 {
+    500
+}
+
+fn default_db_slow_ms() -> u64 {
+    100
+}
+
+fn default_workers() -> usize {
+    1
+}
+
+fn default_buffer_pool_size() -> usize {
+    256
+}
+
+fn default_upstream_probe_interval() -> u64 {
+    30
+}
+
+fn default_stats_interval() -> u64 {
+    60
+}
+
+fn default_cache_sweep_interval() -> u64 {
+    60
+}
+
+fn default_cache_max_entries() -> usize {
+    100_000
+}
+
+fn default_cache_max_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_cache_save_interval() -> u64 {
+    5
+}
+
+fn default_stale_max_age() -> u64 {
+    86_400
+}
+
+fn default_prefetch_min_hits() -> u64 {
+    10
+}
+
+fn default_prefetch_per_second() -> u32 {
+    10
+}
+
+fn default_upstream_cache_size() -> usize {
+    10_000
+}
+
+fn default_upstream_cache_max_ttl() -> u32 {
+    86_400
+}
+
+// A single string or a list of them, for settings that used to take one value only
+fn one_or_more<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMore {
+        One(String),
+        More(Vec<String>),
+    }
+
+    Ok(match OneOrMore::deserialize(deserializer)? {
+        OneOrMore::One(one) => vec![one],
+        OneOrMore::More(more) => more,
+    })
+}
+
+fn default_max_chain_depth() -> usize {
+    8
+}
+
+// How answers are chosen when database rows carry a weight column
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum WeightedSelection {
+    // Return every record, ordered by a weighted shuffle
+    #[default]
+    Shuffle,
+    // Return a single record picked with probability proportional to its weight
+    Single,
+}
+
+// Local zone we answer SOA queries for and attach to negative responses
+#[derive(Deserialize)]
+struct ZoneConfig {
+    origin: String,
+    mname: String,
+    rname: String,
+    #[serde(default = "default_serial")]
+    serial: u32,
+    #[serde(default = "default_refresh")]
+    refresh: i32,
+    #[serde(default = "default_retry")]
+    retry: i32,
+    #[serde(default = "default_expire")]
+    expire: i32,
+    #[serde(default = "default_minimum")]
+    minimum: u32,
+    #[serde(default = "default_soa_ttl")]
+    ttl: u32,
+    // PKCS#8 PEM files with ECDSA P-256 keys; with both set, answers are signed for DO clients
+    ksk_path: Option<String>,
+    zsk_path: Option<String>,
+    // Secondaries allowed to transfer the zone without TSIG, as addresses or CIDR prefixes
+    #[serde(default)]
+    allow_transfer: Vec<String>,
+    // Secondaries sent a NOTIFY when the zone serial changes, as "ip" or "ip:port"
+    #[serde(default)]
+    secondaries: Vec<String>,
+}
+
+fn default_serial() -> u32 {
+    1
+}
+
+fn default_refresh() -> i32 {
+    3600
+}
+
+fn default_retry() -> i32 {
+    600
+}
+
+fn default_expire() -> i32 {
+    604800
+}
+
+fn default_minimum() -> u32 {
+    300
+}
+
+fn default_soa_ttl() -> u32 {
+    3600
+}
+
+impl ZoneConfig {
+    fn origin_name(&self) -> Option<Name> {
+        parse_target_name(&self.origin)
+    }
+
+    // SOA record for the zone; negative answers use the minimum as TTL (RFC 2308)
+    fn soa_record(&self, ttl: u32) -> Option<Record> {
+        self.soa_record_with_serial(self.serial, ttl)
+    }
+
+    fn soa_record_with_serial(&self, serial: u32, ttl: u32) -> Option<Record> {
+        let soa = SOA::new(
+            parse_target_name(&self.mname)?,
+            parse_target_name(&self.rname)?,
+            serial,
+            self.refresh,
+            self.retry,
+            self.expire,
+            self.minimum,
+        );
+        Some(Record::from_rdata(self.origin_name()?, ttl, RData::SOA(soa)))
+    }
+}
+
+// Whether a name falls under one of the configured authoritative zone suffixes (label-wise)
+fn in_authoritative_zone(suffixes: &[String], name: &Name) -> bool {
+    suffixes
+        .iter()
+        .filter_map(|suffix| parse_target_name(suffix))
+        .any(|suffix| suffix.zone_of(name))
+}
+
+// Most specific configured zone containing the name
+fn find_zone<'a>(zones: &'a [ZoneConfig], name: &Name) -> Option<&'a ZoneConfig> {
+    zones
+        .iter()
+        .filter_map(|zone| zone.origin_name().map(|origin| (zone, origin)))
+        .filter(|(_, origin)| origin.zone_of(name))
+        .max_by_key(|(_, origin)| origin.num_labels())
+        .map(|(zone, _)| zone)
+}
+
+fn default_ptr_sql_query() -> String {
+    "SELECT `address` FROM `dns_override` WHERE `type` IN ('A', 'AAAA') AND `value` = ?".to_string()
+}
+
+// DNS Record Cache Structs
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DnsRecord {
+    // The type as a name ("A", "MX", ...) and the value in the form the database rows hold it
+    pub record_type: String,
+    pub value: String,
+    pub ttl: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+    // Unix time the entry was cached; it expires `ttl` seconds later. Entries from cache files
+    // written before this was stored have none and count as expired
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    inserted_at: Option<u64>,
+}
+
+impl DnsRecord {
+    // A record as a data source returns it, its TTL counting from now
+    pub fn new(record_type: &str, value: &str, ttl: u32) -> Self {
+        DnsRecord {
+            record_type: record_type.to_string(),
+            value: value.to_string(),
+            ttl,
+            weight: None,
+            inserted_at: Some(now_secs()),
+        }
+    }
+
+    // Negative entry ("NXDOMAIN" or "NODATA") that expires after the negative TTL
+    fn negative(record_type: &str, ttl: u32) -> Self {
+        DnsRecord {
+            record_type: record_type.to_string(),
+            value: String::new(),
+            ttl,
+            weight: None,
+            inserted_at: Some(now_secs()),
+        }
+    }
+
+    // Seconds left of the TTL, counted from when the entry was cached, but at least `floor`.
+    // Entries without inserted_at have their full TTL
+    fn remaining_ttl(&self, floor: u32) -> u32 {
+        let left = match self.expires_at() {
+            Some(expires_at) => expires_at.saturating_sub(now_secs()).min(u64::from(self.ttl)) as u32,
+            None => self.ttl,
+        };
+        left.max(floor)
+    }
+
+    // When the entry expires, for entries that know when they were cached. A cache file may hold
+    // any inserted_at, so this saturates rather than overflow
+    fn expires_at(&self) -> Option<u64> {
+        self.inserted_at.map(|inserted_at| inserted_at.saturating_add(u64::from(self.ttl)))
+    }
+
+    fn is_negative(&self) -> bool {
+        self.record_type == "NXDOMAIN" || self.record_type == "NODATA"
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at().is_none_or(|expires_at| now >= expires_at)
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// DNAME (RFC 6672) has no native support in trust-dns, so it travels as an unknown type
+const DNAME_TYPE: RecordType = RecordType::Unknown(39);
+
+// UDP payload size we advertise with EDNS0, the DNS flag day 2020 recommendation
+const EDNS_PAYLOAD_SIZE: u16 = 1232;
+
+// Largest buffer the buffer pool keeps; bigger ones, from large TCP-sized answers, are freed
+const MAX_POOLED_BUFFER: usize = 16 * 1024;
+
+// Most names waiting to be prefetched; popular names beyond that expire as usual
+const PREFETCH_QUEUE_SIZE: usize = 64;
+
+// TTL of answers served from expired cache entries (RFC 8767 recommends 30 seconds)
+const STALE_TTL: u32 = 30;
+
+// Longest a query waits for an identical one in flight before doing its own lookup
+const MAX_IN_FLIGHT_WAIT: Duration = Duration::from_secs(10);
+
+// Type name as stored in the database and cache
+fn record_type_name(record_type: RecordType) -> String {
+    if record_type == DNAME_TYPE {
+        "DNAME".to_string()
+    } else {
+        record_type.to_string()
+    }
+}
+
+// Replace the cached entries for a name with its database rows, one cache key per record type.
+// Rows looked up by type (see LookupContext::lookup_type) only replace the types they hold
+async fn cache_rows(cache: &dyn CacheStore, qname: &str, record_type: Option<&str>, entries: Vec<DnsRecord>) -> Vec<DnsRecord> {
+    let mut by_type: HashMap<String, Vec<DnsRecord>> = HashMap::new();
+
+    for entry in &entries {
+        info!("Database result: {} -> {} {} (weight {:?})", qname, entry.record_type, entry.value, entry.weight);
+        by_type.entry(cache_key(qname, &entry.record_type)).or_default().push(entry.clone());
+    }
+
+    match record_type {
+        None => cache.replace_name(qname, by_type).await,
+        Some(_) => {
+            for (key, records) in by_type {
+                cache.insert(key, records).await;
+            }
+        }
+    }
+    cache.save().await;
+
+    entries
+}
+
+// Key of the negative entry for a name without overrides, or without overrides of one type when
+// it was looked up by type
+fn nxdomain_key(qname: &str, record_type: Option<&str>) -> String {
+    match record_type {
+        None => cache_key(qname, "NXDOMAIN"),
+        Some(record_type) => cache_key(qname, &format!("NXDOMAIN-{}", record_type)),
+    }
+}
+
+// Remember that a name has no overrides at all, replacing whatever was cached for it, or that it
+// has none of the type it was looked up by
+async fn cache_nxdomain(cache: &dyn CacheStore, qname: &str, record_type: Option<&str>, ttl: u32) {
+    let negative = vec![DnsRecord::negative("NXDOMAIN", ttl)];
+    match record_type {
+        None => cache.replace_name(qname, HashMap::from([(nxdomain_key(qname, None), negative)])).await,
+        Some(_) => cache.insert(nxdomain_key(qname, record_type), negative).await,
+    }
+    cache.save().await;
+}
+
+// Remember that a name exists but has nothing to answer for a type
+async fn cache_nodata(cache: &dyn CacheStore, qname: &str, qtype: RecordType, ttl: u32) {
+    cache.insert(cache_key(qname, &record_type_name(qtype)), vec![DnsRecord::negative("NODATA", ttl)]).await;
+    cache.save().await;
+}
+
+// Order entries for an answer: weighted selection when weights are present (dropping weight 0),
+// otherwise round-robin rotation so repeated answers spread load
+fn select_entries(mut entries: Vec<DnsRecord>, ctx: &LookupContext<'_>) -> Vec<DnsRecord> {
+    if entries.iter().all(|entry| entry.weight.is_none()) {
+        if !entries.is_empty() {
+            let len = entries.len();
+            entries.rotate_left(ctx.rotation.fetch_add(1, Ordering::Relaxed) % len);
+        }
+        return entries;
+    }
+
+    let mut rng = rand::thread_rng();
+    entries.retain(|entry| entry.weight != Some(0));
+
+    match ctx.weighted_selection {
+        WeightedSelection::Single => {
+            let total: u64 = entries.iter().map(|entry| entry.weight.unwrap_or(1) as u64).sum();
+            if total == 0 {
+                return entries;
+            }
+            let mut pick = rng.gen_range(0..total);
+            for entry in entries {
+                let weight = entry.weight.unwrap_or(1) as u64;
+                if pick < weight {
+                    return vec![entry];
+                }
+                pick -= weight;
+            }
+            Vec::new()
+        }
+        WeightedSelection::Shuffle => {
+            // Efraimidis-Spirakis: sort by u^(1/w) descending
+            let mut keyed: Vec<(f64, DnsRecord)> = entries
+                .into_iter()
+                .map(|entry| {
+                    let weight = entry.weight.unwrap_or(1) as f64;
+                    (rng.gen::<f64>().powf(1.0 / weight), entry)
+                })
+                .collect();
+            keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+            keyed.into_iter().map(|(_, entry)| entry).collect()
+        }
+    }
+}
+
+// Address record type to chase when following a CNAME for the given query type
+fn cname_target_type(qtype: RecordType) -> RecordType {
+    if qtype == RecordType::AAAA {
+        RecordType::AAAA
+    } else {
+        RecordType::A
+    }
+}
+
+// Split a TXT value into character strings of at most 255 bytes without breaking UTF-8 sequences
+fn txt_strings(value: &str) -> Vec<String> {
+    let mut strings = Vec::new();
+    let mut current = String::new();
+
+    for c in value.chars() {
+        if current.len() + c.len_utf8() > 255 {
+            strings.push(std::mem::take(&mut current));
+        }
+        current.push(c);
+    }
+    if !current.is_empty() || strings.is_empty() {
+        strings.push(current);
+    }
+
+    strings
+}
+
+// Parse a target host name stored with or without the trailing dot as a fully qualified name;
+// Unicode names are converted to their xn-- form
+fn parse_target_name(value: &str) -> Option<Name> {
+    let value = value.trim();
+    let mut name = if value.is_ascii() { Name::from_ascii(value) } else { Name::from_utf8(value) }.ok()?;
+    name.set_fqdn(true);
+    Some(name)
+}
+
+// Parse a CAA value in presentation form, e.g. `0 issue "letsencrypt.org"`
+fn parse_caa(value: &str) -> Option<CAA> {
+    let mut parts = value.trim().splitn(3, char::is_whitespace);
+    let (flags, tag, property_value) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(flags), Some(tag), Some(property_value)) => (flags, tag, property_value.trim()),
+        _ => {
+            warn!("Malformed CAA value (expected \"<flags> <tag> <value>\"): {}", value);
+            return None;
+        }
+    };
+
+    let flags: u8 = match flags.parse() {
+        Ok(flags) => flags,
+        Err(_) => {
+            warn!("Invalid CAA flags {:?} in value: {}", flags, value);
+            return None;
+        }
+    };
+    let issuer_critical = flags & 0x80 != 0;
+
+    if tag.is_empty() || !tag.chars().all(|c| c.is_ascii_alphanumeric()) {
+        warn!("Invalid CAA tag {:?} in value: {}", tag, value);
+        return None;
+    }
+
+    let property_value = match property_value.strip_prefix('"') {
+        Some(quoted) => match quoted.strip_suffix('"') {
+            Some(unquoted) => unquoted,
+            None => {
+                warn!("Unterminated quoted CAA value: {}", value);
+                return None;
+            }
+        },
+        None => property_value,
+    };
+
+    let caa = match Property::from(tag.to_string()) {
+        Property::Issue | Property::IssueWild => {
+            let (name, options) = match caa::read_issuer(property_value.as_bytes()) {
+                Ok(issuer) => issuer,
+                Err(e) => {
+                    warn!("Invalid CAA issuer in value {}: {}", value, e);
+                    return None;
+                }
+            };
+            if tag.eq_ignore_ascii_case("issuewild") {
+                CAA::new_issuewild(issuer_critical, name, options)
+            } else {
+                CAA::new_issue(issuer_critical, name, options)
+            }
+        }
+        Property::Iodef => match caa::read_iodef(property_value.as_bytes()) {
+            Ok(url) => CAA::new_iodef(issuer_critical, url),
+            Err(e) => {
+                warn!("Invalid CAA iodef URL in value {}: {}", value, e);
+                return None;
+            }
+        },
+        tag @ Property::Unknown(_) => CAA {
+            issuer_critical,
+            tag,
+            value: Value::Unknown(property_value.as_bytes().to_vec()),
+        },
+    };
+
+    Some(caa)
+}
+
+// Map an SVCB parameter name from presentation form to its key
+fn svc_param_key(key: &str) -> Option<SvcParamKey> {
+    match key {
+        "mandatory" => Some(SvcParamKey::Mandatory),
+        "alpn" => Some(SvcParamKey::Alpn),
+        "no-default-alpn" => Some(SvcParamKey::NoDefaultAlpn),
+        "port" => Some(SvcParamKey::Port),
+        "ipv4hint" => Some(SvcParamKey::Ipv4Hint),
+        "ipv6hint" => Some(SvcParamKey::Ipv6Hint),
+        _ => None,
+    }
+}
+
+// Parse an SVCB/HTTPS value, e.g. `1 . alpn=h2,h3 port=443 ipv4hint=192.0.2.1`
+fn parse_svcb(value: &str) -> Option<SVCB> {
+    let mut parts = value.split_whitespace();
+    let priority = parts.next().and_then(|p| p.parse::<u16>().ok());
+    let target = parts.next().and_then(parse_target_name);
+    let (priority, target) = match (priority, target) {
+        (Some(priority), Some(target)) => (priority, target),
+        _ => {
+            warn!("Malformed SVCB/HTTPS value (expected \"<priority> <target> [params]\"): {}", value);
+            return None;
+        }
+    };
+
+    let mut params = Vec::new();
+    for param in parts {
+        let (key, param_value) = param.split_once('=').unwrap_or((param, ""));
+        let param_value = param_value.trim_matches('"');
+        let list = || param_value.split(',').filter(|v| !v.is_empty());
+
+        let parsed = match svc_param_key(key) {
+            Some(SvcParamKey::Mandatory) => list()
+                .map(svc_param_key)
+                .collect::<Option<Vec<_>>>()
+                .map(|keys| SvcParamValue::Mandatory(Mandatory(keys))),
+            Some(SvcParamKey::Alpn) => Some(SvcParamValue::Alpn(Alpn(list().map(String::from).collect()))),
+            Some(SvcParamKey::NoDefaultAlpn) => Some(SvcParamValue::NoDefaultAlpn),
+            Some(SvcParamKey::Port) => param_value.parse().ok().map(SvcParamValue::Port),
+            Some(SvcParamKey::Ipv4Hint) => list()
+                .map(|ip| ip.parse::<Ipv4Addr>().ok().map(A))
+                .collect::<Option<Vec<_>>>()
+                .map(|ips| SvcParamValue::Ipv4Hint(IpHint(ips))),
+            Some(SvcParamKey::Ipv6Hint) => list()
+                .map(|ip| ip.parse::<Ipv6Addr>().ok().map(AAAA))
+                .collect::<Option<Vec<_>>>()
+                .map(|ips| SvcParamValue::Ipv6Hint(IpHint(ips))),
+            _ => None,
+        };
+
+        match parsed {
+            Some(parsed) => params.push((svc_param_key(key)?, parsed)),
+            None => {
+                warn!("Invalid SVCB/HTTPS parameter {:?} in value: {}", param, value);
+                return None;
+            }
+        }
+    }
+
+    // Parameters must appear in increasing key order on the wire
+    params.sort_by_key(|(key, _)| u16::from(*key));
+
+    Some(SVCB::new(priority, target, params))
+}
+
+// Lookup failures that must be answered with SERVFAIL instead of being forwarded upstream
+#[derive(Debug, Clone)]
+enum LookupError {
+    InvalidRecord(String),
+    // A CNAME/DNAME chain that loops or is longer than max_chain_depth, with the records
+    // collected up to that point
+    BrokenChain(Vec<Record>),
+    // Every database permit stayed taken for db_wait_ms, and nothing was cached to fall back on
+    DatabaseBusy,
+    // The database failed while following a chain and nothing was cached for the link, with the
+    // records collected up to that point. The chain isn't finished upstream, which could answer
+    // a public record for an overridden name
+    DatabaseDown(Vec<Record>),
+}
+
+impl LookupError {
+    // Put the records found before following a chain in front of the ones a broken chain carries
+    fn after(self, records: &[Record]) -> Self {
+        match self {
+            LookupError::BrokenChain(tail) => LookupError::BrokenChain(records.iter().cloned().chain(tail).collect()),
+            LookupError::DatabaseDown(tail) => LookupError::DatabaseDown(records.iter().cloned().chain(tail).collect()),
+            e => e,
+        }
+    }
+}
+
+impl std::fmt::Display for LookupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LookupError::InvalidRecord(reason) => write!(f, "invalid record: {}", reason),
+            LookupError::BrokenChain(_) => write!(f, "CNAME chain loops or is too long"),
+            LookupError::DatabaseBusy => write!(f, "database busy"),
+            LookupError::DatabaseDown(_) => write!(f, "database unavailable"),
+        }
+    }
+}
+
+// Outcome of a top-level lookup
+#[derive(Clone)]
+enum LookupResult {
+    // Records to answer with
+    Records(Vec<Record>),
+    // The name has overrides, just none of the queried type (or all weighted out): NOERROR, empty answer
+    NoData,
+    // Nothing is known locally about the name
+    NotFound,
+}
+
+// Parse a TLSA value of the form "<usage> <selector> <matching-type> <hexdata>"
+fn parse_tlsa(value: &str) -> Result<TLSA, LookupError> {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    let (usage, selector, matching, data) = match parts.as_slice() {
+        [usage, selector, matching, data @ ..] if !data.is_empty() => (usage, selector, matching, data.concat()),
+        _ => {
+            warn!("Malformed TLSA value (expected \"<usage> <selector> <matching-type> <hexdata>\"): {}", value);
+            return Err(LookupError::InvalidRecord(format!("malformed TLSA value {:?}", value)));
+        }
+    };
+
+    let field = |field: &str| {
+        field.parse::<u8>().map_err(|_| {
+            warn!("Invalid TLSA field {:?} in value: {}", field, value);
+            LookupError::InvalidRecord(format!("invalid TLSA field {:?}", field))
+        })
+    };
+    let (usage, selector, matching) = (field(usage)?, field(selector)?, field(matching)?);
+
+    let cert_data = hex::decode(&data).map_err(|e| {
+        warn!("Invalid TLSA certificate data in value {}: {}", value, e);
+        LookupError::InvalidRecord(format!("invalid TLSA hex data: {}", e))
+    })?;
+
+    Ok(TLSA::new(
+        CertUsage::from(usage),
+        Selector::from(selector),
+        Matching::from(matching),
+        cert_data,
+    ))
+}
+
+// Parse a stored value into record data for the given type
+fn parse_rdata(record_type: RecordType, value: &str) -> Option<RData> {
+    let rdata = match record_type {
+        RecordType::A => RData::A(A(value.parse::<Ipv4Addr>().ok()?)),
+        RecordType::AAAA => RData::AAAA(AAAA(value.parse::<Ipv6Addr>().ok()?)),
+        RecordType::TXT => RData::TXT(TXT::new(txt_strings(value))),
+        RecordType::MX => {
+            // Stored as "<preference> <exchange>", e.g. "10 mail.example.com"
+            let (preference, exchange) = value.trim().split_once(char::is_whitespace)?;
+            RData::MX(MX::new(preference.parse().ok()?, parse_target_name(exchange)?))
+        }
+        RecordType::CNAME => RData::CNAME(CNAME(parse_target_name(value)?)),
+        RecordType::PTR => RData::PTR(PTR(parse_target_name(value)?)),
+        RecordType::NS => RData::NS(NS(parse_target_name(value)?)),
+        RecordType::CAA => RData::CAA(parse_caa(value)?),
+        RecordType::SVCB => RData::SVCB(parse_svcb(value)?),
+        RecordType::HTTPS => RData::HTTPS(HTTPS(parse_svcb(value)?)),
+        _ => return None,
+    };
+
+    Some(rdata)
+}
+
+// Build the answer record for a stored value when its type matches the query type
+fn build_record(
+    name: Name,
+    ttl: u32,
+    record_type: &str,
+    value: &str,
+    qtype: RecordType,
+) -> Result<Option<Record>, LookupError> {
+    if record_type != record_type_name(qtype) {
+        return Ok(None);
+    }
+
+    let rdata = match qtype {
+        RecordType::TLSA => Some(RData::TLSA(parse_tlsa(value)?)),
+        DNAME_TYPE => parse_target_name(value).and_then(|target| dname_rdata(&target)),
+        _ => parse_rdata(qtype, value),
+    };
+    // The row is there but unusable; answering as if it weren't would hide the broken override
+    let rdata = rdata.ok_or_else(|| LookupError::InvalidRecord(format!("row {} {} {:?} does not parse", name, record_type, value)))?;
+
+    Ok(Some(Record::from_rdata(name, ttl, rdata)))
+}
+
+// Parse an in-addr.arpa or ip6.arpa name back into the address it represents
+fn reverse_name_to_ip(name: &str) -> Option<IpAddr> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+
+    if let Some(labels) = name.strip_suffix(".in-addr.arpa") {
+        let octets: Vec<u8> = labels
+            .split('.')
+            .rev()
+            .map(|label| label.parse().ok())
+            .collect::<Option<_>>()?;
+        let octets: [u8; 4] = octets.try_into().ok()?;
+        return Some(IpAddr::V4(Ipv4Addr::from(octets)));
+    }
+
+    if let Some(labels) = name.strip_suffix(".ip6.arpa") {
+        let nibbles: Vec<&str> = labels.split('.').rev().collect();
+        if nibbles.len() != 32 || nibbles.iter().any(|n| n.len() != 1) {
+            return None;
+        }
+        let bits = u128::from_str_radix(&nibbles.concat(), 16).ok()?;
+        return Some(IpAddr::V6(Ipv6Addr::from(bits)));
+    }
+
+    None
+}
+
+// Build PTR answers from the A/AAAA overrides that point at the queried address
+async fn synthesize_ptr(query: &Query, db: &dyn DataSource, ttl: u32) -> Vec<Record> {
+    let ip = match reverse_name_to_ip(&query.name().to_string()) {
+        Some(ip) => ip,
+        None => return Vec::new(),
+    };
+
+    let names = match db.names_for_address(&ip.to_string()).await {
+        Ok(names) => names,
+        Err(e) => {
+            warn!("Database query error: {}", e);
+            return Vec::new();
+        }
+    };
+
+    names
+        .iter()
+        .filter_map(|address| build_record(query.name().clone(), ttl, "PTR", address, RecordType::PTR).ok().flatten())
+        .inspect(|record| info!("Synthesized PTR: {} -> {:?}", ip, record.data()))
+        .collect()
+}
+
+// Answer for CHAOS-class identity queries (version.bind, hostname.bind, id.server), if configured
+fn chaos_answer(query: &Query, config: &Config) -> Option<Record> {
+    if query.query_type() != RecordType::TXT {
+        return None;
+    }
+
+    let qname = query.name().to_ascii().trim_end_matches('.').to_ascii_lowercase();
+    let value = match qname.as_str() {
+        "version.bind" | "version.server" => config.version_string.as_ref()?,
+        "hostname.bind" | "id.server" => config.server_id.as_ref()?,
+        _ => return None,
+    };
+
+    let mut record = Record::from_rdata(query.name().clone(), 0, RData::TXT(TXT::new(txt_strings(value))));
+    record.set_dns_class(DNSClass::CH);
+    Some(record)
+}
+
+// Names whose addresses belong in the additional section of a response, and whether
+// upstream may be asked for them (NS glue only comes from our own overrides)
+fn additional_targets(records: &[Record]) -> Vec<(Name, bool)> {
+    records
+        .iter()
+        .filter_map(|record| match record.data() {
+            Some(RData::MX(mx)) => Some((mx.exchange().clone(), true)),
+            Some(RData::NS(ns)) => Some((ns.0.clone(), false)),
+            _ => None,
+        })
+        .collect()
+}
+
+// Every cache_sweep_interval seconds, prune expired cache entries so the cache file doesn't grow
+// with names nobody asks for anymore. Entries are kept for stale_max_age past their expiry, to be
+// served while the database is down
+async fn sweep_cache(config: &Config, cache: &dyn CacheStore) -> Result<(), Box<dyn std::error::Error>> {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.cache_sweep_interval.max(1)));
+    loop {
+        interval.tick().await;
+        cache.sweep(config.stale_max_age).await;
+    }
+}
+
+// Refresh popular names queued by lookups from the database before their cache entries expire, at
+// most prefetch_per_second a second. A name is only refreshed when a database permit is free right
+// away, so prefetching never holds up lookups; names without rows anymore are left to expire
+async fn prefetch_entries(
+    mut queue: mpsc::Receiver<Query>,
+    config: &Config,
+    ctx: &LookupContext<'_>,
+    cache: &dyn CacheStore,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut interval = tokio::time::interval(Duration::from_secs(1) / config.prefetch_per_second.max(1));
+    while let Some(query) = queue.recv().await {
+        interval.tick().await;
+        let qname = ctx.lookup_name(query.name());
+        let record_type = ctx.lookup_type(query.query_type());
+        let Ok(db_permit) = ctx.db_permits.try_acquire() else {
+            debug!("Database busy, not prefetching {}", qname);
+            continue;
+        };
+        let entries = match ctx.db.lookup(&qname, record_type.as_deref()).await {
+            Ok(entries) if entries.is_empty() => find_wildcard(query.name(), record_type.as_deref(), ctx).await,
+            Ok(entries) => entries,
+            Err(DbError::Connection(e)) => {
+                debug!("Database connection failed, not prefetching {}: {}", qname, e);
+                continue;
+            }
+            Err(e) => {
+                warn!("Database query error while prefetching {}: {}", qname, e);
+                continue;
+            }
+        };
+        drop(db_permit);
+        if !entries.is_empty() {
+            info!("Prefetching {} before its cache entries expire", qname);
+            cache_rows(cache, &qname, record_type.as_deref(), entries).await;
+            ctx.metrics.prefetches.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    Ok(())
+}
+
+// Every stats_interval seconds, log the query counters
+async fn log_stats(config: &Config, ctx: &LookupContext<'_>, cache: &dyn CacheStore) -> Result<(), Box<dyn std::error::Error>> {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.stats_interval.max(1)));
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let stats = ctx.metrics.snapshot(cache.size().await, ctx.databases);
+        let cache_size = match stats.cache_size {
+            Some((entries, bytes)) => format!(", {} cache entries of about {} bytes", entries, bytes),
+            None => String::new(),
+        };
+        let up_or_down = |down: bool| if down { "down" } else { "up" };
+        let db_health = match &stats.db_health[..] {
+            [(_, down)] => format!("database {}", up_or_down(*down)),
+            databases => {
+                let health: Vec<String> = databases.iter().map(|(name, down)| format!("{} {}", name, up_or_down(*down))).collect();
+                format!("databases {}", health.join(", "))
+            }
+        };
+        info!(
+            "Stats: {} cache hits, {} cache misses, {} database hits, {} database errors, {} database timeouts, {}, {} forwarded upstream, {} upstream timeouts, {} responses sent{}",
+            stats.cache_hits,
+            stats.cache_misses,
+            stats.db_hits,
+            stats.db_errors,
+            stats.db_timeouts,
+            db_health,
+            stats.upstream_forwards,
+            stats.upstream_timeouts,
+            stats.responses_sent,
+            cache_size
+        );
+    }
+}
+
+// Load configuration from a JSON file
+pub fn load_config(path: &str) -> Result<Config, Box<dyn std::error::Error>> {
+    let config_content = fs::read_to_string(path)?;
+    let config: Config = serde_json::from_str(&config_content)?;
+    Ok(config)
+}
+
+
+// Wire form of DNAME record data: the uncompressed target name
+fn dname_rdata(target: &Name) -> Option<RData> {
+    Some(RData::Unknown {
+        code: u16::from(DNAME_TYPE),
+        rdata: NULL::with(target.to_bytes().ok()?),
+    })
+}
+
+// Everything a lookup needs besides the cache itself
+struct LookupContext<'a> {
+    db: &'a dyn DataSource,
+    // The databases behind db, for their health
+    databases: &'a [HealthCheckedSource<'a>],
+    upstreams: &'a [Upstream],
+    upstream_strategy: UpstreamStrategy,
+    // How many upstream servers the fastest strategy queries at once
+    upstream_race_count: usize,
+    forward_zones: &'a [(Name, Upstream)],
+    // Index of the upstream server tried first, moved on when it fails
+    current_upstream: AtomicUsize,
+    upstream_timeout: Duration,
+    // Whether a truncated upstream answer is asked again over TCP
+    upstream_tcp_retry: bool,
+    trust_anchors: &'a [(Name, DS)],
+    dnssec_keys: std::sync::Mutex<HashMap<Name, (Vec<DNSKEY>, Instant)>>,
+    zone_signers: &'a [ZoneSigner],
+    cookies: Option<DnsCookies>,
+    rate_limiter: Option<RateLimiter>,
+    upstream_0x20: bool,
+    // Queries forwarded on the upstream sockets, by upstream message ID
+    outstanding: std::sync::Mutex<HashMap<u16, PendingQuery>>,
+    upstream_sockets: AtomicUsize,
+    shared_upstream: Mutex<()>,
+    tsig_keys: Vec<TSigner>,
+    update_allowed: Vec<(IpAddr, u8)>,
+    rotation: AtomicUsize,
+    weighted_selection: WeightedSelection,
+    wildcard_depth: usize,
+    max_chain_depth: usize,
+    idn_unicode: bool,
+    metrics: &'a Metrics,
+    zones: &'a [ZoneConfig],
+    negative_ttl: u32,
+    min_answer_ttl: u32,
+    // Local lookups by name and type, and upstream exchanges by the query sent and payload size
+    in_flight_lookups: InFlight<(String, RecordType), Result<LookupResult, LookupError>>,
+    in_flight_forwards: InFlight<(Vec<u8>, u16), Option<ForwardedAnswer>>,
+    upstream_cache: UpstreamCache,
+    buffers: BufferPool,
+    // One permit per database lookup allowed to run at once
+    db_permits: Semaphore,
+    max_db_concurrency: usize,
+    db_wait: Duration,
+    // Seconds past expiry a cache entry may still be answered from while the database is unusable
+    stale_max_age: u64,
+    // Queries for prefetch_entries to refresh, when prefetching is on
+    prefetch: Option<mpsc::Sender<Query>>,
+    prefetch_min_hits: u64,
+    // Becomes true when the server starts shutting down
+    shutdown: watch::Receiver<bool>,
+}
+
+// Packet buffers kept for reuse, so neither a UDP query's copy of the request nor the response
+// serialized for it allocates. Both are handed back once the response is sent
+struct BufferPool {
+    buffers: crossbeam_queue::ArrayQueue<Vec<u8>>,
+}
+
+impl BufferPool {
+    fn new(size: usize) -> Self {
+        BufferPool { buffers: crossbeam_queue::ArrayQueue::new(size.max(1)) }
+    }
+
+    // An empty buffer, which grows as needed for large EDNS payloads
+    fn take(&self) -> Vec<u8> {
+        self.buffers.pop().unwrap_or_else(|| Vec::with_capacity(512))
+    }
+
+    // `message` serialized into a buffer from the pool
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, trust_dns_proto::error::ProtoError> {
+        let mut buffer = self.take();
+        message.emit(&mut BinEncoder::new(&mut buffer))?;
+        Ok(buffer)
+    }
+
+    // Keep a buffer for the next query, unless the pool is full or the buffer is oversized
+    fn give(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() <= MAX_POOLED_BUFFER {
+            buffer.clear();
+            let _ = self.buffers.push(buffer);
+        }
+    }
+}
+
+// Name of the upstream server that answered a forwarded query, and its answer
+type ForwardedAnswer = (String, Vec<u8>);
+
+// Work in progress by key, so identical queries that arrive meanwhile wait for its result
+// instead of repeating the database query or upstream exchange
+struct InFlight<K, V> {
+    // Each entry is numbered, so a waiter that gives up only removes the one it waited for
+    pending: std::sync::Mutex<HashMap<K, (u64, broadcast::Sender<V>)>>,
+    next_id: AtomicU64,
+}
+
+impl<K: std::hash::Hash + Eq + Clone, V: Clone> InFlight<K, V> {
+    fn new() -> Self {
+        InFlight { pending: std::sync::Mutex::new(HashMap::new()), next_id: AtomicU64::new(0) }
+    }
+
+    // Run `work` for `key`, or wait for the run already in progress and take its result. A run
+    // that is dropped or takes longer than MAX_IN_FLIGHT_WAIT is forgotten, and its waiters do
+    // the work themselves.
+    async fn run<F: Future<Output = V>>(&self, key: K, work: impl FnOnce() -> F) -> V {
+        let waiting = {
+            let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+            match pending.get(&key) {
+                Some((id, sender)) => Some((*id, sender.subscribe())),
+                None => {
+                    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                    pending.insert(key.clone(), (id, broadcast::channel(1).0));
+                    None
+                }
+            }
+        };
+
+        if let Some((id, mut receiver)) = waiting {
+            match tokio::time::timeout(MAX_IN_FLIGHT_WAIT, receiver.recv()).await {
+                Ok(Ok(value)) => return value,
+                Ok(Err(_)) => {}
+                Err(_) => {
+                    let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+                    if pending.get(&key).is_some_and(|(pending_id, _)| *pending_id == id) {
+                        warn!("Lookup in flight for over {:?}, no longer waiting for it", MAX_IN_FLIGHT_WAIT);
+                        pending.remove(&key);
+                    }
+                }
+            }
+            return work().await;
+        }
+
+        let mut running = InFlightRun { pending: &self.pending, key: Some(key) };
+        let value = work().await;
+        let key = running.key.take().unwrap();
+        if let Some((_, sender)) = self.pending.lock().unwrap_or_else(|e| e.into_inner()).remove(&key) {
+            let _ = sender.send(value.clone());
+        }
+        value
+    }
+}
+
+// Forgets a run in progress when it is dropped before finishing, which wakes its waiters
+struct InFlightRun<'a, K: std::hash::Hash + Eq, V> {
+    pending: &'a std::sync::Mutex<HashMap<K, (u64, broadcast::Sender<V>)>>,
+    key: Option<K>,
+}
+
+impl<K: std::hash::Hash + Eq, V> Drop for InFlightRun<'_, K, V> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.pending.lock().unwrap_or_else(|e| e.into_inner()).remove(&key);
+        }
+    }
+}
+
+// Counters for data and network problems
+#[derive(Default)]
+struct Metrics {
+    // Rows whose value can't be turned into a record of their type
+    invalid_rows: AtomicU64,
+    // Datagrams on an upstream socket that weren't the answer to a query we sent
+    spoof_dropped: AtomicU64,
+    // Upstream exchanges that got no answer within upstream_timeout_ms
+    upstream_timeouts: AtomicU64,
+    // UDP answers dropped or truncated by response rate limiting
+    rate_limited: AtomicU64,
+    // Lookups that gave up waiting for a database permit
+    db_busy: AtomicU64,
+    // Popular names whose cache entries were refreshed before they expired
+    prefetches: AtomicU64,
+    // Lookups answered from the database or upstream cache, and those that missed both
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    // Database lookups that found rows, and those that failed to connect or query
+    db_hits: AtomicU64,
+    db_errors: AtomicU64,
+    // Queries sent to upstream servers, however many servers were tried
+    upstream_forwards: AtomicU64,
+    // Responses written to clients over any transport
+    responses_sent: AtomicU64,
+}
+
+// The query counters at one point in time, as logged every stats_interval and returned by
+// Server::stats
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub db_hits: u64,
+    pub db_errors: u64,
+    pub upstream_forwards: u64,
+    pub upstream_timeouts: u64,
+    pub responses_sent: u64,
+    // Entries in the database cache and their approximate bytes, when it is in this process
+    pub cache_size: Option<(usize, usize)>,
+    // Each database with whether it is taken for down (see HealthCheckedSource), and their calls
+    // that ran out of time
+    pub db_health: Vec<(String, bool)>,
+    pub db_timeouts: u64,
+}
+
+impl Metrics {
+    fn count(&self, counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self, cache_size: Option<(usize, usize)>, databases: &[HealthCheckedSource]) -> Stats {
+        Stats {
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            db_hits: self.db_hits.load(Ordering::Relaxed),
+            db_errors: self.db_errors.load(Ordering::Relaxed),
+            upstream_forwards: self.upstream_forwards.load(Ordering::Relaxed),
+            upstream_timeouts: self.upstream_timeouts.load(Ordering::Relaxed),
+            responses_sent: self.responses_sent.load(Ordering::Relaxed),
+            cache_size,
+            db_health: databases.iter().map(|database| (database.name.clone(), database.is_down())).collect(),
+            db_timeouts: databases.iter().map(HealthCheckedSource::timeouts).sum(),
+        }
+    }
+}
+
+impl LookupContext<'_> {
+    // Resolves once the server starts shutting down
+    async fn shutting_down(&self) {
+        let mut shutdown = self.shutdown.clone();
+        let _ = shutdown.wait_for(|&stopping| stopping).await;
+    }
+
+    // A turn at the database, to hold while using a connection. None when all
+    // max_db_concurrency permits stay taken for db_wait_ms, so a burst of misses can't queue up
+    // behind the connection pool
+    async fn db_permit(&self) -> Option<SemaphorePermit<'_>> {
+        if let Ok(Ok(permit)) = tokio::time::timeout(self.db_wait, self.db_permits.acquire()).await {
+            return Some(permit);
+        }
+        let busy = self.metrics.db_busy.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!("All {} database permits in use for {:?}, not waiting ({} times so far)", self.max_db_concurrency, self.db_wait, busy);
+        None
+    }
+
+    // Database lookups running now
+    fn db_in_use(&self) -> usize {
+        self.max_db_concurrency - self.db_permits.available_permits()
+    }
+
+    // Negative answers are cached for the enclosing zone's SOA minimum, or negative_ttl outside zones
+    fn negative_ttl_for(&self, name: &Name) -> u32 {
+        find_zone(self.zones, name).map_or(self.negative_ttl, |zone| zone.minimum)
+    }
+
+    // The upstream server of the longest forward zone `name` is in, if any. Names match whole
+    // labels, so corp.internal covers a.corp.internal but not notcorp.internal
+    fn forward_zone(&self, name: &Name) -> Option<(&Name, &Upstream)> {
+        self.forward_zones
+            .iter()
+            .filter(|(zone, _)| zone.zone_of(name))
+            .max_by_key(|(zone, _)| zone.num_labels())
+            .map(|(zone, upstream)| (zone, upstream))
+    }
+
+    // Upstream servers to try, starting with the current one and wrapping around
+    fn upstreams_in_order(&self) -> impl Iterator<Item = (usize, &Upstream)> {
+        let current = self.current_upstream.load(Ordering::Relaxed);
+        let count = self.upstreams.len();
+        (0..count).map(move |i| (current + i) % count).map(|index| (index, &self.upstreams[index]))
+    }
+
+    fn upstream_succeeded(&self, index: usize, elapsed: Duration) {
+        self.upstreams[index].succeeded(elapsed);
+    }
+
+    // A server that times out or fails stops being tried first until probe_upstreams sees it answer
+    fn upstream_failed(&self, index: usize, reason: &str) {
+        self.upstreams[index].failed(reason);
+
+        let next = (index + 1) % self.upstreams.len();
+        if next != index && self.current_upstream.compare_exchange(index, next, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+            warn!("Switching to upstream DNS {}", self.upstreams[next].name);
+        }
+    }
+
+    // Log an upstream exchange that ran out of time, with the running count
+    fn upstream_timed_out(&self, what: &str) {
+        let timeouts = self.metrics.upstream_timeouts.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!("Upstream DNS timed out on {} ({} timeouts so far)", what, timeouts);
+    }
+
+    // The type to look a query up by, when the data source looks names up one type at a time;
+    // ANY queries still want every type
+    fn lookup_type(&self, qtype: RecordType) -> Option<String> {
+        (self.db.by_type() && qtype != RecordType::ANY).then(|| record_type_name(qtype))
+    }
+
+    // Name as the database and cache key it: lowercase without the trailing dot, in the xn--
+    // form as received or in Unicode with idn_unicode. Answers keep the client's spelling.
+    fn lookup_name(&self, name: &Name) -> String {
+        let ascii = name.to_lowercase().to_ascii().trim_end_matches('.').to_string();
+        if !self.idn_unicode {
+            return ascii;
+        }
+
+        let (unicode, result) = idna::domain_to_unicode(&ascii);
+        if result.is_err() {
+            warn!("Invalid IDN {}, looking it up as received", ascii);
+            return ascii;
+        }
+        if unicode != ascii {
+            info!("IDN {} is {}", ascii, unicode);
+        }
+        unicode
+    }
+}
+
+// Rows of the closest wildcard (`*.example.com`) covering a name, stripping at most
+// `wildcard_depth` leading labels
+async fn find_wildcard(name: &Name, record_type: Option<&str>, ctx: &LookupContext<'_>) -> Vec<DnsRecord> {
+    let mut ancestor = name.base_name();
+
+    for _ in 0..ctx.wildcard_depth {
+        if ancestor.is_root() {
+            break;
+        }
+
+        let wildcard = format!("*.{}", ctx.lookup_name(&ancestor));
+        match ctx.db.lookup(&wildcard, record_type).await {
+            Ok(entries) if !entries.is_empty() => {
+                info!("Wildcard {} matches {}", wildcard, name);
+                return entries;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("Database query error: {}", e);
+                break;
+            }
+        }
+
+        ancestor = ancestor.base_name();
+    }
+
+    Vec::new()
+}
+
+// Whether we hold any override for a name, regardless of type
+async fn name_exists(name: &Name, ctx: &LookupContext<'_>, cache: &dyn CacheStore) -> bool {
+    let qname = ctx.lookup_name(name);
+    if cache.has_name(&qname).await {
+        return true;
+    }
+
+    let Some(_db_permit) = ctx.db_permit().await else { return false };
+    match ctx.db.lookup(&qname, None).await {
+        Ok(entries) if !entries.is_empty() => {
+            cache_rows(cache, &qname, None, entries).await;
+            true
+        }
+        Ok(_) => false,
+        Err(e) => {
+            warn!("Database query error: {}", e);
+            false
+        }
+    }
+}
+
+// Closest enclosing DNAME (owner, target, ttl) for a name without an override of its own
+async fn find_dname(
+    name: &Name,
+    ctx: &LookupContext<'_>,
+    cache: &dyn CacheStore,
+) -> Option<(Name, Name, u32)> {
+    let mut db_permit = None;
+    let mut ancestor = name.base_name();
+    let record_type = ctx.db.by_type().then_some("DNAME");
+
+    while !ancestor.is_root() {
+        let key = ctx.lookup_name(&ancestor);
+
+        if let Some(cached) = cache.get(&cache_key(&key, "DNAME")).await {
+            let cached = cached.first()?;
+            return parse_target_name(&cached.value).map(|target| (ancestor, target, cached.ttl));
+        }
+
+        if db_permit.is_none() {
+            db_permit = Some(ctx.db_permit().await?);
+        }
+        let entries = match ctx.db.lookup(&key, record_type).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Database query error: {}", e);
+                return None;
+            }
+        };
+
+        if let Some(dname) = entries.iter().find(|entry| entry.record_type == "DNAME") {
+            let target = parse_target_name(&dname.value).map(|target| (ancestor, target, dname.ttl));
+            cache_rows(cache, &key, record_type, entries).await;
+            return target;
+        }
+
+        ancestor = ancestor.base_name();
+    }
+
+    None
+}
+
+// Answer a name below a DNAME owner: the DNAME itself, a synthesized CNAME (RFC 6672 section 3.3)
+// and whatever the rewritten name resolves to
+async fn follow_dname(
+    query: &Query,
+    (owner, target, ttl): (Name, Name, u32),
+    ctx: &LookupContext<'_>,
+    cache: &dyn CacheStore,
+    depth: usize,
+) -> Result<Vec<Record>, LookupError> {
+    let prefix_len = (query.name().num_labels() - owner.num_labels()) as usize;
+    let rewritten = Name::from_labels(query.name().iter().take(prefix_len))
+        .and_then(|prefix| prefix.append_domain(&target))
+        .map_err(|e| LookupError::InvalidRecord(format!("DNAME substitution for {} failed: {}", query.name(), e)))?;
+
+    info!("DNAME {} -> {} rewrites {} to {}", owner, target, query.name(), rewritten);
+
+    let mut records = Vec::new();
+    records.extend(dname_rdata(&target).map(|rdata| Record::from_rdata(owner, ttl, rdata)));
+    records.push(Record::from_rdata(query.name().clone(), ttl, RData::CNAME(CNAME(rewritten.clone()))));
+
+    if query.query_type() != RecordType::CNAME {
+        let rewritten_query = Query::query(rewritten, query.query_type());
+        records.extend(handle_query_recursive(rewritten_query, ctx, cache, depth + 1).await.map_err(|e| e.after(&records))?);
+    }
+
+    Ok(records)
+}
+
+// Flatten an ALIAS/ANAME at the apex: resolve the target's addresses (database first, then upstream)
+// and answer them under the original name, caching the flattened result there
+async fn flatten_alias(
+    query: &Query,
+    target: &str,
+    alias_ttl: u32,
+    ctx: &LookupContext<'_>,
+    cache: &dyn CacheStore,
+    depth: usize,
+) -> Result<Vec<Record>, LookupError> {
+    let qtype = query.query_type();
+    let target = parse_target_name(target)
+        .ok_or_else(|| LookupError::InvalidRecord(format!("malformed ALIAS target {:?} for {}", target, query.name())))?;
+    let target_query = Query::query(target.clone(), qtype);
+
+    let mut target_records: Vec<Record> = handle_query_recursive(target_query.clone(), ctx, cache, depth + 1)
+        .await?
+        .into_iter()
+        .filter(|record| record.record_type() == qtype)
+        .collect();
+    if target_records.is_empty() {
+        target_records = resolve_upstream(target_query, ctx).await;
+    }
+
+    let ttl = target_records.iter().map(|record| record.ttl()).fold(alias_ttl, u32::min);
+    let records: Vec<Record> = target_records
+        .iter()
+        .filter_map(|record| record.data().cloned())
+        .map(|rdata| Record::from_rdata(query.name().clone(), ttl, rdata))
+        .collect();
+
+    info!("Flattened ALIAS {} -> {}: {:?}", query.name(), target, records);
+
+    let qname = ctx.lookup_name(query.name());
+    let flattened: Vec<DnsRecord> = records
+        .iter()
+        .filter_map(|record| record.data().and_then(|rdata| rdata.ip_addr()))
+        .map(|address| DnsRecord {
+            record_type: record_type_name(qtype),
+            value: address.to_string(),
+            ttl,
+            weight: None,
+            inserted_at: Some(now_secs()),
+        })
+        .collect();
+    if !flattened.is_empty() {
+        cache.insert(cache_key(&qname, &record_type_name(qtype)), flattened).await;
+        cache.save().await;
+    }
+
+    Ok(records)
+}
+
+// Records for the target of a CNAME. A target we don't know locally is resolved upstream, so the
+// answer doesn't end at a CNAME into someone else's zone; targets in our own zones never are.
+async fn follow_cname(
+    owner: &Name,
+    target: &Name,
+    qtype: RecordType,
+    ctx: &LookupContext<'_>,
+    cache: &dyn CacheStore,
+    depth: usize,
+) -> Result<Vec<Record>, LookupError> {
+    if target == owner {
+        warn!("{} is a CNAME to itself", owner);
+        return Err(LookupError::BrokenChain(Vec::new()));
+    }
+
+    let target_query = Query::query(target.clone(), cname_target_type(qtype));
+    let records = handle_query_recursive(target_query.clone(), ctx, cache, depth).await?;
+    if !records.is_empty() || cache.has_name(&ctx.lookup_name(target)).await || find_zone(ctx.zones, target).is_some() {
+        return Ok(records);
+    }
+
+    info!("CNAME target {} is not known locally, asking upstream", target);
+    Ok(resolve_upstream_chain(target_query, ctx).await)
+}
+
+// Answer records for a name's entries, from the cache or the database alike. Entries of the
+// queried type are answered as they are; a CNAME is answered together with what its target
+// resolves to, and an ALIAS is flattened into addresses.
+async fn entry_records(
+    query: &Query,
+    entries: &[DnsRecord],
+    ctx: &LookupContext<'_>,
+    cache: &dyn CacheStore,
+    depth: usize,
+) -> Result<Vec<Record>, LookupError> {
+    let name = query.name();
+    let qtype = query.query_type();
+    let mut records = Vec::new();
+
+    for entry in entries {
+        let (record_type, value, ttl) = (&entry.record_type, &entry.value, entry.remaining_ttl(ctx.min_answer_ttl));
+
+        // A negative entry stands for an empty answer
+        if entry.is_negative() {
+            continue;
+        }
+
+        if let Some(record) = build_record(name.clone(), ttl, record_type, value, qtype)? {
+            records.push(record);
+        } else if record_type == "CNAME" {
+            // Targets are absolute whether or not they are stored with the trailing dot
+            let cname = parse_target_name(value)
+                .ok_or_else(|| LookupError::InvalidRecord(format!("malformed CNAME target {:?} for {}", value, name)))?;
+            records.push(Record::from_rdata(name.clone(), ttl, RData::CNAME(CNAME(cname.clone()))));
+
+            // Follow the CNAME to its addresses, locally or upstream
+            records.extend(follow_cname(name, &cname, qtype, ctx, cache, depth + 1).await.map_err(|e| e.after(&records))?);
+        } else if record_type == "ALIAS" && matches!(qtype, RecordType::A | RecordType::AAAA) {
+            records.extend(flatten_alias(query, value, ttl, ctx, cache, depth).await?);
+        }
+    }
+
+    Ok(records)
+}
+
+// Define a helper type for a boxed future
+type BoxedFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<Record>, LookupError>> + Send + 'a>>;
+
+fn handle_query_recursive<'a>(
+    query: Query,
+    ctx: &'a LookupContext<'a>,
+    cache: &'a dyn CacheStore,
+    depth: usize,
+) -> BoxedFuture<'a> {
+    Box::pin(async move {
+        let mut records = Vec::new();
+        let qname = ctx.lookup_name(query.name());
+        let qtype = query.query_type();
+        let record_type = ctx.lookup_type(qtype);
+
+        if depth > ctx.max_chain_depth {
+            warn!("Chain for {} exceeds {} links, stopping", qname, ctx.max_chain_depth);
+            return Err(LookupError::BrokenChain(records));
+        }
+
+        info!("Handling query: {} {:?}", qname, qtype);
+
+        // Step 1: Check the cache first
+        let cached = cache
+            .get_any(&[cache_key(&qname, &record_type_name(qtype)), cache_key(&qname, "CNAME"), cache_key(&qname, "ALIAS")])
+            .await;
+        if let Some(cached) = cached {
+            let entries = select_entries(cached, ctx);
+            return entry_records(&query, &entries, ctx, cache, depth).await;
+        }
+
+        // A cached NXDOMAIN means the database has nothing for this name
+        if cache.get(&nxdomain_key(&qname, record_type.as_deref())).await.is_some() {
+            return Ok(records);
+        }
+
+        // Step 2: Query the database. Without it, a link answers from an expired cache entry as
+        // lookup_query does, and otherwise ends the chain
+        let Some(db_permit) = ctx.db_permit().await else { return Ok(records) };
+        let entries = match ctx.db.lookup(&qname, record_type.as_deref()).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                database_failed(&query, &qname, e, ctx);
+                drop(db_permit);
+                let keys = [cache_key(&qname, &record_type_name(qtype)), cache_key(&qname, "CNAME"), cache_key(&qname, "ALIAS")];
+                return match stale_lookup(&query, &keys, ctx, cache).await? {
+                    Some(LookupResult::Records(records)) => Ok(records),
+                    _ => Err(LookupError::DatabaseDown(records)),
+                };
+            }
+        };
+
+        // Fall back to a wildcard override; an exact match always wins
+        let entries = if entries.is_empty() {
+            find_wildcard(query.name(), record_type.as_deref(), ctx).await
+        } else {
+            entries
+        };
+        // Following the records may need the database again
+        drop(db_permit);
+
+        if !entries.is_empty() {
+            // Update the cache, wildcard matches under the concrete name
+            let entries = cache_rows(cache, &qname, record_type.as_deref(), entries).await;
+
+            let entries = select_entries(entries, ctx);
+            records.extend(entry_records(&query, &entries, ctx, cache, depth).await?);
+        } else if let Some(dname) = find_dname(query.name(), ctx, cache).await {
+            // A DNAME on an ancestor rewrites the whole subtree
+            records.extend(follow_dname(&query, dname, ctx, cache, depth).await?);
+        } else {
+            // No result, cache the miss instead of asking the database again
+            cache_nxdomain(cache, &qname, record_type.as_deref(), ctx.negative_ttl_for(query.name())).await;
+        }
+
+        Ok(records)
+    })
+}
+
+// Look a query up locally. Identical queries that arrive while the lookup runs share its result;
+// the name's case counts, as the records carry it.
+async fn handle_query(
+    query: Query,
+    ctx: &LookupContext<'_>,
+    cache: &dyn CacheStore,
+) -> Result<LookupResult, LookupError> {
+    let key = (query.name().to_ascii(), query.query_type());
+    ctx.in_flight_lookups.run(key, || lookup_query(query, ctx, cache)).await
+}
+
+// Log and count a failed database lookup, and queue the query for prefetch_entries so the name is
+// refreshed as soon as the database answers again rather than on the next query for it
+fn database_failed(query: &Query, qname: &str, e: DbError, ctx: &LookupContext<'_>) {
+    match e {
+        DbError::Connection(e) => warn!("Database connection failed for {}: {}", qname, e),
+        e => warn!("Database query error for {}: {}", qname, e),
+    }
+    ctx.metrics.count(&ctx.metrics.db_errors);
+    if let Some(prefetch) = &ctx.prefetch {
+        if prefetch.try_send(query.clone()).is_err() {
+            debug!("Prefetch queue full, not scheduling a refresh of {}", qname);
+        }
+    }
+}
+
+// Answer a query from expired cache entries under `keys` when the database can't be asked,
+// with the TTL cut to STALE_TTL so clients come back soon. Entries that expired more than
+// stale_max_age ago are not used; None when there is nothing to answer from
+async fn stale_lookup(
+    query: &Query,
+    keys: &[String],
+    ctx: &LookupContext<'_>,
+    cache: &dyn CacheStore,
+) -> Result<Option<LookupResult>, LookupError> {
+    let Some(stale) = cache.get_any_stale(keys, ctx.stale_max_age).await else { return Ok(None) };
+    warn!("Answering {} from an expired cache entry", query.name());
+    // Without inserted_at the cut TTL is answered in full rather than counted down
+    let stale = stale
+        .into_iter()
+        .map(|entry| DnsRecord { ttl: entry.ttl.min(STALE_TTL), inserted_at: None, ..entry })
+        .collect();
+    let entries = select_entries(stale, ctx);
+    let records = entry_records(query, &entries, ctx, cache, 0).await?;
+    if records.is_empty() {
+        return Ok(Some(LookupResult::NoData));
+    }
+    Ok(Some(LookupResult::Records(records)))
+}
+
+async fn lookup_query(
+    query: Query,
+    ctx: &LookupContext<'_>,
+    cache: &dyn CacheStore,
+) -> Result<LookupResult, LookupError> {
+    let mut records = Vec::new();
+    let qname = ctx.lookup_name(query.name());
+    let qtype = query.query_type();
+    let record_type = ctx.lookup_type(qtype);
+
+    info!("Handling query: {} {:?}", qname, qtype);
+
+    // Check the cache first
+    let keys = [cache_key(&qname, &record_type_name(qtype)), cache_key(&qname, "CNAME"), cache_key(&qname, "ALIAS")];
+    if let Some(cached) = cache.get_any(&keys).await {
+        info!("Cache hit for {}: {:?}", qname, cached);
+        ctx.metrics.count(&ctx.metrics.cache_hits);
+        if let Some(prefetch) = &ctx.prefetch {
+            if cache.claim_prefetch(&keys, ctx.prefetch_min_hits).await && prefetch.try_send(query.clone()).is_err() {
+                debug!("Prefetch queue full, not refreshing {}", qname);
+            }
+        }
+
+        let entries = select_entries(cached, ctx);
+        let records = entry_records(&query, &entries, ctx, cache, 0).await?;
+        if records.is_empty() {
+            return Ok(LookupResult::NoData);
+        }
+        return Ok(LookupResult::Records(records));
+    }
+
+    // Other types are cached for this name, so it exists without the queried type. When names
+    // are looked up by type, the other types don't tell
+    if !ctx.db.by_type() && cache.has_name(&qname).await {
+        info!("Cache hit for {}: no {} records", qname, qtype);
+        ctx.metrics.count(&ctx.metrics.cache_hits);
+        return Ok(LookupResult::NoData);
+    }
+
+    // A cached NXDOMAIN means the database has nothing for this name
+    if cache.get(&nxdomain_key(&qname, record_type.as_deref())).await.is_some() {
+        info!("Negative cache hit for {}", qname);
+        ctx.metrics.count(&ctx.metrics.cache_hits);
+        return Ok(LookupResult::NotFound);
+    }
+    ctx.metrics.count(&ctx.metrics.cache_misses);
+
+    // Query the database. When it is too busy, an expired cache entry beats queueing for it
+    let Some(db_permit) = ctx.db_permit().await else {
+        info!("Database busy for {}", qname);
+        return stale_lookup(&query, &keys, ctx, cache).await?.ok_or(LookupError::DatabaseBusy);
+    };
+    // Without the database, overridden names keep their last known answer instead of falling
+    // through to the public one upstream; the next query for them tries the database again
+    let entries = match ctx.db.lookup(&qname, record_type.as_deref()).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            database_failed(&query, &qname, e, ctx);
+            drop(db_permit);
+            return Ok(stale_lookup(&query, &keys, ctx, cache).await?.unwrap_or(LookupResult::NotFound));
+        }
+    };
+
+    // Fall back to a wildcard override; an exact match always wins
+    let entries = if entries.is_empty() {
+        find_wildcard(query.name(), record_type.as_deref(), ctx).await
+    } else {
+        entries
+    };
+    // Following the records may need the database again
+    drop(db_permit);
+
+    if !entries.is_empty() {
+        ctx.metrics.count(&ctx.metrics.db_hits);
+        // Update the cache, wildcard matches under the concrete name
+        let entries = cache_rows(cache, &qname, record_type.as_deref(), entries).await;
+
+        let entries = select_entries(entries, ctx);
+        records.extend(entry_records(&query, &entries, ctx, cache, 0).await?);
+        if records.is_empty() {
+            cache_nodata(cache, &qname, qtype, ctx.negative_ttl_for(query.name())).await;
+            return Ok(LookupResult::NoData);
+        }
+    } else if let Some(dname) = find_dname(query.name(), ctx, cache).await {
+        // A DNAME on an ancestor rewrites the whole subtree
+        records.extend(follow_dname(&query, dname, ctx, cache, 0).await?);
+    } else {
+        // No result, cache the miss instead of asking the database again
+        cache_nxdomain(cache, &qname, record_type.as_deref(), ctx.negative_ttl_for(query.name())).await;
+        return Ok(LookupResult::NotFound);
+    }
+
+    Ok(LookupResult::Records(records))
+}
+
+// Secrets used to make server cookies
+struct CookieSecrets {
+    current: [u8; 16],
+    previous: Option<[u8; 16]>,
+    rotated_at: Instant,
+}
+
+// Server cookies as in RFC 9018: version, reserved bytes, timestamp, and a SipHash-2-4 over the
+// client cookie, those fields and the client address, keyed with a secret that rotates
+struct DnsCookies {
+    rotation: Duration,
+    rate_limit: Option<u32>,
+    secrets: std::sync::Mutex<CookieSecrets>,
+    // Queries without a valid cookie per source address, counted for the current second only
+    uncookied: std::sync::Mutex<(u64, HashMap<IpAddr, u32>)>,
+}
+
+impl DnsCookies {
+    const VERSION: u8 = 1;
+
+    fn new(config: &DnsCookiesConfig) -> Self {
+        DnsCookies {
+            rotation: Duration::from_secs(config.secret_rotation),
+            rate_limit: config.rate_limit,
+            secrets: std::sync::Mutex::new(CookieSecrets { current: rand::random(), previous: None, rotated_at: Instant::now() }),
+            uncookied: std::sync::Mutex::new((0, HashMap::new())),
+        }
+    }
+
+    // The current and previous secret, rotating first when the current one is due
+    fn secrets(&self) -> ([u8; 16], Option<[u8; 16]>) {
+        let mut secrets = self.secrets.lock().unwrap_or_else(|e| e.into_inner());
+        if secrets.rotated_at.elapsed() >= self.rotation {
+            secrets.previous = Some(secrets.current);
+            secrets.current = rand::random();
+            secrets.rotated_at = Instant::now();
+            info!("Rotated DNS cookie secret");
+        }
+        (secrets.current, secrets.previous)
+    }
+
+    fn hash(secret: &[u8; 16], client_cookie: &[u8], header: &[u8], ip: IpAddr) -> [u8; 8] {
+        let mut hasher = SipHasher24::new_with_key(secret);
+        hasher.write(client_cookie);
+        hasher.write(header);
+        match ip {
+            IpAddr::V4(ip) => hasher.write(&ip.octets()),
+            IpAddr::V6(ip) => hasher.write(&ip.octets()),
+        }
+        hasher.finish().to_le_bytes()
+    }
+
+    // Client cookie followed by a fresh server cookie, as the COOKIE option data of a response
+    fn reply(&self, client_cookie: &[u8], ip: IpAddr) -> Vec<u8> {
+        let (secret, _) = self.secrets();
+        let mut cookie = client_cookie.to_vec();
+        cookie.extend([Self::VERSION, 0, 0, 0]);
+        cookie.extend((now_secs() as u32).to_be_bytes());
+        let hash = Self::hash(&secret, client_cookie, &cookie[8..16], ip);
+        cookie.extend(hash);
+        cookie
+    }
+
+    // A server cookie is accepted when it was made for this client cookie and address within the
+    // last hour (allowing five minutes of clock skew), with the current or the previous secret
+    fn verify(&self, client_cookie: &[u8], server_cookie: &[u8], ip: IpAddr) -> bool {
+        if server_cookie.len() != 16 || server_cookie[0] != Self::VERSION {
+            return false;
+        }
+        let (header, hash) = server_cookie.split_at(8);
+        let timestamp = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let now = now_secs() as u32;
+        if timestamp.wrapping_sub(now) as i32 > 300 || now.wrapping_sub(timestamp) as i32 > 3600 {
+            return false;
+        }
+        let (current, previous) = self.secrets();
+        std::iter::once(current)
+            .chain(previous)
+            .any(|secret| Self::hash(&secret, client_cookie, header, ip) == hash)
+    }
+
+    // Count a query without a valid cookie; false once the source is over the limit this second
+    fn within_rate_limit(&self, ip: IpAddr) -> bool {
+        let Some(limit) = self.rate_limit else { return true };
+        let now = now_secs();
+        let mut uncookied = self.uncookied.lock().unwrap_or_else(|e| e.into_inner());
+        if uncookied.0 != now {
+            *uncookied = (now, HashMap::new());
+        }
+        let count = uncookied.1.entry(ip).or_insert(0);
+        *count += 1;
+        *count <= limit
+    }
+}
+
+// What response rate limiting does with an answer
+#[derive(PartialEq, Debug)]
+enum RateLimitAction {
+    Send,
+    // Send it empty and truncated instead
+    Slip,
+    Drop,
+}
+
+// Answers sent to one source prefix for one name and response code, kept as in BIND's RRL: the
+// balance gains responses_per_second each second up to that many, every answer takes one, and
+// answers are limited while it is below zero
+struct RateLimitAccount {
+    balance: i64,
+    updated: u64,
+    limited: u32,
+}
+
+struct RateLimiter {
+    responses_per_second: i64,
+    window: i64,
+    slip: u32,
+    prefix_v4: u8,
+    prefix_v6: u8,
+    exempt_clients: Vec<(IpAddr, u8)>,
+    accounts: std::sync::Mutex<HashMap<(IpAddr, Name, u16), RateLimitAccount>>,
+}
+
+impl RateLimiter {
+    // Accounts are pruned of idle entries once there are this many
+    const PRUNE_AT: usize = 10_000;
+
+    fn new(config: &RateLimitConfig) -> Result<Self, String> {
+        let exempt_clients = config
+            .exempt_clients
+            .iter()
+            .map(|cidr| parse_cidr(cidr).ok_or_else(|| format!("invalid rate_limit exempt_clients entry '{}'", cidr)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(RateLimiter {
+            responses_per_second: i64::from(config.responses_per_second),
+            window: config.window.max(1) as i64,
+            slip: config.slip,
+            prefix_v4: config.prefix_v4,
+            prefix_v6: config.prefix_v6,
+            exempt_clients,
+            accounts: std::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Charge an answer to `client`'s account and decide whether it goes out as it is
+    fn check(&self, client: IpAddr, response: &Message) -> RateLimitAction {
+        if self.exempt_clients.iter().any(|network| cidr_contains(network, client)) {
+            return RateLimitAction::Send;
+        }
+        let prefix = if client.is_ipv4() { self.prefix_v4 } else { self.prefix_v6 };
+        let network = network_address(client, prefix);
+        let name = response.queries().first().map_or_else(Name::root, |query| query.name().to_lowercase());
+        let key = (network, name.clone(), u16::from(response.response_code()));
+
+        let now = now_secs();
+        let mut accounts = self.accounts.lock().unwrap_or_else(|e| e.into_inner());
+        if accounts.len() >= Self::PRUNE_AT {
+            let window = self.window as u64;
+            accounts.retain(|_, account| now.saturating_sub(account.updated) <= window);
+        }
+        let account = accounts.entry(key).or_insert(RateLimitAccount { balance: self.responses_per_second, updated: now, limited: 0 });
+        let earned = now.saturating_sub(account.updated) as i64 * self.responses_per_second;
+        account.balance = (account.balance + earned).min(self.responses_per_second) - 1;
+        account.balance = account.balance.max(-self.window * self.responses_per_second);
+        account.updated = now;
+        if account.balance >= 0 {
+            account.limited = 0;
+            return RateLimitAction::Send;
+        }
+
+        account.limited += 1;
+        if account.limited == 1 {
+            info!("Rate limiting {} answers for {} to {}/{}", response.response_code(), name, network, prefix);
+        }
+        if self.slip > 0 && account.limited.is_multiple_of(self.slip) {
+            RateLimitAction::Slip
+        } else {
+            RateLimitAction::Drop
+        }
+    }
+
+    // The answer to send to `client` in place of `response`, or None to send nothing
+    fn limit(&self, client: IpAddr, response: Vec<u8>, metrics: &Metrics) -> Option<Vec<u8>> {
+        let Ok(mut message) = Message::from_vec(&response) else { return Some(response) };
+        let action = self.check(client, &message);
+        if action == RateLimitAction::Send {
+            return Some(response);
+        }
+        metrics.rate_limited.fetch_add(1, Ordering::Relaxed);
+        debug!("Rate limited answer to {}: {:?}", client, action);
+        if action == RateLimitAction::Drop {
+            return None;
+        }
+        message.take_answers();
+        message.take_name_servers();
+        message.take_additionals();
+        message.set_truncated(true);
+        message.to_vec().ok()
+    }
+}
+
+// Apply ecs_mode to the EDNS of an upstream query; true when an ECS option of ours was added
+fn apply_ecs(edns: &mut Option<Edns>, config: &Config, client: IpAddr) -> bool {
+    match config.ecs_mode {
+        EcsMode::Forward => false,
+        EcsMode::Strip => {
+            if let Some(edns) = edns {
+                edns.options_mut().remove(EdnsCode::Subnet);
+            }
+            false
+        }
+        EcsMode::Add => {
+            // A client sending a source prefix of 0 asked for its address to be left out
+            let opted_out = match edns.as_ref().and_then(|edns| edns.option(EdnsCode::Subnet)) {
+                Some(EdnsOption::Subnet(subnet)) => Vec::<u8>::try_from(subnet).is_ok_and(|data| data.get(2) == Some(&0)),
+                _ => false,
+            };
+            if opted_out {
+                return false;
+            }
+            let Some(subnet) = client_subnet(client, config) else {
+                if let Some(edns) = edns {
+                    edns.options_mut().remove(EdnsCode::Subnet);
+                }
+                return false;
+            };
+            let edns = edns.get_or_insert_with(|| {
+                let mut edns = Edns::new();
+                edns.set_max_payload(EDNS_PAYLOAD_SIZE);
+                edns
+            });
+            edns.options_mut().insert(EdnsOption::Subnet(subnet));
+            true
+        }
+    }
+}
+
+// The client's network as an ECS option, with the host bits cleared. Loopback and private
+// addresses mean nothing to the upstream server and are not sent
+fn client_subnet(client: IpAddr, config: &Config) -> Option<ClientSubnet> {
+    match client {
+        IpAddr::V4(ip) if ip.is_loopback() || ip.is_private() || ip.is_link_local() => None,
+        IpAddr::V4(_) => {
+            let prefix = config.ecs_prefix_v4.min(32);
+            Some(ClientSubnet::new(network_address(client, prefix), prefix, 0))
+        }
+        IpAddr::V6(ip) if ip.is_loopback() || ip.is_unspecified() => None,
+        IpAddr::V6(_) => {
+            let prefix = config.ecs_prefix_v6.min(128);
+            Some(ClientSubnet::new(network_address(client, prefix), prefix, 0))
+        }
+    }
+}
+
+// `ip` with everything after the first `prefix` bits cleared
+fn network_address(ip: IpAddr, prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix.min(32))).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix.min(128))).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+        }
+    }
+}
+
+// Make the OPT record of an upstream answer match what the client sent us: our cookie goes in,
+// an ECS option we added ourselves comes out, and a client that sent no EDNS gets no OPT
+fn client_view(
+    response: Vec<u8>,
+    client_edns: bool,
+    cookie: &Option<Vec<u8>>,
+    added_ecs: bool,
+    payload_size: u16,
+    buffers: &BufferPool,
+) -> Result<Vec<u8>, trust_dns_proto::error::ProtoError> {
+    if cookie.is_none() && !added_ecs {
+        return Ok(response);
+    }
+    let mut message = Message::from_vec(&response)?;
+    buffers.give(response);
+    if !client_edns {
+        *message.extensions_mut() = None;
+    } else if let Some(edns) = message.extensions_mut() {
+        if added_ecs {
+            edns.options_mut().remove(EdnsCode::Subnet);
+        }
+        if let Some(cookie) = cookie {
+            edns.options_mut().insert(EdnsOption::Unknown(u16::from(EdnsCode::Cookie), cookie.clone()));
+        }
+    }
+    encode_response(&mut message, payload_size, buffers)
+}
+
+// Serialize a response so it fits the client's UDP payload limit: the additional section
+// goes first, and if the answer still doesn't fit it is dropped with TC set so the client
+// retries over TCP
+fn encode_response(response: &mut Message, payload_size: u16, buffers: &BufferPool) -> Result<Vec<u8>, trust_dns_proto::error::ProtoError> {
+    let limit = payload_size as usize;
+    let mut bytes = buffers.encode(response)?;
+    if bytes.len() > limit {
+        response.take_additionals();
+        buffers.give(bytes);
+        bytes = buffers.encode(response)?;
+    }
+    if bytes.len() > limit {
+        info!("Response of {} bytes exceeds {} byte limit, truncating", bytes.len(), limit);
+        response.take_answers();
+        response.take_name_servers();
+        response.set_truncated(true);
+        buffers.give(bytes);
+        bytes = buffers.encode(response)?;
+    }
+    Ok(bytes)
+}
+
+// Target name as stored in the `value` column
+fn db_name(name: &Name) -> String {
+    name.to_string().trim_end_matches('.').to_string()
+}
+
+// Record data in the form the `value` column stores it; None for types we can't store
+fn update_value(rdata: &RData) -> Option<String> {
+    match rdata {
+        RData::A(a) => Some(a.to_string()),
+        RData::AAAA(aaaa) => Some(aaaa.to_string()),
+        RData::CNAME(CNAME(target)) | RData::PTR(PTR(target)) | RData::NS(NS(target)) => Some(db_name(target)),
+        RData::MX(mx) => Some(format!("{} {}", mx.preference(), db_name(mx.exchange()))),
+        RData::TXT(txt) => Some(txt.txt_data().iter().map(|data| String::from_utf8_lossy(data)).collect()),
+        _ => None,
+    }
+}
+
+// "192.0.2.0/24", "2001:db8::/32" or a single address
+fn parse_cidr(cidr: &str) -> Option<(IpAddr, u8)> {
+    match cidr.split_once('/') {
+        Some((addr, prefix)) => Some((addr.parse().ok()?, prefix.parse().ok()?)),
+        None => {
+            let addr: IpAddr = cidr.parse().ok()?;
+            Some((addr, if addr.is_ipv4() { 32 } else { 128 }))
+        }
+    }
+}
+
+fn cidr_contains(&(network, prefix): &(IpAddr, u8), addr: IpAddr) -> bool {
+    match (network, addr) {
+        (IpAddr::V4(network), IpAddr::V4(addr)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix.min(32))).unwrap_or(0);
+            u32::from(network) & mask == u32::from(addr) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(addr)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix.min(128))).unwrap_or(0);
+            u128::from(network) & mask == u128::from(addr) & mask
+        }
+        _ => false,
+    }
+}
+
+// TSIG keys from the configuration
+fn load_tsig_keys(keys: &[TsigKeyConfig]) -> Result<Vec<TSigner>, Box<dyn std::error::Error>> {
+    keys.iter()
+        .map(|key| {
+            let name = Name::from_ascii(&key.name)?;
+            let algorithm = TsigAlgorithm::from_name(Name::from_ascii(&key.algorithm)?);
+            let secret = base64::engine::general_purpose::STANDARD.decode(&key.secret)?;
+            Ok(TSigner::new(secret, algorithm, name, 300)?)
+        })
+        .collect()
+}
+
+// One change to the database from the update section of an UPDATE message
+pub enum UpdateOperation {
+    Add { name: String, record_type: String, value: String },
+    // Without a type the whole name goes; without a value the whole RRset
+    Delete { name: String, record_type: Option<String>, value: Option<String> },
+}
+
+// A condition from the prerequisite section of an UPDATE message (RFC 2136 section 2.4)
+pub enum Prerequisite {
+    // The name has records, of any type
+    NameInUse(String),
+    NameNotInUse(String),
+    // The name has records of the type; with a value, that record among them
+    RrsetExists { name: String, record_type: String, value: Option<String> },
+    RrsetDoesNotExist { name: String, record_type: String },
+}
+
+impl Prerequisite {
+    pub fn name(&self) -> &str {
+        match self {
+            Prerequisite::NameInUse(name) | Prerequisite::NameNotInUse(name) => name,
+            Prerequisite::RrsetExists { name, .. } | Prerequisite::RrsetDoesNotExist { name, .. } => name,
+        }
+    }
+
+    // Whether the condition holds, given every record of its name
+    pub fn holds(&self, records: &[DnsRecord]) -> bool {
+        let has_type = |record_type: &str| records.iter().any(|record| record.record_type == record_type);
+        match self {
+            Prerequisite::NameInUse(_) => !records.is_empty(),
+            Prerequisite::NameNotInUse(_) => records.is_empty(),
+            Prerequisite::RrsetExists { record_type, value: None, .. } => has_type(record_type),
+            Prerequisite::RrsetExists { record_type, value: Some(value), .. } => {
+                records.iter().any(|record| &record.record_type == record_type && &record.value == value)
+            }
+            Prerequisite::RrsetDoesNotExist { record_type, .. } => !has_type(record_type),
+        }
+    }
+
+    // The response code of an update failing on this prerequisite
+    fn failure(&self) -> ResponseCode {
+        match self {
+            Prerequisite::NameInUse(_) => ResponseCode::NXDomain,
+            Prerequisite::NameNotInUse(_) => ResponseCode::YXDomain,
+            Prerequisite::RrsetExists { .. } => ResponseCode::NXRRSet,
+            Prerequisite::RrsetDoesNotExist { .. } => ResponseCode::YXRRSet,
+        }
+    }
+}
+
+// Answer a dynamic update (RFC 2136). It must carry a valid TSIG signature or come from an
+// address in update_allowed; the response is signed with the same key
+async fn answer_update(
+    request: &[u8],
+    message: &Message,
+    src: SocketAddr,
+    config: &Config,
+    ctx: &LookupContext<'_>,
+    cache: &dyn CacheStore,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut response = Message::new();
+    response.set_id(message.id());
+    response.set_message_type(MessageType::Response);
+    response.set_op_code(OpCode::Update);
+    response.add_queries(message.queries().iter().cloned());
+
+    let signer = match verify_tsig(request, message, ctx) {
+        Ok(Some(signer)) => Some(signer),
+        Ok(None) if ctx.update_allowed.iter().any(|network| cidr_contains(network, src.ip())) => None,
+        Ok(None) => {
+            warn!("Refusing unsigned update from {}", src);
+            response.set_response_code(ResponseCode::Refused);
+            return Ok(ctx.buffers.encode(&response)?);
+        }
+        Err(()) => {
+            warn!("Rejecting update from {} with a bad TSIG signature", src);
+            response.set_response_code(ResponseCode::NotAuth);
+            return Ok(ctx.buffers.encode(&response)?);
+        }
+    };
+
+    let response_code = apply_update(message, config, ctx, cache).await;
+    info!("Update from {} answered with {}", src, response_code);
+    response.set_response_code(response_code);
+
+    if let Some((signer, request_mac)) = signer {
+        sign_response(&mut response, signer, &request_mac, true)?;
+    }
+    Ok(ctx.buffers.encode(&response)?)
+}
+
+// Check the TSIG signature of a request against our keys: Ok(None) for an unsigned request,
+// otherwise the key and the request MAC the response must be signed over. Unknown keys, bad
+// MACs and signing times outside the fudge window are errors
+fn verify_tsig<'a>(request: &[u8], message: &Message, ctx: &'a LookupContext<'_>) -> Result<Option<(&'a TSigner, Vec<u8>)>, ()> {
+    let Some(tsig) = message.signature().first() else { return Ok(None) };
+    let signer = ctx.tsig_keys.iter().find(|signer| signer.signer_name() == tsig.name()).ok_or(())?;
+    let (mac, window, _) = signer.verify_message_byte(None, request, true).map_err(|_| ())?;
+    if !(window.start..=window.end).contains(&now_secs()) {
+        return Err(());
+    }
+    Ok(Some((signer, mac)))
+}
+
+// Append a TSIG record to a response and return its MAC. The first message of an answer is signed
+// over the request MAC and all TSIG variables; later messages of a zone transfer only over the
+// previous message's MAC and the timers (RFC 8945 section 5.3.1)
+fn sign_response(response: &mut Message, signer: &TSigner, previous_mac: &[u8], first: bool) -> Result<Vec<u8>, trust_dns_proto::error::ProtoError> {
+    let pre_tsig = TSIG::new(signer.algorithm().clone(), now_secs(), signer.fudge(), Vec::new(), response.id(), 0, Vec::new());
+    let tbs = if first {
+        tsig::message_tbs(Some(previous_mac), response, &pre_tsig, signer.signer_name())?
+    } else {
+        let mut tbs = (previous_mac.len() as u16).to_be_bytes().to_vec();
+        tbs.extend(previous_mac);
+        tbs.extend(response.to_vec()?);
+        tbs.extend(((pre_tsig.time() >> 32) as u16).to_be_bytes());
+        tbs.extend((pre_tsig.time() as u32).to_be_bytes());
+        tbs.extend(pre_tsig.fudge().to_be_bytes());
+        tbs
+    };
+    let mac = signer.sign(&tbs)?;
+    response.add_tsig(tsig::make_tsig_record(signer.signer_name().clone(), pre_tsig.set_mac(mac.clone())));
+    Ok(mac)
+}
+
+// Serial of a zone's SOA: from the data source when it tracks one (with zone_serial_sql), so
+// secondaries notice changes in the database, otherwise the configured serial
+async fn zone_serial(zone: &ZoneConfig, ctx: &LookupContext<'_>) -> u32 {
+    match query_zone_serial(zone, ctx).await {
+        Ok(serial) => serial,
+        Err(e) => {
+            warn!("Zone serial query failed for {}: {}", zone.origin, e);
+            zone.serial
+        }
+    }
+}
+
+async fn query_zone_serial(zone: &ZoneConfig, ctx: &LookupContext<'_>) -> Result<u32, DbError> {
+    Ok(ctx.db.zone_serial(&zone.origin).await?.unwrap_or(zone.serial))
+}
+
+// Poll the serial of every zone with secondaries and send them a NOTIFY (RFC 1996) whenever it
+// differs from the last one announced, starting with the first poll. While the database is
+// down the last serial is kept and nothing is sent
+async fn notify_secondaries(config: &Config, ctx: &LookupContext<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let zones: Vec<(&ZoneConfig, Vec<SocketAddr>)> = config
+        .zones
+        .iter()
+        .filter(|zone| !zone.secondaries.is_empty())
+        .map(|zone| {
+            let secondaries = zone
+                .secondaries
+                .iter()
+                .map(|secondary| {
+                    secondary
+                        .parse::<SocketAddr>()
+                        .or_else(|_| secondary.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+                        .map_err(|_| format!("invalid secondary '{}' for zone {}", secondary, zone.origin))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok::<_, String>((zone, secondaries))
+        })
+        .collect::<Result<_, _>>()?;
+    if zones.is_empty() {
+        return Ok(());
+    }
+
+    let mut last_serials: HashMap<&str, u32> = HashMap::new();
+    let mut interval = tokio::time::interval(Duration::from_secs(config.notify_interval.max(1)));
+    loop {
+        interval.tick().await;
+        for (zone, secondaries) in &zones {
+            let serial = match query_zone_serial(zone, ctx).await {
+                Ok(serial) => serial,
+                Err(e) => {
+                    warn!("Zone serial query failed for {}, not notifying: {}", zone.origin, e);
+                    continue;
+                }
+            };
+            if last_serials.get(zone.origin.as_str()) == Some(&serial) {
+                continue;
+            }
+            info!("Zone {} has serial {}, notifying {} secondaries", zone.origin, serial, secondaries.len());
+            futures::future::join_all(secondaries.iter().map(|secondary| send_notify(zone, serial, *secondary))).await;
+            last_serials.insert(zone.origin.as_str(), serial);
+        }
+    }
+}
+
+// Send a NOTIFY for a zone to one secondary, retrying with exponential backoff until it is
+// acknowledged or five attempts have gone unanswered
+async fn send_notify(zone: &ZoneConfig, serial: u32, secondary: SocketAddr) {
+    let (Some(origin), Some(soa)) = (zone.origin_name(), zone.soa_record_with_serial(serial, zone.ttl)) else { return };
+    let mut notify = Message::new();
+    notify.set_id(rand::random());
+    notify.set_message_type(MessageType::Query);
+    notify.set_op_code(OpCode::Notify);
+    notify.set_authoritative(true);
+    notify.add_query(Query::query(origin, RecordType::SOA));
+    notify.add_answer(soa);
+    let request = match notify.to_vec() {
+        Ok(request) => request,
+        Err(e) => {
+            warn!("Could not encode NOTIFY for {}: {}", zone.origin, e);
+            return;
+        }
+    };
+
+    let mut backoff = Duration::from_secs(1);
+    for attempt in 1..=5 {
+        match exchange_udp(&request, secondary, Duration::from_secs(2)).await.map(|response| Message::from_vec(&response)) {
+            Ok(Ok(response)) if response.op_code() == OpCode::Notify && response.response_code() == ResponseCode::NoError => {
+                info!("Secondary {} acknowledged NOTIFY for {} serial {}", secondary, zone.origin, serial);
+                return;
+            }
+            Ok(Ok(response)) => warn!("Secondary {} answered NOTIFY for {} with {}", secondary, zone.origin, response.response_code()),
+            Ok(Err(e)) => warn!("Malformed NOTIFY answer from {}: {}", secondary, e),
+            Err(e) => warn!("NOTIFY for {} to {} failed (attempt {}): {}", zone.origin, secondary, attempt, e),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    }
+    warn!("Giving up on NOTIFY for {} to {}", zone.origin, secondary);
+}
+
+// Largest amount of record data packed into one zone transfer message
+const AXFR_MESSAGE_SIZE: usize = 16 * 1024;
+
+// Answer an AXFR (RFC 5936) for one of our zones with the SOA, every database row of the zone and
+// the SOA again, split over as many TCP messages as needed. Transfers need a valid TSIG signature
+// or a source address in the zone's allow_transfer
+async fn answer_axfr(
+    request: &[u8],
+    message: &Message,
+    src: SocketAddr,
+    config: &Config,
+    ctx: &LookupContext<'_>,
+) -> Result<Vec<Vec<u8>>, Box<dyn std::error::Error>> {
+    let mut response = Message::new();
+    response.set_id(message.id());
+    response.set_message_type(MessageType::Response);
+    response.set_op_code(OpCode::Query);
+    response.add_queries(message.queries().iter().cloned());
+
+    let Some(zone) = message.queries().first().and_then(|query| {
+        config.zones.iter().find(|zone| zone.origin_name().as_ref() == Some(query.name()))
+    }) else {
+        warn!("Refusing AXFR from {} of a zone not in zones", src);
+        response.set_response_code(ResponseCode::Refused);
+        return Ok(vec![response.to_vec()?]);
+    };
+    let signer = match verify_tsig(request, message, ctx) {
+        Ok(Some(signer)) => Some(signer),
+        Ok(None) if zone.allow_transfer.iter().filter_map(|cidr| parse_cidr(cidr)).any(|network| cidr_contains(&network, src.ip())) => None,
+        Ok(None) => {
+            warn!("Refusing AXFR of {} to {}", zone.origin, src);
+            response.set_response_code(ResponseCode::Refused);
+            return Ok(vec![response.to_vec()?]);
+        }
+        Err(()) => {
+            warn!("Rejecting AXFR of {} from {} with a bad TSIG signature", zone.origin, src);
+            response.set_response_code(ResponseCode::NotAuth);
+            return Ok(vec![response.to_vec()?]);
+        }
+    };
+
+    let entries = match ctx.db.export(&zone.origin).await {
+        Ok(entries) => entries,
+        Err(e @ DbError::Unsupported(_)) => {
+            warn!("Refusing AXFR of {}: {}", zone.origin, e);
+            response.set_response_code(ResponseCode::NotImp);
+            return Ok(vec![response.to_vec()?]);
+        }
+        Err(e) => {
+            warn!("AXFR query for {} failed: {}", zone.origin, e);
+            response.set_response_code(ResponseCode::ServFail);
+            return Ok(vec![response.to_vec()?]);
+        }
+    };
+
+    // Rows that don't make a valid record (ALIAS, bad values, names outside the zone) are left out
+    let origin = zone.origin_name().ok_or("invalid zone origin")?;
+    let soa = zone.soa_record_with_serial(zone_serial(zone, ctx).await, zone.ttl).ok_or("invalid zone SOA")?;
+    let mut records = vec![soa.clone()];
+    for (address, entry) in &entries {
+        let Some(name) = parse_target_name(address).filter(|name| origin.zone_of(name)) else { continue };
+        let record_type = entry.record_type.as_str();
+        let rtype = if record_type == "DNAME" { DNAME_TYPE } else { RecordType::from_str(record_type).unwrap_or(RecordType::NULL) };
+        if rtype == RecordType::SOA {
+            continue;
+        }
+        if let Ok(Some(record)) = build_record(name, entry.ttl, record_type, &entry.value, rtype) {
+            records.push(record);
+        }
+    }
+    records.push(soa);
+    info!("AXFR of {} to {}: {} records", zone.origin, src, records.len());
+
+    let mut messages = Vec::new();
+    let mut previous_mac = signer.as_ref().map(|(_, request_mac)| request_mac.clone()).unwrap_or_default();
+    let mut records = records.into_iter().peekable();
+    while records.peek().is_some() {
+        let mut chunk = response.clone();
+        chunk.set_authoritative(true);
+        let mut size = 0;
+        while let Some(record) = records.next_if(|record| size == 0 || size + record.to_bytes().map_or(0, |bytes| bytes.len()) <= AXFR_MESSAGE_SIZE) {
+            size += record.to_bytes().map_or(0, |bytes| bytes.len());
+            chunk.add_answer(record);
+        }
+        if let Some((signer, _)) = signer {
+            previous_mac = sign_response(&mut chunk, signer, &previous_mac, messages.is_empty())?;
+        }
+        messages.push(chunk.to_vec()?);
+    }
+    Ok(messages)
+}
+
+// Check the prerequisites of an UPDATE message and write its changes to the database in one
+// transaction, dropping the cached entries of every name touched
+async fn apply_update(message: &Message, config: &Config, ctx: &LookupContext<'_>, cache: &dyn CacheStore) -> ResponseCode {
+    // The zone section names one of our zones
+    let [zone_query] = message.queries() else {
+        return ResponseCode::FormErr;
+    };
+    if zone_query.query_type() != RecordType::SOA {
+        return ResponseCode::FormErr;
+    }
+    let Some(origin) = config
+        .zones
+        .iter()
+        .filter_map(|zone| zone.origin_name())
+        .find(|origin| origin == zone_query.name())
+    else {
+        return ResponseCode::NotAuth;
+    };
+    if message.answers().iter().chain(message.name_servers()).any(|record| !origin.zone_of(record.name())) {
+        return ResponseCode::NotZone;
+    }
+
+    // Turn the update section into database operations before touching anything
+    let mut operations = Vec::new();
+    for update in message.name_servers() {
+        let name = ctx.lookup_name(update.name());
+        let record_type = record_type_name(update.record_type());
+        let value = update.data().and_then(update_value);
+        let operation = match update.dns_class() {
+            DNSClass::IN => match value {
+                Some(value) => UpdateOperation::Add { name, record_type, value },
+                None => {
+                    warn!("Unsupported record type {} in update", update.record_type());
+                    return ResponseCode::Refused;
+                }
+            },
+            DNSClass::ANY if update.record_type() == RecordType::ANY => UpdateOperation::Delete { name, record_type: None, value: None },
+            DNSClass::ANY => UpdateOperation::Delete { name, record_type: Some(record_type), value: None },
+            DNSClass::NONE if value.is_some() => UpdateOperation::Delete { name, record_type: Some(record_type), value },
+            _ => return ResponseCode::FormErr,
+        };
+        operations.push(operation);
+    }
+
+    // Prerequisites (RFC 2136 section 2.4), checked by the data source in the update's transaction
+    let mut prerequisites = Vec::new();
+    for prerequisite in message.answers() {
+        let name = ctx.lookup_name(prerequisite.name());
+        let record_type = record_type_name(prerequisite.record_type());
+        let prerequisite = match prerequisite.dns_class() {
+            DNSClass::ANY if prerequisite.record_type() == RecordType::ANY => Prerequisite::NameInUse(name),
+            DNSClass::ANY => Prerequisite::RrsetExists { name, record_type, value: None },
+            DNSClass::NONE if prerequisite.record_type() == RecordType::ANY => Prerequisite::NameNotInUse(name),
+            DNSClass::NONE => Prerequisite::RrsetDoesNotExist { name, record_type },
+            DNSClass::IN => match prerequisite.data().and_then(update_value) {
+                Some(value) => Prerequisite::RrsetExists { name, record_type, value: Some(value) },
+                None => {
+                    warn!("Unsupported record type {} in update prerequisite", prerequisite.record_type());
+                    return ResponseCode::Refused;
+                }
+            },
+            _ => return ResponseCode::FormErr,
+        };
+        prerequisites.push(prerequisite);
+    }
+
+    let Some(_db_permit) = ctx.db_permit().await else {
+        warn!("Database busy, not applying the update");
+        return ResponseCode::ServFail;
+    };
+    let touched = match ctx.db.update(&prerequisites, &operations).await {
+        Ok(touched) => touched,
+        Err(DbError::Prerequisite(index)) => {
+            let prerequisite = &prerequisites[index];
+            info!("Update prerequisite for {} not met", prerequisite.name());
+            return prerequisite.failure();
+        }
+        Err(e @ DbError::Unsupported(_)) => {
+            warn!("Refusing update: {}", e);
+            return ResponseCode::NotImp;
+        }
+        Err(e) => {
+            warn!("Database update failed: {}", e);
+            return ResponseCode::ServFail;
+        }
+    };
+
+    for name in &touched {
+        cache.remove_name(name).await;
+    }
+    if !touched.is_empty() {
+        cache.save().await;
+    }
+    ResponseCode::NoError
+}
+
+// Drop records repeating an earlier one's name, type and data, whatever their TTL
+fn dedup_records(records: Vec<Record>) -> Vec<Record> {
+    let mut unique: Vec<Record> = Vec::with_capacity(records.len());
+    for record in records {
+        let repeated = unique.iter().any(|seen| {
+            seen.name() == record.name() && seen.record_type() == record.record_type() && seen.data() == record.data()
+        });
+        if !repeated {
+            unique.push(record);
+        }
+    }
+    unique
+}
+
+// FORMERR for a message that doesn't parse, if it has a full header that isn't a response
+fn format_error(request: &[u8], buffers: &BufferPool) -> Option<Vec<u8>> {
+    if request.len() < 12 || request[2] & 0x80 != 0 {
+        return None;
+    }
+    let mut response = Message::new();
+    response.set_id(u16::from_be_bytes([request[0], request[1]]));
+    response.set_message_type(MessageType::Response);
+    response.set_response_code(ResponseCode::FormErr);
+    buffers.encode(&response).ok()
+}
+
+// Answer one DNS message locally or through the upstream server; returns the response to
+// send back, or None when the message is dropped. Over TCP there is no UDP payload limit
+async fn answer_message(
+    request: &[u8],
+    src: SocketAddr,
+    tcp: bool,
+    config: &Config,
+    ctx: &LookupContext<'_>,
+    cache: &dyn CacheStore,
+    upstream_socket: &UdpSocket,
+) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    let message = match Message::from_vec(request) {
+        Ok(message) => message,
+        Err(e) => {
+            debug!("Malformed message from {}: {}", src, e);
+            return Ok(format_error(request, &ctx.buffers));
+        }
+    };
+    if message.op_code() == OpCode::Update {
+        return Ok(Some(answer_update(request, &message, src, config, ctx, cache).await?));
+    }
+    // Other opcodes (STATUS, NOTIFY, ...) are not implemented; don't pass them upstream either
+    if message.op_code() != OpCode::Query {
+        info!("Opcode {:?} from {} is not implemented", message.op_code(), src);
+        let mut response = Message::new();
+        response.set_id(message.id());
+        response.set_message_type(MessageType::Response);
+        response.set_op_code(message.op_code());
+        response.set_response_code(ResponseCode::NotImp);
+        response.add_queries(message.queries().iter().cloned());
+        return Ok(Some(ctx.buffers.encode(&response)?));
+    }
+    let mut response = Message::new();
+    response.set_id(message.id());
+    response.set_message_type(MessageType::Response);
+    response.set_op_code(OpCode::Query);
+    // RD and CD are copied from the request and recursion is available through the upstream
+    // server. Answers built here from local records never carry AD; only upstream answers that
+    // dnssec_validation checked get it
+    response.set_recursion_desired(message.recursion_desired());
+    response.set_checking_disabled(message.checking_disabled());
+    response.set_recursion_available(true);
+    response.set_authentic_data(false);
+
+    // EDNS0: remember what the client can receive, and only answer with an OPT if it sent one;
+    // over TCP only the two-byte length prefix limits the size
+    let payload_size = if tcp { u16::MAX } else { message.max_payload() };
+    let client_dnssec_ok = message.extensions().as_ref().is_some_and(|edns| edns.dnssec_ok());
+    if message.extensions().is_some() {
+        let mut edns = Edns::new();
+        edns.set_max_payload(EDNS_PAYLOAD_SIZE);
+        edns.set_version(0);
+        edns.set_dnssec_ok(client_dnssec_ok);
+        response.set_edns(edns);
+    }
+    // Echo the question section exactly as asked; some resolvers drop responses without it
+    response.add_queries(message.queries().iter().cloned());
+
+    // DNS cookies: every response to a cookie gets a fresh server cookie, and UDP clients without
+    // a valid one are told to retry over TCP once they go over the rate limit, since a spoofed
+    // source address can't complete a TCP handshake
+    let mut reply_cookie = None;
+    if let Some(cookies) = &ctx.cookies {
+        let mut valid_cookie = false;
+        match message.extensions().as_ref().and_then(|edns| edns.option(EdnsCode::Cookie)) {
+            Some(EdnsOption::Unknown(_, data)) if data.len() == 8 || (16..=40).contains(&data.len()) => {
+                let (client_cookie, server_cookie) = data.split_at(8);
+                valid_cookie = cookies.verify(client_cookie, server_cookie, src.ip());
+                reply_cookie = Some(cookies.reply(client_cookie, src.ip()));
+            }
+            Some(_) => {
+                warn!("Malformed DNS cookie from {}", src);
+                response.set_response_code(ResponseCode::FormErr);
+                return Ok(Some(ctx.buffers.encode(&response)?));
+            }
+            None => {}
+        }
+        if let (Some(cookie), Some(edns)) = (&reply_cookie, response.extensions_mut()) {
+            edns.options_mut().insert(EdnsOption::Unknown(u16::from(EdnsCode::Cookie), cookie.clone()));
+        }
+        if !tcp && !valid_cookie && !cookies.within_rate_limit(src.ip()) {
+            info!("Query from {} without a valid cookie is over the rate limit, truncating", src);
+            response.set_truncated(true);
+            return Ok(Some(ctx.buffers.encode(&response)?));
+        }
+    }
+
+    // Exactly one question is expected; several are only answered if configured
+    if message.queries().is_empty() {
+        info!("Query from {} has no question, answering FORMERR", src);
+        response.set_response_code(ResponseCode::FormErr);
+        return Ok(Some(encode_response(&mut response, payload_size, &ctx.buffers)?));
+    }
+    let multiple_questions = message.queries().len() > 1;
+    if multiple_questions && config.multiple_questions != MultipleQuestions::Answer {
+        info!("Query from {} has {} questions, answering {:?}", src, message.queries().len(), config.multiple_questions);
+        response.set_response_code(match config.multiple_questions {
+            MultipleQuestions::Refused => ResponseCode::Refused,
+            _ => ResponseCode::FormErr,
+        });
+        return Ok(Some(encode_response(&mut response, payload_size, &ctx.buffers)?));
+    }
+
+    let mut handled = false;
+    let mut unanswered = Vec::new();
+    for query in message.queries() {
+        info!("Received query from {}: {:?}", src, query);
+    
+        // CHAOS-class queries are about this server itself; upstream would only refuse them
+        if query.query_class() == DNSClass::CH {
+            match chaos_answer(query, config) {
+                Some(record) => response.add_answer(record),
+                None => response.set_response_code(ResponseCode::Refused),
+            };
+            handled = true;
+            continue;
+        }
+
+        let zone = find_zone(&config.zones, query.name());
+        let authoritative = zone.is_some() || in_authoritative_zone(&config.authoritative_zones, query.name());
+        let signer = find_signer(ctx.zone_signers, query.name()).filter(|_| client_dnssec_ok);
+
+        // Zone transfers are served over TCP only, before a message gets here
+        if query.query_type() == RecordType::AXFR {
+            info!("Refusing AXFR for {} from {} outside TCP", query.name(), src);
+            response.set_response_code(ResponseCode::Refused);
+            handled = true;
+            continue;
+        }
+
+        // The DNSKEY set of a signed zone is answered from the configured keys
+        if query.query_type() == RecordType::DNSKEY {
+            if let Some(zone_signer) = find_signer(ctx.zone_signers, query.name()).filter(|s| &s.origin == query.name()) {
+                response.add_answers(zone_signer.dnskey_records());
+                response.set_authoritative(true);
+                handled = true;
+                continue;
+            }
+        }
+
+        // RFC 8482: don't dump every type for ANY; answer known names with a minimal HINFO
+        if query.query_type() == RecordType::ANY {
+            if config.refuse_any {
+                info!("Refusing ANY query for {}", query.name());
+                response.set_response_code(ResponseCode::NotImp);
+                handled = true;
+                continue;
+            }
+            if name_exists(query.name(), ctx, cache).await {
+                let hinfo = HINFO::new("RFC8482".to_string(), String::new());
+                response.add_answer(Record::from_rdata(query.name().clone(), 3600, RData::HINFO(hinfo)));
+                response.set_authoritative(true);
+                handled = true;
+                info!("Answered ANY query for {} with RFC 8482 HINFO", query.name());
+                continue;
+            }
+        }
+
+        // Fetch records from handle_query
+        let (mut records, name_known) = match handle_query(query.clone(), ctx, cache).await {
+            Ok(LookupResult::Records(records)) => (records, true),
+            Ok(LookupResult::NoData) => (Vec::new(), true),
+            Ok(LookupResult::NotFound) => (Vec::new(), false),
+            Err(LookupError::BrokenChain(partial)) => {
+                warn!("Answering SERVFAIL for {}: CNAME chain loops or is too long", query.name());
+                response.add_answers(partial);
+                response.set_response_code(ResponseCode::ServFail);
+                handled = true;
+                continue;
+            }
+            Err(LookupError::DatabaseBusy) => {
+                warn!("Answering SERVFAIL for {}: database busy and nothing cached", query.name());
+                response.set_response_code(ResponseCode::ServFail);
+                handled = true;
+                continue;
+            }
+            Err(LookupError::DatabaseDown(partial)) => {
+                warn!("Answering SERVFAIL for {}: database unavailable and nothing cached for the rest of the chain", query.name());
+                response.add_answers(partial);
+                response.set_response_code(ResponseCode::ServFail);
+                handled = true;
+                continue;
+            }
+            Err(e) => {
+                // A broken override must not silently fall through to the upstream answer
+                let invalid_rows = ctx.metrics.invalid_rows.fetch_add(1, Ordering::Relaxed) + 1;
+                error!("Answering SERVFAIL for {}: {} ({} answers failed on invalid rows so far)", query.name(), e, invalid_rows);
+                response.set_response_code(ResponseCode::ServFail);
+                handled = true;
+                continue;
+            }
+        };
+        if records.is_empty() && config.synthesize_ptr && query.query_type() == RecordType::PTR {
+            records = synthesize_ptr(query, ctx.db, config.default_ttl).await;
+        }
+        if records.is_empty() && query.query_type() == RecordType::SOA {
+            if let Some(zone) = zone.filter(|z| z.origin_name().as_ref() == Some(query.name())) {
+                let serial = zone_serial(zone, ctx).await;
+                records.extend(zone.soa_record_with_serial(serial, zone.ttl));
+            }
+        }
+    
+        if !records.is_empty() {
+            for record in &records {  // Use a reference to avoid consuming the Vec
+                response.add_answer(record.clone()); // Clone the record if needed
+            }
+            handled = true;
+            if authoritative {
+                response.set_authoritative(true);
+            }
+            info!("Query resolved locally: {:?}", records);
+
+            // Attach addresses for MX exchanges and NS glue so clients don't need a second round trip
+            for (target, use_upstream) in additional_targets(&records) {
+                for rtype in [RecordType::A, RecordType::AAAA] {
+                    let target_query = Query::query(target.clone(), rtype);
+                    let mut glue = match handle_query(target_query.clone(), ctx, cache).await {
+                        Ok(LookupResult::Records(glue)) => glue,
+                        _ => Vec::new(),
+                    };
+                    if glue.is_empty() && use_upstream {
+                        glue = resolve_upstream(target_query, ctx).await;
+                    }
+                    for record in glue.into_iter().filter(|r| r.record_type() == rtype) {
+                        response.add_additional(record);
+                    }
+                }
+            }
+        } else if name_known && (config.local_names_authoritative || authoritative) {
+            // The name exists locally without this type: NODATA, so internal names don't leak upstream
+            response.add_name_servers(zone.and_then(|zone| zone.soa_record(zone.minimum)));
+            if let (Some(signer), Some(zone)) = (signer, zone) {
+                response.add_name_servers(signer.denial(query.name(), zone.minimum));
+            }
+            if authoritative {
+                response.set_authoritative(true);
+            }
+            handled = true;
+            info!("No {} records for {}, answering NODATA.", query.query_type(), query.name());
+        } else if authoritative {
+            // We are authoritative for this name, so answer negatively instead of forwarding;
+            // the zone apex itself exists (it holds the SOA), so it never gets NXDOMAIN
+            let is_apex = zone.is_some_and(|zone| zone.origin_name().as_ref() == Some(query.name()));
+            match (signer, zone) {
+                (Some(signer), Some(zone)) => response.add_name_servers(signer.denial(query.name(), zone.minimum)),
+                _ if !is_apex => response.set_response_code(ResponseCode::NXDomain),
+                _ => &mut response,
+            };
+            response.add_name_servers(zone.and_then(|zone| zone.soa_record(zone.minimum)));
+            response.set_authoritative(true);
+            handled = true;
+            info!("No local result for {} in an authoritative zone, answering {}.", query.name(), response.response_code());
+        } else {
+            info!("No local result for {}, forwarding to upstream DNS.", query.name());
+            unanswered.push(query.clone());
+        }
+    }
+
+    // With several questions only the ones without a local answer go upstream, each on its own
+    if multiple_questions {
+        for query in unanswered {
+            response.add_answers(resolve_upstream_chain(query, ctx).await);
+        }
+        handled = true;
+    }
+
+    if handled {
+        // Chains followed from several places can repeat a record, which some resolvers reject
+        let answers = response.take_answers();
+        response.add_answers(dedup_records(answers));
+        let additionals = response.take_additionals();
+        response.add_additionals(dedup_records(additionals));
+
+        // Sign local answers from signed zones for clients that asked for DNSSEC records
+        if client_dnssec_ok && !ctx.zone_signers.is_empty() {
+            let answers = response.take_answers();
+            response.add_answers(sign_records(answers, ctx.zone_signers));
+            let name_servers = response.take_name_servers();
+            response.add_name_servers(sign_records(name_servers, ctx.zone_signers));
+            let additionals = response.take_additionals();
+            response.add_additionals(sign_records(additionals, ctx.zone_signers));
+        }
+
+        // Send the response if the query was handled locally
+        let response_buf = encode_response(&mut response, payload_size, &ctx.buffers)?;
+        info!("Response sent to {} from database/cache", src);
+        Ok(Some(response_buf))
+    } else {
+        // Forward the query to the upstream DNS server, advertising no more than either side can take.
+        // The client's DO and CD bits go along, so a validating stub gets the RRSIG and NSEC records
+        // it needs; the upstream answer is relayed as received, without touching DNSSEC records
+        let mut upstream_request = message.clone();
+        if let Some(edns) = message.extensions() {
+            let mut upstream_edns = edns.clone();
+            upstream_edns.set_max_payload(payload_size.min(EDNS_PAYLOAD_SIZE));
+            upstream_edns.set_dnssec_ok(client_dnssec_ok);
+            // Cookies are between the client and us; the upstream server gets none of them
+            if ctx.cookies.is_some() {
+                upstream_edns.options_mut().remove(EdnsCode::Cookie);
+            }
+            upstream_request.set_edns(upstream_edns);
+            upstream_request.set_checking_disabled(message.checking_disabled());
+        }
+        let added_ecs = apply_ecs(upstream_request.extensions_mut(), config, src.ip());
+
+        // Validating mode: ask with DO and CD set, check the signatures here, and only set AD on
+        // answers that chain up to a trust anchor. The query goes out under an ID of its own, as
+        // forwarded ones do
+        if config.dnssec_validation {
+            let mut validated_query = message.clone();
+            validated_query.set_id(rand::random());
+            let mut edns = message.extensions().clone().unwrap_or_default();
+            edns.set_max_payload(EDNS_PAYLOAD_SIZE);
+            edns.set_dnssec_ok(true);
+            if ctx.cookies.is_some() {
+                edns.options_mut().remove(EdnsCode::Cookie);
+            }
+            validated_query.set_edns(edns);
+            validated_query.set_checking_disabled(true);
+            apply_ecs(validated_query.extensions_mut(), config, src.ip());
+
+            let upstream_response = upstream_exchange(&validated_query, ctx).await.map_err(|e| e.to_string());
+            let mut upstream_response = match upstream_response {
+                Ok(upstream_response) => upstream_response,
+                Err(e) => {
+                    warn!("Upstream lookup for validation failed, answering SERVFAIL: {}", e);
+                    response.set_response_code(ResponseCode::ServFail);
+                    return Ok(Some(encode_response(&mut response, payload_size, &ctx.buffers)?));
+                }
+            };
+
+            let secure = match validate_answers(&upstream_response, ctx).await {
+                Validation::Secure => true,
+                Validation::Insecure => false,
+                // A client that set CD does its own validation and gets the data anyway
+                Validation::Bogus(reason) if message.checking_disabled() => {
+                    warn!("DNSSEC validation failed, passing through for CD client: {}", reason);
+                    false
+                }
+                Validation::Bogus(reason) => {
+                    warn!("DNSSEC validation failed, answering SERVFAIL: {}", reason);
+                    response.set_response_code(ResponseCode::ServFail);
+                    return Ok(Some(encode_response(&mut response, payload_size, &ctx.buffers)?));
+                }
+            };
+            upstream_response.set_id(message.id());
+            upstream_response.set_authentic_data(secure);
+            upstream_response.set_checking_disabled(message.checking_disabled());
+
+            // DNSSEC records only go to clients that asked for them with the DO bit
+            if !client_dnssec_ok {
+                let qtypes: Vec<RecordType> = message.queries().iter().map(|query| query.query_type()).collect();
+                let is_unwanted = |record: &Record| {
+                    matches!(record.record_type(), RecordType::RRSIG | RecordType::NSEC | RecordType::NSEC3)
+                        && !qtypes.contains(&record.record_type())
+                };
+                let answers = upstream_response.take_answers();
+                upstream_response.add_answers(answers.into_iter().filter(|record| !is_unwanted(record)));
+                let name_servers = upstream_response.take_name_servers();
+                upstream_response.add_name_servers(name_servers.into_iter().filter(|record| !is_unwanted(record)));
+            }
+            match message.extensions() {
+                Some(_) => {
+                    let mut edns = Edns::new();
+                    edns.set_max_payload(EDNS_PAYLOAD_SIZE);
+                    edns.set_dnssec_ok(client_dnssec_ok);
+                    if let Some(cookie) = &reply_cookie {
+                        edns.options_mut().insert(EdnsOption::Unknown(u16::from(EdnsCode::Cookie), cookie.clone()));
+                    }
+                    upstream_response.set_edns(edns);
+                }
+                None => *upstream_response.extensions_mut() = None,
+            }
+            info!("Response sent to {} from upstream DNS (DNSSEC {})", src, if secure { "secure" } else { "insecure" });
+            return Ok(Some(encode_response(&mut upstream_response, payload_size, &ctx.buffers)?));
+        }
+
+        // A question asked before, with the same EDNS options, is answered from the upstream cache
+        // until the answer expires
+        let cache_key = ctx.upstream_cache.key(&upstream_request, payload_size);
+        if let Some(mut cached) = cache_key.as_ref().and_then(|key| ctx.upstream_cache.get(key)) {
+            ctx.metrics.count(&ctx.metrics.cache_hits);
+            cached.set_id(message.id());
+            restore_case(&mut cached, message.queries());
+            info!("Response sent to {} from the upstream cache", src);
+            let cached = encode_response(&mut cached, payload_size, &ctx.buffers)?;
+            return Ok(Some(client_view(cached, message.extensions().is_some(), &reply_cookie, added_ecs, payload_size, &ctx.buffers)?));
+        }
+        if cache_key.is_some() {
+            ctx.metrics.count(&ctx.metrics.cache_misses);
+        }
+
+        // Identical queries, down to the name's case and their EDNS options, share one upstream exchange
+        let mut in_flight_key = upstream_request.clone();
+        in_flight_key.set_id(0);
+        let in_flight_key = (in_flight_key.to_vec()?, payload_size);
+        let forward = |upstream| forward_to(upstream, &upstream_request, src, payload_size, config, ctx, upstream_socket);
+        let forwarded = ctx.in_flight_forwards.run(in_flight_key, || async {
+            let (upstream, upstream_response) = ask_upstreams(ctx, &upstream_request, forward).await?;
+            if let Some(key) = cache_key.clone() {
+                ctx.upstream_cache.insert(key, &upstream_response);
+            }
+            Some((upstream.name.clone(), upstream_response))
+        });
+        if let Some((upstream, mut upstream_response)) = forwarded.await {
+            // A shared answer carries the ID of the query that went upstream
+            if upstream_response.len() >= 2 {
+                upstream_response[..2].copy_from_slice(&message.id().to_be_bytes());
+            }
+            info!("Response sent to {} from upstream DNS {}", src, upstream);
+            return Ok(Some(client_view(upstream_response, message.extensions().is_some(), &reply_cookie, added_ecs, payload_size, &ctx.buffers)?));
+        }
+        warn!("No upstream DNS server answered for {}, answering SERVFAIL", src);
+        response.set_response_code(ResponseCode::ServFail);
+        Ok(Some(encode_response(&mut response, payload_size, &ctx.buffers)?))
+    }
+}
+
+// Reload TLS certificates, and in preload mode the overrides, whenever the process receives SIGHUP
+#[cfg(unix)]
+async fn reload_on_hangup(
+    certificates: &[Arc<TlsCertificates>],
+    config: &Config,
+    ctx: &LookupContext<'_>,
+    cache: &dyn CacheStore,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        for certificate in certificates {
+            certificate.reload();
+        }
+        if config.mode == SourceMode::Preload {
+            reload_overrides(ctx, cache).await;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn reload_on_hangup(
+    _certificates: &[Arc<TlsCertificates>],
+    _config: &Config,
+    _ctx: &LookupContext<'_>,
+    _cache: &dyn CacheStore,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Ok(())
+}
+
+// Load the overrides into memory again and drop the cached entries of the names that changed.
+// When the database can't be read, the loaded overrides stay as they are
+async fn reload_overrides(ctx: &LookupContext<'_>, cache: &dyn CacheStore) {
+    match ctx.db.reload().await {
+        Ok(changed) => {
+            for name in &changed {
+                cache.remove_name(name).await;
+            }
+            if !changed.is_empty() {
+                cache.save().await;
+            }
+        }
+        Err(e) => warn!("Keeping the loaded overrides, reloading them failed: {}", e),
+    }
+}
+
+// In preload mode, reload the overrides every preload_interval seconds
+async fn preload_periodically(config: &Config, ctx: &LookupContext<'_>, cache: &dyn CacheStore) -> Result<(), Box<dyn std::error::Error>> {
+    if config.mode != SourceMode::Preload || config.preload_interval == 0 {
+        return Ok(());
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(config.preload_interval));
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        reload_overrides(ctx, cache).await;
+    }
+}
+
+// How long a shutdown waits for queries in flight before saving the cache and exiting anyway
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(3);
+
+// Wait for Ctrl-C or, as sent by service managers, SIGTERM
+#[cfg(unix)]
+async fn shutdown_signal() -> Result<(), Box<dyn std::error::Error>> {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+        interrupted = tokio::signal::ctrl_c() => interrupted?,
+        _ = terminate.recv() => {}
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> Result<(), Box<dyn std::error::Error>> {
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+// What a cache flush removes
+enum Flush {
+    All,
+    Name(Name),
+    // A domain and every name below it
+    Suffix(Name),
+}
+
+impl std::fmt::Display for Flush {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Flush::All => write!(f, "all names"),
+            Flush::Name(name) => write!(f, "{}", name),
+            Flush::Suffix(zone) => write!(f, "{} and below", zone),
+        }
+    }
+}
+
+// Remove entries from the database cache and the upstream cache, and write the cache file right
+// away; returns how many entries went
+async fn flush_cache(flush: &Flush, ctx: &LookupContext<'_>, cache: &dyn CacheStore) -> usize {
+    let target = match flush {
+        Flush::All => String::new(),
+        Flush::Name(name) | Flush::Suffix(name) => ctx.lookup_name(name),
+    };
+    let flushed = |name: &str| match flush {
+        Flush::All => true,
+        Flush::Name(_) => name == target,
+        Flush::Suffix(_) => name == target || name.strip_suffix(target.as_str()).is_some_and(|rest| rest.ends_with('.')),
+    };
+    let removed = cache.remove_matching(&flushed).await + ctx.upstream_cache.remove_matching(|name| flushed(&ctx.lookup_name(name)));
+    if let Err(e) = cache.write().await {
+        warn!("Failed to save the flushed cache: {}", e);
+    }
+    info!("Flushed {} cache entries for {}", removed, flush);
+    removed
+}
+
+// A cached record as `cache dump` shows it. `ttl` is the seconds left, negative once expired
+#[derive(Serialize, Deserialize)]
+struct DumpedEntry {
+    name: String,
+    #[serde(rename = "type")]
+    record_type: String,
+    value: String,
+    ttl: i64,
+    hits: u64,
+    // "database" or "upstream"
+    source: String,
+}
+
+// Every entry of the database cache and the upstream cache, or only those for one name
+async fn dump_cache(name: Option<&Name>, ctx: &LookupContext<'_>, cache: &dyn CacheStore) -> Vec<DumpedEntry> {
+    let target = name.map(|name| ctx.lookup_name(name));
+    let now = now_secs() as i64;
+    let mut entries = Vec::new();
+    for (key, records, hits) in cache.entries(target.as_deref()).await {
+        let name = key.rsplit_once(':').map_or(key.as_str(), |(name, _)| name);
+        for record in records {
+            let expires_at = record.expires_at().map_or(0, |expires_at| i64::try_from(expires_at).unwrap_or(i64::MAX));
+            entries.push(DumpedEntry {
+                name: name.to_string(),
+                record_type: record.record_type,
+                value: record.value,
+                ttl: expires_at - now,
+                hits,
+                source: "database".to_string(),
+            });
+        }
+    }
+    entries.extend(ctx.upstream_cache.dump(|name| target.as_ref().is_none_or(|target| ctx.lookup_name(name) == *target)));
+    entries.sort_by(|a, b| (&a.name, &a.record_type, &a.source).cmp(&(&b.name, &b.record_type, &b.source)));
+    entries
+}
+
+// Flush the whole cache whenever the process receives SIGUSR1
+#[cfg(unix)]
+async fn flush_on_signal(ctx: &LookupContext<'_>, cache: &dyn CacheStore) -> Result<(), Box<dyn std::error::Error>> {
+    let mut user1 = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())?;
+    while user1.recv().await.is_some() {
+        flush_cache(&Flush::All, ctx, cache).await;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn flush_on_signal(_ctx: &LookupContext<'_>, _cache: &dyn CacheStore) -> Result<(), Box<dyn std::error::Error>> {
+    Ok(())
+}
+
+// Cut a complete upstream answer down to the client's UDP payload limit when needed
+fn fit_payload(response: Vec<u8>, payload_size: u16, buffers: &BufferPool) -> Result<Vec<u8>, trust_dns_proto::error::ProtoError> {
+    if response.len() <= payload_size as usize {
+        return Ok(response);
+    }
+    let mut message = Message::from_vec(&response)?;
+    buffers.give(response);
+    encode_response(&mut message, payload_size, buffers)
+}
+
+// The DNS proxy with its listeners, cache and data sources, as config.json sets them up. Built
+// on the SQL databases in db_settings with new, or on records from elsewhere with with_source
+pub struct Server {
+    config: Config,
+    cache_file: String,
+    databases: Vec<HealthCheckedSource<'static>>,
+    cache: Box<dyn CacheStore>,
+    metrics: Metrics,
+}
+
+impl Server {
+    // The server on the databases in db_settings, tried in order, with sql_query and the other
+    // SQL settings
+    pub fn new(config: Config, cache_file: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        if config.db_settings.is_empty() {
+            return Err("db_settings needs at least one database".into());
+        }
+        if config.sql_query.is_empty() {
+            return Err("db_settings needs sql_query".into());
+        }
+        let mut sources = Vec::new();
+        for url in &config.db_settings {
+            let source: Box<dyn DataSource> = Box::new(SqlSource::new(open_database(url, &config)?, &config));
+            sources.push((redact_url(url), source));
+        }
+        Self::with_sources(config, cache_file, sources)
+    }
+
+    // The server on records from `source` instead of db_settings, whose SQL settings are then
+    // unused. The source gets the same deadlines and health checks as a database
+    pub fn with_source(config: Config, cache_file: &str, source: Box<dyn DataSource>) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::with_sources(config, cache_file, vec![("custom source".to_string(), source)])
+    }
+
+    // Like with_source with several sources, named for logs and tried in order like the
+    // databases in db_settings
+    pub fn with_sources(
+        config: Config,
+        cache_file: &str,
+        sources: Vec<(String, Box<dyn DataSource>)>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if sources.is_empty() {
+            return Err("with_sources needs at least one data source in sources".into());
+        }
+        let databases = sources.into_iter().map(|(name, source)| HealthCheckedSource::new(name, source, &config)).collect();
+
+        // Load cache; queries are answered side by side and share it
+        let max_entries = config.cache_max_entries.max(1);
+        let max_bytes = config.cache_max_bytes;
+        let cache: Box<dyn CacheStore> = match config.cache_backend {
+            CacheBackend::File => Box::new(FileJsonCache::load(cache_file, config.cache_format, max_entries, max_bytes)),
+            CacheBackend::Memory => Box::new(MemoryCache::new(Cache::with_limits(max_entries, max_bytes))),
+            CacheBackend::Redis => {
+                let redis = config.redis.as_ref().ok_or("cache_backend redis needs the redis setting")?;
+                Box::new(RedisCache::new(redis, config.stale_max_age)?)
+            }
+            CacheBackend::Sled => {
+                let database = format!("{}.sled", cache_file.strip_suffix(".json").unwrap_or(cache_file));
+                Box::new(SledCache::load(&database, cache_file, max_entries, max_bytes)?)
+            }
+        };
+        Ok(Server { config, cache_file: cache_file.to_string(), databases, cache, metrics: Metrics::default() })
+    }
+
+    // The counters so far, the cache size and the health of each data source; safe to call while
+    // the server runs
+    pub async fn stats(&self) -> Stats {
+        self.metrics.snapshot(self.cache.size().await, &self.databases)
+    }
+
+    // Serve until Ctrl-C or SIGTERM, or until a listener fails
+    pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let (config, cache_file) = (&self.config, self.cache_file.as_str());
+        let listen_addr = format!("{}:{}", config.bind_address, config.port);
+        // With more than one worker, every worker gets its own socket on the listening address
+        let (socket, worker_sockets) = if config.workers > 1 {
+            let addr = tokio::net::lookup_host(&listen_addr).await?.next().ok_or("bind_address did not resolve")?;
+            let mut sockets = bind_udp_workers(addr, config.workers)?;
+            (UdpSocket::from_std(sockets.remove(0))?, sockets)
+        } else {
+            (UdpSocket::bind(&listen_addr).await?, Vec::new())
+        };
+        // Without SO_REUSEPORT there is one socket, and queries are handed over to the extra workers
+        let (handoff, worker_queries): (Vec<_>, Vec<_>) = match worker_sockets.is_empty() {
+            true => (1..config.workers)
+                .map(|_| {
+                    let (sender, queue) = mpsc::channel(UDP_QUEUE_SIZE);
+                    (sender, UdpWorkerQueries::Queue(queue, &socket))
+                })
+                .unzip(),
+            false => (Vec::new(), worker_sockets.into_iter().map(UdpWorkerQueries::Socket).collect()),
+        };
+        let listener = TcpListener::bind(&listen_addr).await?;
+        // Upstream servers in order of preference; the next one is used while one fails
+        let upstreams = config
+            .upstream_dns
+            .iter()
+            .map(|upstream_dns| Upstream::parse(upstream_dns, config))
+            .collect::<Result<Vec<_>, _>>()?;
+        if upstreams.is_empty() {
+            return Err("upstream_dns needs at least one server".into());
+        }
+        let mut forward_zones = Vec::new();
+        for (zone, upstream_dns) in &config.forward_zones {
+            let mut zone = Name::from_utf8(zone).map_err(|e| format!("invalid forward zone {}: {}", zone, e))?;
+            zone.set_fqdn(true);
+            info!("Forwarding queries under {} to upstream DNS {}", zone, upstream_dns);
+            forward_zones.push((zone, Upstream::parse(upstream_dns, config)?));
+        }
+        let upstream_socket = UdpSocket::bind("0.0.0.0:0").await?;
+        // Without configured trust anchors, validation starts from the built-in root keys
+        let trust_anchors = config
+            .trust_anchors
+            .iter()
+            .map(|anchor| parse_trust_anchor(anchor))
+            .collect::<Result<Vec<_>, _>>()?;
+        let update_allowed = config
+            .update_allowed
+            .iter()
+            .map(|cidr| parse_cidr(cidr).ok_or_else(|| format!("invalid update_allowed entry '{}'", cidr)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let zone_signers = config
+            .zones
+            .iter()
+            .filter_map(|zone| Some((zone, zone.ksk_path.as_deref()?, zone.zsk_path.as_deref()?)))
+            .map(|(zone, ksk_path, zsk_path)| ZoneSigner::load(zone, ksk_path, zsk_path))
+            .collect::<Result<Vec<_>, _>>()?;
+        let (prefetch, prefetch_queue) = mpsc::channel(PREFETCH_QUEUE_SIZE);
+        let databases = &self.databases[..];
+        let failover = FailoverSource::new(databases, config.db_round_robin);
+        let db: Box<dyn DataSource + '_> = match config.mode {
+            SourceMode::PerQuery => Box::new(failover),
+            SourceMode::Preload => Box::new(PreloadedSource::new(Box::new(failover))),
+        };
+        let (shut_down, shutdown) = watch::channel(false);
+        let ctx = LookupContext {
+            db: &*db,
+            databases,
+            upstreams: &upstreams,
+            upstream_strategy: config.upstream_strategy,
+            upstream_race_count: config.upstream_race_count.unwrap_or(upstreams.len()).max(1),
+            forward_zones: &forward_zones,
+            current_upstream: AtomicUsize::new(0),
+            upstream_timeout: Duration::from_millis(config.upstream_timeout_ms),
+            upstream_tcp_retry: config.upstream_tcp_retry,
+            trust_anchors: &trust_anchors,
+            dnssec_keys: std::sync::Mutex::new(HashMap::new()),
+            zone_signers: &zone_signers,
+            cookies: config.dns_cookies.as_ref().map(DnsCookies::new),
+            rate_limiter: config.rate_limit.as_ref().map(RateLimiter::new).transpose()?,
+            upstream_0x20: config.upstream_0x20,
+            outstanding: std::sync::Mutex::new(HashMap::new()),
+            upstream_sockets: AtomicUsize::new(0),
+            shared_upstream: Mutex::new(()),
+            tsig_keys: load_tsig_keys(&config.tsig_keys)?,
+            update_allowed,
+            rotation: AtomicUsize::new(0),
+            weighted_selection: config.weighted_selection,
+            wildcard_depth: config.wildcard_depth,
+            max_chain_depth: config.max_chain_depth,
+            idn_unicode: config.idn_unicode,
+            metrics: &self.metrics,
+            zones: &config.zones,
+            negative_ttl: config.negative_ttl,
+            min_answer_ttl: config.min_answer_ttl,
+            in_flight_lookups: InFlight::new(),
+            in_flight_forwards: InFlight::new(),
+            upstream_cache: UpstreamCache::new(config),
+            buffers: BufferPool::new(config.buffer_pool_size),
+            db_permits: Semaphore::new(config.max_db_concurrency.max(1)),
+            max_db_concurrency: config.max_db_concurrency.max(1),
+            db_wait: Duration::from_millis(config.db_wait_ms),
+            stale_max_age: config.stale_max_age,
+            prefetch: (config.prefetch_per_second > 0).then_some(prefetch),
+            prefetch_min_hits: config.prefetch_min_hits,
+            shutdown,
+        };
+
+        let cache = &*self.cache;
+
+        // Without the overrides loaded, names are forwarded until a reload succeeds
+        if config.mode == SourceMode::Preload {
+            if let Err(e) = db.reload().await {
+                error!("Loading the overrides failed, forwarding every name until a reload succeeds: {}", e);
+            }
+        }
+
+        info!("DNS proxy listening on {} (UDP and TCP, {} UDP workers)", listen_addr, worker_queries.len() + 1);
+
+        // Optional DNS-over-TLS and DNS-over-HTTPS listeners; certificates are re-read on SIGHUP
+        let mut certificates = Vec::new();
+        let dot = match &config.dot_listen {
+            Some(dot) => {
+                let dot_addr = format!("{}:{}", dot.address, dot.port);
+                let dot_certificates = Arc::new(TlsCertificates::load(&dot.cert_path, &dot.key_path)?);
+                certificates.push(dot_certificates.clone());
+                let dot_listener = TcpListener::bind(&dot_addr).await?;
+                info!("DNS-over-TLS listening on {}", dot_addr);
+                Some((dot, dot_certificates, dot_listener))
+            }
+            None => None,
+        };
+        let doh = match &config.doh_listen {
+            Some(doh) => {
+                let doh_addr = format!("{}:{}", doh.address, doh.port);
+                // Without its own certificate, DoH shares the DoT one
+                let doh_certificates = match (&doh.cert_path, &doh.key_path, &dot) {
+                    (Some(cert_path), Some(key_path), _) => {
+                        let doh_certificates = Arc::new(TlsCertificates::load(cert_path, key_path)?);
+                        certificates.push(doh_certificates.clone());
+                        doh_certificates
+                    }
+                    (None, None, Some((_, dot_certificates, _))) => dot_certificates.clone(),
+                    _ => return Err("doh_listen needs cert_path and key_path unless dot_listen is configured".into()),
+                };
+                let doh_listener = TcpListener::bind(&doh_addr).await?;
+                info!("DNS-over-HTTPS listening on https://{}{}", doh_addr, doh.path);
+                Some((doh, doh_certificates, doh_listener))
+            }
+            None => None,
+        };
+        let serve_dot = async {
+            match &dot {
+                Some((dot, dot_certificates, dot_listener)) => {
+                    serve_dot_listener(dot_listener, dot, dot_certificates, config, &ctx, cache, &upstream_socket).await
+                }
+                None => Ok(()),
+            }
+        };
+        let serve_doh = async {
+            match &doh {
+                Some((doh, doh_certificates, doh_listener)) => {
+                    let (queries, doh_queries) = mpsc::channel(64);
+                    let accept = async {
+                        tokio::select! {
+                            accepted = serve_doh_listener(doh_listener, &doh.path, doh_certificates, queries) => accepted,
+                            () = ctx.shutting_down() => Ok(()),
+                        }
+                    };
+                    tokio::try_join!(accept, serve_doh_queries(doh_queries, config, &ctx, cache, &upstream_socket))?;
+                    Ok(())
+                }
+                None => Ok(()),
+            }
+        };
+
+        // The listeners stop accepting once shutdown starts and return when their queries are answered
+        let listeners = async {
+            tokio::try_join!(
+                serve_udp(&socket, &handoff, config, &ctx, cache, &upstream_socket),
+                serve_tcp_listener(&listener, config, &ctx, cache, &upstream_socket),
+                serve_dot,
+                serve_doh,
+            )?;
+            Ok::<(), Box<dyn std::error::Error>>(())
+        };
+        let background = async {
+            tokio::try_join!(
+                reload_on_hangup(&certificates, config, &ctx, cache),
+                preload_periodically(config, &ctx, cache),
+                notify_secondaries(config, &ctx),
+                probe_upstreams(config, &ctx),
+                futures::future::try_join_all(databases.iter().map(HealthCheckedSource::watch_health)),
+                sweep_cache(config, cache),
+                prefetch_entries(prefetch_queue, config, &ctx, cache),
+                log_stats(config, &ctx, cache),
+                flush_on_signal(&ctx, cache),
+                serve_control(config, &ctx, cache),
+                cache.write_back(Duration::from_secs(config.cache_save_interval)),
+            )?;
+            Ok(())
+        };
+        // On Ctrl-C or SIGTERM, stop taking new queries, give the ones in flight SHUTDOWN_GRACE to
+        // finish, then write out changes write_back hasn't saved yet
+        let serve = async {
+            tokio::pin!(listeners);
+            tokio::select! {
+                served = &mut listeners => served,
+                served = background => served,
+                signal = shutdown_signal() => {
+                    signal?;
+                    info!("Shutting down, waiting for queries in flight");
+                    let _ = shut_down.send(true);
+                    match tokio::time::timeout(SHUTDOWN_GRACE, &mut listeners).await {
+                        Ok(Err(e)) => warn!("Stopping the listeners failed: {}", e),
+                        Err(_) => warn!("Queries still in flight after {:?}, dropping them", SHUTDOWN_GRACE),
+                        Ok(Ok(())) => {}
+                    }
+                    match config.cache_backend {
+                        CacheBackend::File => info!("Saving the cache to {}", cache_file),
+                        _ => debug!("Writing out cache changes"),
+                    }
+                    cache.write().await
+                }
+            }
+        };
+        if worker_queries.is_empty() {
+            return serve.await;
+        }
+
+        // The extra workers borrow the server state, so they run on scoped threads while this thread
+        // keeps serving everything else, and are stopped when that ends
+        let (stop, stopped) = watch::channel(false);
+        tokio::task::block_in_place(|| {
+            std::thread::scope(|scope| {
+                for (worker, queries) in worker_queries.into_iter().enumerate() {
+                    let stopped = stopped.clone();
+                    let (ctx, cache, upstream_socket) = (&ctx, cache, &upstream_socket);
+                    scope.spawn(move || run_udp_worker(worker + 1, queries, config, ctx, cache, upstream_socket, stopped));
+                }
+                let served = tokio::runtime::Handle::current().block_on(serve);
+                let _ = stop.send(true);
+                served
+            })
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oversize_responses_lose_the_additional_section_first_then_the_answer() {
+        let buffers = BufferPool::new(4);
+        let name = Name::from_str("big.test.").unwrap();
+        let address = |last: u8| Record::from_rdata(name.clone(), 300, RData::A(A(Ipv4Addr::new(192, 0, 2, last))));
+        let mut response = Message::new();
+        response.set_message_type(MessageType::Response);
+        response.add_query(Query::query(name.clone(), RecordType::A));
+        response.add_answers((1..=10).map(address));
+        response.add_name_server(Record::from_rdata(name.clone(), 300, RData::NS(NS(name.clone()))));
+        response.add_additionals((11..=40).map(address));
+        let full = response.to_vec().unwrap();
+
+        // Without the additional section the answer fits
+        let mut fitted = Message::from_vec(&full).unwrap();
+        let bytes = encode_response(&mut fitted, 512, &buffers).unwrap();
+        let fitted = Message::from_vec(&bytes).unwrap();
+        assert!(bytes.len() <= 512 && !fitted.truncated());
+        assert_eq!((fitted.answers().len(), fitted.name_servers().len(), fitted.additionals().len()), (10, 1, 0));
+
+        // Too many answers for even that: everything goes, with TC set
+        response.add_answers((41..=80).map(address));
+        let bytes = fit_payload(response.to_vec().unwrap(), 512, &buffers).unwrap();
+        let truncated = Message::from_vec(&bytes).unwrap();
+        assert!(truncated.truncated());
+        assert_eq!((truncated.answers().len(), truncated.name_servers().len(), truncated.additionals().len()), (0, 0, 0));
+        assert_eq!(truncated.queries(), response.queries());
+
+        // Within the limit, nothing changes
+        assert_eq!(fit_payload(full.clone(), 4096, &buffers).unwrap(), full);
+    }
+}
@@ -0,0 +1,683 @@
+use super::*;
+
+// Queries waiting for each extra UDP worker where SO_REUSEPORT doesn't exist; while a worker's
+// queue is full, the main worker answers its share itself
+pub(crate) const UDP_QUEUE_SIZE: usize = 256;
+
+// Errors from a listening socket. Request errors concern one client, such as a reset from a
+// client that went away or a connection aborted before it was accepted, and are logged while
+// serving goes on. Fatal means the listening socket itself keeps failing, which stops the server.
+#[derive(Debug)]
+pub(crate) enum ServerError {
+    Request(std::io::Error),
+    Fatal(std::io::Error),
+}
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerError::Request(e) => write!(f, "{}", e),
+            ServerError::Fatal(e) => write!(f, "listening socket failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+// Failures in a row after which a listening socket is taken to be dead
+pub(crate) const MAX_LISTENER_FAILURES: u32 = 50;
+
+// Sorts the errors of one listening socket into ServerError kinds
+#[derive(Default)]
+pub(crate) struct ListenerFailures {
+    in_a_row: u32,
+}
+
+impl ListenerFailures {
+    fn succeeded(&mut self) {
+        self.in_a_row = 0;
+    }
+
+    // Errors a single client causes are Request errors right away. Anything else, such as running
+    // out of file descriptors, is waited out briefly and only turns Fatal when it keeps happening.
+    async fn sort(&mut self, e: std::io::Error) -> ServerError {
+        use std::io::ErrorKind::*;
+        if matches!(e.kind(), ConnectionReset | ConnectionAborted | ConnectionRefused | Interrupted | WouldBlock | TimedOut) {
+            return ServerError::Request(e);
+        }
+        self.in_a_row += 1;
+        if self.in_a_row >= MAX_LISTENER_FAILURES {
+            return ServerError::Fatal(e);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        ServerError::Request(e)
+    }
+}
+
+// Answer UDP queries until the socket fails. Each query is copied out of the receive buffer
+// and answered on its own, so the next datagram is received while an answer is pending.
+pub(crate) async fn serve_udp(
+    socket: &UdpSocket,
+    handoff: &[mpsc::Sender<(Vec<u8>, SocketAddr)>],
+    config: &Config,
+    ctx: &LookupContext<'_>,
+    cache: &dyn CacheStore,
+    upstream_socket: &UdpSocket,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut buf = [0u8; 4096];
+    let mut queries = FuturesUnordered::new();
+    let mut failures = ListenerFailures::default();
+    let mut turn = 0;
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let (len, src) = match received {
+                    Ok(received) => received,
+                    Err(e) => match failures.sort(e).await {
+                        ServerError::Request(e) => {
+                            warn!("Receiving a UDP query failed: {}", e);
+                            continue;
+                        }
+                        fatal => return Err(fatal.into()),
+                    },
+                };
+                failures.succeeded();
+                let mut request = ctx.buffers.take();
+                request.extend_from_slice(&buf[..len]);
+                // Queries take turns between this worker and the ones it hands over to; one whose
+                // queue is full leaves the query here
+                turn = (turn + 1) % (handoff.len() + 1);
+                if let Some(worker) = turn.checked_sub(1).map(|worker| &handoff[worker]) {
+                    match worker.try_send((request, src)) {
+                        Ok(()) => continue,
+                        Err(mpsc::error::TrySendError::Full((returned, _)) | mpsc::error::TrySendError::Closed((returned, _))) => request = returned,
+                    }
+                }
+                queries.push(answer_udp(request, src, socket, config, ctx, cache, upstream_socket));
+            }
+            Some(()) = queries.next(), if !queries.is_empty() => {}
+            () = ctx.shutting_down() => {
+                while queries.next().await.is_some() {}
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Answer queries serve_udp hands over on `socket`, where SO_REUSEPORT doesn't exist
+pub(crate) async fn serve_udp_queue(
+    mut queue: mpsc::Receiver<(Vec<u8>, SocketAddr)>,
+    socket: &UdpSocket,
+    config: &Config,
+    ctx: &LookupContext<'_>,
+    cache: &dyn CacheStore,
+    upstream_socket: &UdpSocket,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut queries = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            query = queue.recv() => {
+                let Some((request, src)) = query else { return Ok(()) };
+                queries.push(answer_udp(request, src, socket, config, ctx, cache, upstream_socket));
+            }
+            Some(()) = queries.next(), if !queries.is_empty() => {}
+            () = ctx.shutting_down() => {
+                while queries.next().await.is_some() {}
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Answer one UDP query from `src` on `socket`, giving both buffers back to the pool
+pub(crate) async fn answer_udp(
+    request: Vec<u8>,
+    src: SocketAddr,
+    socket: &UdpSocket,
+    config: &Config,
+    ctx: &LookupContext<'_>,
+    cache: &dyn CacheStore,
+    upstream_socket: &UdpSocket,
+) {
+    // One bad message must not stop the server
+    let response = match answer_message(&request, src, false, config, ctx, cache, upstream_socket).await {
+        Ok(Some(response)) => match &ctx.rate_limiter {
+            Some(limiter) => limiter.limit(src.ip(), response, ctx.metrics),
+            None => Some(response),
+        },
+        Ok(None) => None,
+        Err(e) => {
+            warn!("Failed to answer query from {}: {}", src, e);
+            None
+        }
+    };
+    if let Some(response) = response {
+        match socket.send_to(&response, src).await {
+            Ok(_) => ctx.metrics.count(&ctx.metrics.responses_sent),
+            Err(e) => warn!("Failed to answer {}: {}", src, e),
+        }
+        ctx.buffers.give(response);
+    }
+    ctx.buffers.give(request);
+}
+
+// Accept TCP connections and serve them side by side
+pub(crate) async fn serve_tcp_listener(
+    listener: &TcpListener,
+    config: &Config,
+    ctx: &LookupContext<'_>,
+    cache: &dyn CacheStore,
+    upstream_socket: &UdpSocket,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut connections = FuturesUnordered::new();
+    let mut failures = ListenerFailures::default();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, src) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => match failures.sort(e).await {
+                        ServerError::Request(e) => {
+                            warn!("Accepting a TCP connection failed: {}", e);
+                            continue;
+                        }
+                        fatal => return Err(fatal.into()),
+                    },
+                };
+                failures.succeeded();
+                info!("Accepted TCP connection from {}", src);
+                connections.push(async move {
+                    if let Err(e) = serve_stream(stream, src, None, config, ctx, cache, upstream_socket).await {
+                        warn!("TCP connection from {} failed: {}", src, e);
+                    }
+                });
+            }
+            Some(()) = connections.next(), if !connections.is_empty() => {}
+            () = ctx.shutting_down() => {
+                while connections.next().await.is_some() {}
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Accept DNS-over-TLS connections and serve them side by side
+pub(crate) async fn serve_dot_listener(
+    listener: &TcpListener,
+    dot: &DotListenConfig,
+    certificates: &TlsCertificates,
+    config: &Config,
+    ctx: &LookupContext<'_>,
+    cache: &dyn CacheStore,
+    upstream_socket: &UdpSocket,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut connections = FuturesUnordered::new();
+    let mut failures = ListenerFailures::default();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, src) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => match failures.sort(e).await {
+                        ServerError::Request(e) => {
+                            warn!("Accepting a DNS-over-TLS connection failed: {}", e);
+                            continue;
+                        }
+                        fatal => return Err(fatal.into()),
+                    },
+                };
+                failures.succeeded();
+                info!("Accepted DNS-over-TLS connection from {}", src);
+                let acceptor = certificates.acceptor(&[b"dot"]);
+                let idle_timeout = Duration::from_secs(config.tcp_idle_timeout);
+                connections.push(async move {
+                    let served = async {
+                        let stream = tokio::time::timeout(idle_timeout, acceptor.accept(stream)).await??;
+                        serve_stream(stream, src, Some(dot.max_queries_per_connection), config, ctx, cache, upstream_socket).await
+                    };
+                    if let Err(e) = served.await {
+                        warn!("DNS-over-TLS connection from {} failed: {}", src, e);
+                    }
+                });
+            }
+            Some(()) = connections.next(), if !connections.is_empty() => {}
+            () = ctx.shutting_down() => {
+                while connections.next().await.is_some() {}
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Serve one TCP or TLS connection: length-prefixed messages, answered in order (so pipelined
+// queries work), until the client closes it, stays idle for `tcp_idle_timeout` seconds or has
+// sent `max_queries` queries
+pub(crate) async fn serve_stream<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    src: SocketAddr,
+    max_queries: Option<usize>,
+    config: &Config,
+    ctx: &LookupContext<'_>,
+    cache: &dyn CacheStore,
+    upstream_socket: &UdpSocket,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let idle_timeout = Duration::from_secs(config.tcp_idle_timeout);
+    let mut queries = 0;
+    loop {
+        if max_queries.is_some_and(|max_queries| queries >= max_queries) {
+            info!("Closing connection from {} after {} queries", src, queries);
+            return Ok(());
+        }
+        queries += 1;
+
+        // Once shutdown starts, finish the query in hand but don't wait for another
+        let mut len_buf = [0u8; 2];
+        let read = tokio::select! {
+            read = tokio::time::timeout(idle_timeout, stream.read_exact(&mut len_buf)) => read,
+            () = ctx.shutting_down() => return Ok(()),
+        };
+        match read {
+            Err(_) => {
+                info!("Closing idle connection from {}", src);
+                return Ok(());
+            }
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(result) => {
+                result?;
+            }
+        }
+
+        let mut request = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+        tokio::time::timeout(idle_timeout, stream.read_exact(&mut request)).await??;
+
+        // A zone transfer is answered with a series of messages on the same connection
+        let message = Message::from_vec(&request).ok();
+        if let Some(message) = message.filter(|m| m.queries().first().is_some_and(|q| q.query_type() == RecordType::AXFR)) {
+            for response in answer_axfr(&request, &message, src, config, ctx).await? {
+                stream.write_all(&(response.len() as u16).to_be_bytes()).await?;
+                stream.write_all(&response).await?;
+                ctx.metrics.count(&ctx.metrics.responses_sent);
+            }
+            continue;
+        }
+
+        let response = answer_message(&request, src, true, config, ctx, cache, upstream_socket).await?;
+        if let Some(response) = response {
+            stream.write_all(&(response.len() as u16).to_be_bytes()).await?;
+            stream.write_all(&response).await?;
+            ctx.metrics.count(&ctx.metrics.responses_sent);
+        }
+    }
+}
+
+// Certificate and key served to TLS clients; reload() swaps in new files for new connections
+pub(crate) struct TlsCertificates {
+    cert_path: String,
+    key_path: String,
+    tls_config: std::sync::RwLock<Arc<ServerConfig>>,
+}
+
+impl TlsCertificates {
+    pub(crate) fn load(cert_path: &str, key_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(TlsCertificates {
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+            tls_config: std::sync::RwLock::new(Self::build_config(cert_path, key_path)?),
+        })
+    }
+
+    fn build_config(cert_path: &str, key_path: &str) -> Result<Arc<ServerConfig>, Box<dyn std::error::Error>> {
+        let certs = CertificateDer::pem_file_iter(cert_path)?.collect::<Result<Vec<_>, _>>()?;
+        let key = PrivateKeyDer::from_pem_file(key_path)?;
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let tls_config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        Ok(Arc::new(tls_config))
+    }
+
+    // Acceptor offering the given ALPN protocols, so DoT and DoH can share one certificate
+    fn acceptor(&self, alpn: &[&[u8]]) -> TlsAcceptor {
+        let mut tls_config = ServerConfig::clone(&self.tls_config.read().unwrap_or_else(|e| e.into_inner()));
+        tls_config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+        TlsAcceptor::from(Arc::new(tls_config))
+    }
+
+    // A broken file on reload keeps the previous certificate in service
+    pub(crate) fn reload(&self) {
+        match Self::build_config(&self.cert_path, &self.key_path) {
+            Ok(tls_config) => {
+                *self.tls_config.write().unwrap_or_else(|e| e.into_inner()) = tls_config;
+                info!("Reloaded TLS certificate from {}", self.cert_path);
+            }
+            Err(e) => warn!("Keeping the current TLS certificate, reload failed: {}", e),
+        }
+    }
+}
+
+// Take commands on the control_socket Unix socket, one per line: `flush` empties the cache,
+// `flush <name>` removes one name and `flush-suffix <domain>` a domain with everything below it.
+// Each command is answered with "OK <entries removed>" or "ERR <reason>"
+#[cfg(unix)]
+pub(crate) async fn serve_control(config: &Config, ctx: &LookupContext<'_>, cache: &dyn CacheStore) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(path) = &config.control_socket else { return Ok(()) };
+    // A socket file left behind by an earlier run would make the bind fail
+    let _ = fs::remove_file(path);
+    let listener = tokio::net::UnixListener::bind(path)?;
+    info!("Control socket listening on {}", path);
+
+    let mut connections = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Control socket accept failed: {}", e);
+                        continue;
+                    }
+                };
+                connections.push(async move {
+                    if let Err(e) = serve_control_connection(stream, ctx, cache).await {
+                        debug!("Control connection ended: {}", e);
+                    }
+                });
+            }
+            Some(()) = connections.next(), if !connections.is_empty() => {}
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn serve_control(config: &Config, _ctx: &LookupContext<'_>, _cache: &dyn CacheStore) -> Result<(), Box<dyn std::error::Error>> {
+    if config.control_socket.is_some() {
+        warn!("control_socket needs Unix domain sockets, not listening");
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub(crate) async fn serve_control_connection(
+    stream: tokio::net::UnixStream,
+    ctx: &LookupContext<'_>,
+    cache: &dyn CacheStore,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let mut words = line.split_whitespace();
+        // `dump [name]` answers one JSON object per entry, then `OK <entries>`
+        if let (Some("dump"), name, None) = (words.next(), words.next(), words.next()) {
+            let reply = match name.map(Name::from_utf8).transpose() {
+                Ok(name) => {
+                    let entries = dump_cache(name.as_ref(), ctx, cache).await;
+                    let mut reply = String::new();
+                    for entry in &entries {
+                        reply += &serde_json::to_string(entry)?;
+                        reply.push('\n');
+                    }
+                    reply + &format!("OK {}\n", entries.len())
+                }
+                Err(e) => format!("ERR {}\n", e),
+            };
+            writer.write_all(reply.as_bytes()).await?;
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let flush = match (words.next(), words.next(), words.next()) {
+            (None, ..) => continue,
+            (Some("flush"), None, _) => Ok(Flush::All),
+            (Some("flush"), Some(name), None) => Name::from_utf8(name).map(Flush::Name).map_err(|e| e.to_string()),
+            (Some("flush-suffix"), Some(zone), None) => Name::from_utf8(zone).map(Flush::Suffix).map_err(|e| e.to_string()),
+            _ => Err(format!("unknown command: {}", line.trim())),
+        };
+        let reply = match flush {
+            Ok(flush) => format!("OK {}\n", flush_cache(&flush, ctx, cache).await),
+            Err(e) => format!("ERR {}\n", e),
+        };
+        writer.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+// A DNS-over-HTTPS request waiting for the query loop to answer it
+pub(crate) struct DohQuery {
+    request: Vec<u8>,
+    src: SocketAddr,
+    reply: oneshot::Sender<Option<Vec<u8>>>,
+}
+
+// Accept DNS-over-HTTPS connections. HTTP/2 runs each request on its own task, so requests are
+// passed to serve_doh_queries over a channel instead of borrowing the lookup state
+pub(crate) async fn serve_doh_listener(
+    listener: &TcpListener,
+    path: &str,
+    certificates: &TlsCertificates,
+    queries: mpsc::Sender<DohQuery>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path: Arc<str> = Arc::from(path);
+    let mut failures = ListenerFailures::default();
+    loop {
+        let (stream, src) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => match failures.sort(e).await {
+                ServerError::Request(e) => {
+                    warn!("Accepting a DNS-over-HTTPS connection failed: {}", e);
+                    continue;
+                }
+                fatal => return Err(fatal.into()),
+            },
+        };
+        failures.succeeded();
+        info!("Accepted DNS-over-HTTPS connection from {}", src);
+        let acceptor = certificates.acceptor(&[b"h2", b"http/1.1"]);
+        let path = path.clone();
+        let queries = queries.clone();
+        tokio::spawn(async move {
+            let stream = match tokio::time::timeout(Duration::from_secs(10), acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => return warn!("DNS-over-HTTPS handshake with {} failed: {}", src, e),
+                Err(_) => return warn!("DNS-over-HTTPS handshake with {} timed out", src),
+            };
+            let service = service_fn(move |request| handle_doh_request(request, src, path.clone(), queries.clone()));
+            if let Err(e) = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                warn!("DNS-over-HTTPS connection from {} failed: {}", src, e);
+            }
+        });
+    }
+}
+
+// RFC 8484: GET with a base64url `dns` parameter, or POST with an application/dns-message body
+pub(crate) async fn handle_doh_request(
+    request: hyper::Request<Incoming>,
+    src: SocketAddr,
+    path: Arc<str>,
+    queries: mpsc::Sender<DohQuery>,
+) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
+    if request.uri().path() != &*path {
+        return Ok(doh_error(StatusCode::NOT_FOUND));
+    }
+
+    let message = match *request.method() {
+        Method::GET => {
+            let dns = request
+                .uri()
+                .query()
+                .and_then(|query| query.split('&').find_map(|pair| pair.strip_prefix("dns=")));
+            match dns.and_then(|dns| URL_SAFE_NO_PAD.decode(dns.trim_end_matches('=')).ok()) {
+                Some(message) => message,
+                None => return Ok(doh_error(StatusCode::BAD_REQUEST)),
+            }
+        }
+        Method::POST => {
+            let content_type = request.headers().get(hyper::header::CONTENT_TYPE);
+            if content_type.is_none_or(|content_type| content_type != "application/dns-message") {
+                return Ok(doh_error(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+            }
+            match Limited::new(request.into_body(), u16::MAX as usize).collect().await {
+                Ok(body) => body.to_bytes().to_vec(),
+                Err(_) => return Ok(doh_error(StatusCode::PAYLOAD_TOO_LARGE)),
+            }
+        }
+        _ => return Ok(doh_error(StatusCode::METHOD_NOT_ALLOWED)),
+    };
+
+    let (reply, answer) = oneshot::channel();
+    if queries.send(DohQuery { request: message, src, reply }).await.is_err() {
+        return Ok(doh_error(StatusCode::SERVICE_UNAVAILABLE));
+    }
+    let Ok(Some(response)) = answer.await else {
+        return Ok(doh_error(StatusCode::BAD_REQUEST));
+    };
+
+    // Let HTTP caches keep the answer no longer than its shortest TTL
+    let max_age = Message::from_vec(&response)
+        .ok()
+        .and_then(|message| {
+            message.answers().iter().chain(message.name_servers()).map(|record| record.ttl()).min()
+        })
+        .unwrap_or(0);
+    let mut http_response = hyper::Response::new(Full::new(Bytes::from(response)));
+    let headers = http_response.headers_mut();
+    headers.insert(hyper::header::CONTENT_TYPE, HeaderValue::from_static("application/dns-message"));
+    headers.insert(hyper::header::CACHE_CONTROL, HeaderValue::from_str(&format!("max-age={}", max_age)).unwrap());
+    Ok(http_response)
+}
+
+pub(crate) fn doh_error(status: StatusCode) -> hyper::Response<Full<Bytes>> {
+    let mut response = hyper::Response::new(Full::new(Bytes::new()));
+    *response.status_mut() = status;
+    response
+}
+
+// Answer DNS-over-HTTPS requests handed over by serve_doh_listener
+pub(crate) async fn serve_doh_queries(
+    mut queries: mpsc::Receiver<DohQuery>,
+    config: &Config,
+    ctx: &LookupContext<'_>,
+    cache: &dyn CacheStore,
+    upstream_socket: &UdpSocket,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut answering = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            query = queries.recv() => {
+                let Some(query) = query else { return Ok(()) };
+                answering.push(async move {
+                    let response = match answer_message(&query.request, query.src, true, config, ctx, cache, upstream_socket).await {
+                        Ok(response) => response,
+                        Err(e) => {
+                            warn!("DNS-over-HTTPS query from {} failed: {}", query.src, e);
+                            None
+                        }
+                    };
+                    let answered = response.is_some();
+                    if query.reply.send(response).is_ok() && answered {
+                        ctx.metrics.count(&ctx.metrics.responses_sent);
+                    }
+                });
+            }
+            Some(()) = answering.next(), if !answering.is_empty() => {}
+            () = ctx.shutting_down() => {
+                while answering.next().await.is_some() {}
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Bind `count` UDP sockets on the same address with SO_REUSEPORT, so the kernel spreads incoming
+// queries over them. Where the option doesn't exist, there is only the one socket, and the main
+// worker hands queries over to the others (see UdpWorkerQueries)
+pub(crate) fn bind_udp_workers(addr: SocketAddr, count: usize) -> Result<Vec<std::net::UdpSocket>, Box<dyn std::error::Error>> {
+    let bind = || -> std::io::Result<std::net::UdpSocket> {
+        let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+        socket.set_reuse_port(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        Ok(socket.into())
+    };
+
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    let sockets = (0..count).map(|_| bind()).collect::<Result<Vec<_>, _>>()?;
+    #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+    let sockets = {
+        let _ = count;
+        vec![bind()?]
+    };
+    Ok(sockets)
+}
+
+// Where an extra UDP worker gets its queries: a socket of its own, or a queue fed by the main
+// worker from the listening socket, which it answers on
+pub(crate) enum UdpWorkerQueries<'a> {
+    Socket(std::net::UdpSocket),
+    Queue(mpsc::Receiver<(Vec<u8>, SocketAddr)>, &'a UdpSocket),
+}
+
+// Run an extra UDP worker on its own thread with a runtime of its own, until `stop` changes.
+// It shares the cache, database pool and upstream state with the rest of the server.
+pub(crate) fn run_udp_worker(
+    worker: usize,
+    queries: UdpWorkerQueries<'_>,
+    config: &Config,
+    ctx: &LookupContext<'_>,
+    cache: &dyn CacheStore,
+    upstream_socket: &UdpSocket,
+    mut stop: watch::Receiver<bool>,
+) {
+    let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            error!("UDP worker {} could not start: {}", worker, e);
+            return;
+        }
+    };
+    let served = runtime.block_on(async {
+        let serve = async {
+            match queries {
+                UdpWorkerQueries::Socket(socket) => serve_udp(&UdpSocket::from_std(socket)?, &[], config, ctx, cache, upstream_socket).await,
+                UdpWorkerQueries::Queue(queue, socket) => serve_udp_queue(queue, socket, config, ctx, cache, upstream_socket).await,
+            }
+        };
+        tokio::select! {
+            served = serve => served,
+            _ = stop.changed() => Ok(()),
+        }
+    });
+    if let Err(e) = served {
+        error!("UDP worker {} stopped: {}", worker, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn listener_errors_turn_fatal_only_when_they_keep_happening() {
+        use std::io::{Error, ErrorKind};
+        let mut failures = ListenerFailures::default();
+
+        // A client resetting its connection never counts against the socket
+        for _ in 0..MAX_LISTENER_FAILURES * 2 {
+            assert!(matches!(failures.sort(Error::from(ErrorKind::ConnectionReset)).await, ServerError::Request(_)));
+        }
+
+        // Running out of file descriptors is waited out, and forgotten once the socket works again
+        let exhausted = || Error::from_raw_os_error(24);
+        for _ in 1..MAX_LISTENER_FAILURES {
+            assert!(matches!(failures.sort(exhausted()).await, ServerError::Request(_)));
+        }
+        failures.succeeded();
+        for _ in 1..MAX_LISTENER_FAILURES {
+            assert!(matches!(failures.sort(exhausted()).await, ServerError::Request(_)));
+        }
+        assert!(matches!(failures.sort(exhausted()).await, ServerError::Fatal(_)));
+    }
+}
@@ -0,0 +1,584 @@
+use super::*;
+
+pub type DbFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, DbError>> + Send + 'a>>;
+
+// A database failure: no connection to run the statement on, the statement itself failing, no
+// answer in time, or something the data source can't do. Prerequisite gives the index of the
+// first prerequisite of an update that doesn't hold
+#[derive(Debug)]
+pub enum DbError {
+    Connection(String),
+    Query(String),
+    Timeout(Duration),
+    Unsupported(&'static str),
+    Prerequisite(usize),
+}
+
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::Connection(reason) => write!(f, "connection failed: {}", reason),
+            DbError::Query(reason) => write!(f, "{}", reason),
+            DbError::Timeout(limit) => write!(f, "no answer within {:?}", limit),
+            DbError::Unsupported(what) => write!(f, "{} is not supported by the data source", what),
+            DbError::Prerequisite(index) => write!(f, "prerequisite {} of the update doesn't hold", index + 1),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+// Where override records come from. The server only needs lookup; a source without export
+// answers AXFR with NOTIMP, and one without update refuses dynamic updates the same way.
+// SqlSource serves each database in db_settings, and Server::with_source takes any other source
+pub trait DataSource: Send + Sync {
+    // The records of a name, lowercase without the trailing dot. Without a record type, or when
+    // the source doesn't look up by type, every type comes at once, since the cache keeps a
+    // name's types together
+    fn lookup<'a>(&'a self, name: &'a str, record_type: Option<&'a str>) -> DbFuture<'a, Vec<DnsRecord>>;
+
+    // Whether lookup with a record type returns only that type and CNAME, so that what a name
+    // has for one type says nothing about its others
+    fn by_type(&self) -> bool {
+        false
+    }
+
+    // Every record the source holds with its owner name, for preloading
+    fn export_all(&self) -> DbFuture<'_, Vec<(String, DnsRecord)>> {
+        Box::pin(async { Err(DbError::Unsupported("preloading")) })
+    }
+
+    // Bring the records held in memory up to date; returns the names that changed
+    fn reload(&self) -> DbFuture<'_, Vec<String>> {
+        Box::pin(async { Ok(Vec::new()) })
+    }
+
+    // Every record at and below a zone origin with its owner name, for zone transfers
+    fn export<'a>(&'a self, _origin: &'a str) -> DbFuture<'a, Vec<(String, DnsRecord)>> {
+        Box::pin(async { Err(DbError::Unsupported("zone export")) })
+    }
+
+    // A zone's current serial, if the source tracks one
+    fn zone_serial<'a>(&'a self, _origin: &'a str) -> DbFuture<'a, Option<u32>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    // Names whose address records hold an IP, for synthesize_ptr
+    fn names_for_address<'a>(&'a self, _ip: &'a str) -> DbFuture<'a, Vec<String>> {
+        Box::pin(async { Ok(Vec::new()) })
+    }
+
+    // Apply a dynamic update all at once if its prerequisites hold, checking them in the same
+    // transaction; returns the names it changed
+    fn update<'a>(&'a self, _prerequisites: &'a [Prerequisite], _operations: &'a [UpdateOperation]) -> DbFuture<'a, Vec<String>> {
+        Box::pin(async { Err(DbError::Unsupported("dynamic update")) })
+    }
+
+    // Whether the source answers, for finding out when it is back after failing
+    fn ping(&self) -> DbFuture<'_, ()> {
+        Box::pin(async move { self.lookup("fusiondns-ping.invalid", None).await.map(drop) })
+    }
+}
+
+// The SQL database in db_settings as the data source, through the SQL settings of Config
+pub(crate) struct SqlSource {
+    database: Box<dyn SqlDatabase>,
+    sql_query: String,
+    axfr_sql: String,
+    preload_sql: String,
+    zone_serial_sql: Option<String>,
+    ptr_sql_query: String,
+    update_insert_sql: String,
+    update_delete_sql: String,
+    default_ttl: u32,
+    // Whether sql_query takes the record type as well as the name
+    by_type: bool,
+}
+
+impl SqlSource {
+    pub(crate) fn new(database: Box<dyn SqlDatabase>, config: &Config) -> Self {
+        // The type is a second `?` or `:type`; bound as NULL, it shows among the values
+        let by_type = bind_params(&config.sql_query, &[("address", Some("")), ("type", None)], false)
+            .is_ok_and(|(_, values)| values.contains(&None));
+        if by_type {
+            info!("sql_query takes the record type, looking names up one type at a time");
+        }
+        SqlSource {
+            database,
+            sql_query: config.sql_query.clone(),
+            axfr_sql: config.axfr_sql.clone(),
+            preload_sql: config.preload_sql.clone(),
+            zone_serial_sql: config.zone_serial_sql.clone(),
+            ptr_sql_query: config.ptr_sql_query.clone(),
+            update_insert_sql: config.update_insert_sql.clone(),
+            update_delete_sql: config.update_delete_sql.clone(),
+            default_ttl: config.default_ttl,
+            by_type,
+        }
+    }
+
+    // Named parameters for the whole-zone queries: the origin itself and a LIKE pattern below it
+    fn zone_params(origin: &str) -> (String, String) {
+        let origin = origin.trim_end_matches('.');
+        (origin.to_string(), format!("%.{}", origin))
+    }
+
+    async fn rows_of(&self, transaction: &mut dyn DbTransaction, name: &str, record_type: Option<&str>) -> Result<Vec<DnsRecord>, DbError> {
+        let rows = transaction.query(&self.sql_query, &[("address", Some(name)), ("type", record_type)]).await?;
+        Ok(rows.iter().filter_map(|row| row_to_record(row, self.default_ttl)).collect())
+    }
+
+    async fn rows(&self, name: &str, record_type: Option<&str>) -> Result<Vec<DnsRecord>, DbError> {
+        let rows = self.database.query(&self.sql_query, &[("address", Some(name)), ("type", record_type)]).await?;
+        Ok(rows.iter().filter_map(|row| row_to_record(row, self.default_ttl)).collect())
+    }
+
+    // Rows of the owner name followed by a row as sql_query returns it
+    fn owned_records(&self, rows: Vec<DbRow>) -> Vec<(String, DnsRecord)> {
+        rows.into_iter()
+            .filter_map(|row| {
+                let name = row.get(0)?.to_string();
+                let record = DbRow { columns: row.columns[1..].into(), values: row.values[1..].to_vec() };
+                Some((name, row_to_record(&record, self.default_ttl)?))
+            })
+            .collect()
+    }
+}
+
+impl DataSource for SqlSource {
+    // sql_query takes the name as `?` or `:address`, and optionally the type as a second `?` or
+    // `:type`, NULL when every type is wanted. A name without rows of the type is asked for its
+    // CNAME in a second query, so CNAMEs keep answering for every type
+    fn lookup<'a>(&'a self, name: &'a str, record_type: Option<&'a str>) -> DbFuture<'a, Vec<DnsRecord>> {
+        Box::pin(async move {
+            let rows = self.rows(name, record_type).await?;
+            match record_type {
+                Some(record_type) if self.by_type && rows.is_empty() && record_type != "CNAME" => self.rows(name, Some("CNAME")).await,
+                _ => Ok(rows),
+            }
+        })
+    }
+
+    fn by_type(&self) -> bool {
+        self.by_type
+    }
+
+    fn export<'a>(&'a self, origin: &'a str) -> DbFuture<'a, Vec<(String, DnsRecord)>> {
+        Box::pin(async move {
+            let (origin, suffix) = Self::zone_params(origin);
+            let rows = self.database.query(&self.axfr_sql, &[("origin", Some(&origin)), ("suffix", Some(&suffix))]).await?;
+            Ok(self.owned_records(rows))
+        })
+    }
+
+    fn export_all(&self) -> DbFuture<'_, Vec<(String, DnsRecord)>> {
+        Box::pin(async move { Ok(self.owned_records(self.database.query(&self.preload_sql, &[]).await?)) })
+    }
+
+    fn zone_serial<'a>(&'a self, origin: &'a str) -> DbFuture<'a, Option<u32>> {
+        Box::pin(async move {
+            let Some(serial_sql) = &self.zone_serial_sql else { return Ok(None) };
+            let (origin, suffix) = Self::zone_params(origin);
+            let rows = self.database.query(serial_sql, &[("origin", Some(&origin)), ("suffix", Some(&suffix))]).await?;
+            match rows.first().and_then(|row| row.get(0)) {
+                Some(serial) => serial.parse().map(Some).map_err(|_| DbError::Query(format!("serial {} is not a number", serial))),
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn names_for_address<'a>(&'a self, ip: &'a str) -> DbFuture<'a, Vec<String>> {
+        Box::pin(async move {
+            let rows = self.database.query(&self.ptr_sql_query, &[("value", Some(ip))]).await?;
+            Ok(rows.iter().filter_map(|row| row.get(0)).map(str::to_string).collect())
+        })
+    }
+
+    fn update<'a>(&'a self, prerequisites: &'a [Prerequisite], operations: &'a [UpdateOperation]) -> DbFuture<'a, Vec<String>> {
+        Box::pin(async move {
+            let mut touched = Vec::new();
+            let mut transaction = self.database.begin().await?;
+            for (index, prerequisite) in prerequisites.iter().enumerate() {
+                let rows = self.rows_of(transaction.as_mut(), prerequisite.name(), None).await?;
+                if !prerequisite.holds(&rows) {
+                    return Err(DbError::Prerequisite(index));
+                }
+            }
+            for operation in operations {
+                match operation {
+                    UpdateOperation::Add { name, record_type, value } => {
+                        // Adding a record that already exists changes nothing
+                        let rows = self.rows_of(transaction.as_mut(), name, Some(record_type)).await?;
+                        if rows.iter().any(|row| &row.record_type == record_type && &row.value == value) {
+                            continue;
+                        }
+                        info!("Update: add {} {} {}", name, record_type, value);
+                        let params = [("address", Some(name.as_str())), ("type", Some(record_type.as_str())), ("value", Some(value.as_str()))];
+                        transaction.query(&self.update_insert_sql, &params).await?;
+                        touched.push(name.clone());
+                    }
+                    UpdateOperation::Delete { name, record_type, value } => {
+                        info!("Update: delete {} {:?} {:?}", name, record_type, value);
+                        let params = [("address", Some(name.as_str())), ("type", record_type.as_deref()), ("value", value.as_deref())];
+                        transaction.query(&self.update_delete_sql, &params).await?;
+                        touched.push(name.clone());
+                    }
+                }
+            }
+            transaction.commit().await?;
+            Ok(touched)
+        })
+    }
+
+    fn ping(&self) -> DbFuture<'_, ()> {
+        Box::pin(async move { self.database.query("SELECT 1", &[]).await.map(drop) })
+    }
+}
+
+// Every override of another source held in memory, for mode preload. Lookups, zone exports and
+// PTR synthesis are answered from the index and never wait for the database or notice it is
+// down; zone serials and updates still go to it. reload builds a whole new index before swapping
+// it in, so a lookup sees either the old table or the new one, never part of it
+pub(crate) struct PreloadedSource<'a> {
+    inner: Box<dyn DataSource + 'a>,
+    index: std::sync::RwLock<Arc<HashMap<String, Vec<DnsRecord>>>>,
+}
+
+impl<'a> PreloadedSource<'a> {
+    pub(crate) fn new(inner: Box<dyn DataSource + 'a>) -> Self {
+        PreloadedSource { inner, index: std::sync::RwLock::new(Arc::new(HashMap::new())) }
+    }
+
+    fn index(&self) -> Arc<HashMap<String, Vec<DnsRecord>>> {
+        self.index.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    // Records as if just read from the database, so their TTLs count from now
+    fn fresh(records: &[DnsRecord]) -> impl Iterator<Item = DnsRecord> + '_ {
+        let now = now_secs();
+        records.iter().map(move |record| DnsRecord { inserted_at: Some(now), ..record.clone() })
+    }
+}
+
+impl DataSource for PreloadedSource<'_> {
+    fn lookup<'a>(&'a self, name: &'a str, _record_type: Option<&'a str>) -> DbFuture<'a, Vec<DnsRecord>> {
+        Box::pin(async move { Ok(self.index().get(name).map(|records| Self::fresh(records).collect()).unwrap_or_default()) })
+    }
+
+    fn export<'a>(&'a self, origin: &'a str) -> DbFuture<'a, Vec<(String, DnsRecord)>> {
+        Box::pin(async move {
+            let origin = origin.trim_end_matches('.');
+            let suffix = format!(".{}", origin);
+            Ok(self
+                .index()
+                .iter()
+                .filter(|(name, _)| *name == origin || name.ends_with(&suffix))
+                .flat_map(|(name, records)| Self::fresh(records).map(move |record| (name.clone(), record)))
+                .collect())
+        })
+    }
+
+    fn zone_serial<'a>(&'a self, origin: &'a str) -> DbFuture<'a, Option<u32>> {
+        self.inner.zone_serial(origin)
+    }
+
+    fn names_for_address<'a>(&'a self, ip: &'a str) -> DbFuture<'a, Vec<String>> {
+        Box::pin(async move {
+            Ok(self
+                .index()
+                .iter()
+                .filter(|(_, records)| records.iter().any(|record| matches!(record.record_type.as_str(), "A" | "AAAA") && record.value == ip))
+                .map(|(name, _)| name.clone())
+                .collect())
+        })
+    }
+
+    // The index only catches up with an update on the next reload, so reload right away
+    fn update<'a>(&'a self, prerequisites: &'a [Prerequisite], operations: &'a [UpdateOperation]) -> DbFuture<'a, Vec<String>> {
+        Box::pin(async move {
+            let touched = self.inner.update(prerequisites, operations).await?;
+            if let Err(e) = self.reload().await {
+                warn!("Reloading the overrides after an update failed: {}", e);
+            }
+            Ok(touched)
+        })
+    }
+
+    fn ping(&self) -> DbFuture<'_, ()> {
+        self.inner.ping()
+    }
+
+    fn reload(&self) -> DbFuture<'_, Vec<String>> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let mut index: HashMap<String, Vec<DnsRecord>> = HashMap::new();
+            let mut count = 0;
+            for (name, record) in self.inner.export_all().await? {
+                index.entry(name.trim_end_matches('.').to_lowercase()).or_default().push(record);
+                count += 1;
+            }
+
+            // Names added, removed or with other records than before
+            let same = |a: &DnsRecord, b: &DnsRecord| (&a.record_type, &a.value, a.ttl, a.weight) == (&b.record_type, &b.value, b.ttl, b.weight);
+            let previous = self.index();
+            let mut changed: Vec<String> = index
+                .iter()
+                .filter(|(name, records)| {
+                    previous.get(*name).is_none_or(|old| old.len() != records.len() || old.iter().zip(records.iter()).any(|(a, b)| !same(a, b)))
+                })
+                .map(|(name, _)| name.clone())
+                .collect();
+            changed.extend(previous.keys().filter(|name| !index.contains_key(*name)).cloned());
+
+            info!("Loaded {} overrides for {} names in {:?}, {} names changed", count, index.len(), started.elapsed(), changed.len());
+            *self.index.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(index);
+            Ok(changed)
+        })
+    }
+}
+
+// First and longest wait between tries of a database that is down
+pub(crate) const DB_RETRY_MIN: Duration = Duration::from_secs(1);
+
+pub(crate) const DB_RETRY_MAX: Duration = Duration::from_secs(60);
+
+// Longest a zone transfer's export or a dynamic update may take; they are off the query path
+// and may touch many rows, so db_timeout_ms is too short for them
+pub(crate) const DB_BULK_TIMEOUT: Duration = Duration::from_secs(30);
+
+// A data source with deadlines that is taken for down after db_failure_threshold connection
+// failures or timeouts in a row. While it is down, calls fail at once instead of each waiting
+// out a timeout, so lookups go to the next database or straight to stale cache entries or
+// upstream; watch_health tries it with a doubling backoff and takes it back when it answers.
+// Failed statements on a working connection don't count
+pub(crate) struct HealthCheckedSource<'a> {
+    // The URL without credentials, for logs and stats
+    pub(crate) name: String,
+    inner: Box<dyn DataSource + 'a>,
+    threshold: usize,
+    timeout: Duration,
+    slow: Duration,
+    failures: AtomicUsize,
+    timeouts: AtomicU64,
+    down: AtomicBool,
+    went_down: Notify,
+}
+
+impl<'a> HealthCheckedSource<'a> {
+    pub(crate) fn new(name: String, inner: Box<dyn DataSource + 'a>, config: &Config) -> Self {
+        HealthCheckedSource {
+            name,
+            inner,
+            threshold: config.db_failure_threshold.max(1),
+            timeout: Duration::from_millis(config.db_timeout_ms.max(1)),
+            slow: Duration::from_millis(config.db_slow_ms),
+            failures: AtomicUsize::new(0),
+            timeouts: AtomicU64::new(0),
+            down: AtomicBool::new(false),
+            went_down: Notify::new(),
+        }
+    }
+
+    pub(crate) fn is_down(&self) -> bool {
+        self.down.load(Ordering::Relaxed)
+    }
+
+    // Calls that ran out of time since startup
+    pub(crate) fn timeouts(&self) -> u64 {
+        self.timeouts.load(Ordering::Relaxed)
+    }
+
+    async fn checked<T>(&self, call: DbFuture<'_, T>, limit: Duration, what: impl FnOnce() -> String) -> Result<T, DbError> {
+        if self.is_down() {
+            return Err(DbError::Connection("the database is down, waiting for it to come back".to_string()));
+        }
+        let started = Instant::now();
+        let result = match tokio::time::timeout(limit, call).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.timeouts.fetch_add(1, Ordering::Relaxed);
+                Err(DbError::Timeout(limit))
+            }
+        };
+        let elapsed = started.elapsed();
+        if result.is_ok() && elapsed >= self.slow {
+            warn!("Slow database {}: {:?}", what(), elapsed);
+        }
+        match &result {
+            Err(e @ (DbError::Connection(_) | DbError::Timeout(_))) => {
+                let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if failures >= self.threshold && !self.down.swap(true, Ordering::Relaxed) {
+                    warn!("Database {} down after {} failed connections in a row ({}), skipping it until it answers again", self.name, failures, e);
+                    self.went_down.notify_one();
+                }
+            }
+            _ => self.failures.store(0, Ordering::Relaxed),
+        }
+        result
+    }
+
+    // Each time the source goes down, try it after DB_RETRY_MIN, doubling up to DB_RETRY_MAX,
+    // until it answers
+    pub(crate) async fn watch_health(&self) -> Result<(), Box<dyn std::error::Error>> {
+        loop {
+            self.went_down.notified().await;
+            let mut backoff = DB_RETRY_MIN;
+            loop {
+                tokio::time::sleep(backoff).await;
+                match tokio::time::timeout(self.timeout, self.inner.ping()).await.unwrap_or(Err(DbError::Timeout(self.timeout))) {
+                    Ok(()) => break,
+                    Err(e) => {
+                        backoff = (backoff * 2).min(DB_RETRY_MAX);
+                        debug!("Database {} still down ({}), trying again in {:?}", self.name, e, backoff);
+                    }
+                }
+            }
+            self.failures.store(0, Ordering::Relaxed);
+            self.down.store(false, Ordering::Relaxed);
+            info!("Database {} answers again, using it for lookups", self.name);
+        }
+    }
+}
+
+impl DataSource for HealthCheckedSource<'_> {
+    fn lookup<'a>(&'a self, name: &'a str, record_type: Option<&'a str>) -> DbFuture<'a, Vec<DnsRecord>> {
+        Box::pin(self.checked(self.inner.lookup(name, record_type), self.timeout, move || format!("lookup of {}", name)))
+    }
+
+    fn by_type(&self) -> bool {
+        self.inner.by_type()
+    }
+
+    fn export<'a>(&'a self, origin: &'a str) -> DbFuture<'a, Vec<(String, DnsRecord)>> {
+        Box::pin(self.checked(self.inner.export(origin), DB_BULK_TIMEOUT, move || format!("export of zone {}", origin)))
+    }
+
+    fn export_all(&self) -> DbFuture<'_, Vec<(String, DnsRecord)>> {
+        Box::pin(self.checked(self.inner.export_all(), DB_BULK_TIMEOUT, || "load of every override".to_string()))
+    }
+
+    fn zone_serial<'a>(&'a self, origin: &'a str) -> DbFuture<'a, Option<u32>> {
+        Box::pin(self.checked(self.inner.zone_serial(origin), self.timeout, move || format!("serial query of zone {}", origin)))
+    }
+
+    fn names_for_address<'a>(&'a self, ip: &'a str) -> DbFuture<'a, Vec<String>> {
+        Box::pin(self.checked(self.inner.names_for_address(ip), self.timeout, move || format!("PTR lookup of {}", ip)))
+    }
+
+    fn update<'a>(&'a self, prerequisites: &'a [Prerequisite], operations: &'a [UpdateOperation]) -> DbFuture<'a, Vec<String>> {
+        Box::pin(self.checked(self.inner.update(prerequisites, operations), DB_BULK_TIMEOUT, move || format!("update of {} records", operations.len())))
+    }
+
+    fn ping(&self) -> DbFuture<'_, ()> {
+        self.inner.ping()
+    }
+}
+
+// The databases in db_settings, each with its own health tracking. A read goes to the first one
+// that isn't down and moves on to the next when it can't be reached or runs out of time; a failed
+// statement is returned as it is, since the others hold the same table. With db_round_robin, each
+// read starts one database further along, spreading reads over every one that is up. Updates
+// only go to the first database, the primary
+pub(crate) struct FailoverSource<'a> {
+    databases: &'a [HealthCheckedSource<'a>],
+    round_robin: bool,
+    next: AtomicUsize,
+}
+
+impl<'a> FailoverSource<'a> {
+    pub(crate) fn new(databases: &'a [HealthCheckedSource<'a>], round_robin: bool) -> Self {
+        FailoverSource { databases, round_robin, next: AtomicUsize::new(0) }
+    }
+
+    async fn read<'b, T>(&'b self, call: impl Fn(&'b HealthCheckedSource<'a>) -> DbFuture<'b, T>) -> Result<T, DbError> {
+        let count = self.databases.len();
+        let start = if self.round_robin { self.next.fetch_add(1, Ordering::Relaxed) % count } else { 0 };
+        let mut failed = None;
+        for database in (0..count).map(|i| &self.databases[(start + i) % count]) {
+            if database.is_down() {
+                continue;
+            }
+            match call(database).await {
+                Err(e @ (DbError::Connection(_) | DbError::Timeout(_))) => {
+                    if count > 1 {
+                        warn!("Database {} failed ({}), trying the next one", database.name, e);
+                    }
+                    failed = Some(e);
+                }
+                result => return result,
+            }
+        }
+        match failed {
+            Some(e) => Err(e),
+            // With every database down, the first one says so at once
+            None => call(&self.databases[start]).await,
+        }
+    }
+}
+
+impl DataSource for FailoverSource<'_> {
+    fn lookup<'b>(&'b self, name: &'b str, record_type: Option<&'b str>) -> DbFuture<'b, Vec<DnsRecord>> {
+        Box::pin(self.read(move |database| database.lookup(name, record_type)))
+    }
+
+    fn by_type(&self) -> bool {
+        self.databases[0].by_type()
+    }
+
+    fn export<'b>(&'b self, origin: &'b str) -> DbFuture<'b, Vec<(String, DnsRecord)>> {
+        Box::pin(self.read(move |database| database.export(origin)))
+    }
+
+    fn zone_serial<'b>(&'b self, origin: &'b str) -> DbFuture<'b, Option<u32>> {
+        Box::pin(self.read(move |database| database.zone_serial(origin)))
+    }
+
+    fn names_for_address<'b>(&'b self, ip: &'b str) -> DbFuture<'b, Vec<String>> {
+        Box::pin(self.read(move |database| database.names_for_address(ip)))
+    }
+
+    fn update<'b>(&'b self, prerequisites: &'b [Prerequisite], operations: &'b [UpdateOperation]) -> DbFuture<'b, Vec<String>> {
+        self.databases[0].update(prerequisites, operations)
+    }
+
+    fn export_all(&self) -> DbFuture<'_, Vec<(String, DnsRecord)>> {
+        Box::pin(self.read(|database| database.export_all()))
+    }
+}
+
+// Longest TTL a database row may set; longer ones, and 0, are clamped with a warning
+pub(crate) const MAX_RECORD_TTL: u32 = 7 * 24 * 3600;
+
+// Read a database row: `type` and `value` first, an optional TTL third (a column named `ttl`, or
+// any third column not named `weight`), plus an optional `weight` column. Rows without a TTL, or
+// with a NULL one, get `default_ttl`
+pub(crate) fn row_to_record(row: &DbRow, default_ttl: u32) -> Option<DnsRecord> {
+    let record_type = row.get(0)?.to_string();
+    let value = row.get(1)?.to_string();
+    let weight = row.column("weight").and_then(|column| row.get(column)?.parse::<u32>().ok());
+    let record_type = if record_type == "ANAME" { "ALIAS".to_string() } else { record_type };
+    let ttl_column = row
+        .column("ttl")
+        .or_else(|| row.columns.get(2).filter(|column| column.as_str() != "weight").map(|_| 2));
+    let ttl = match ttl_column.and_then(|column| row.get(column)).map(str::parse::<i64>) {
+        Some(Ok(ttl)) if ttl < 1 || ttl > MAX_RECORD_TTL as i64 => {
+            let clamped = ttl.clamp(1, MAX_RECORD_TTL as i64) as u32;
+            warn!("TTL {} of the {} row {} is out of range, using {}", ttl, record_type, value, clamped);
+            clamped
+        }
+        Some(Ok(ttl)) => ttl as u32,
+        None => default_ttl,
+        Some(Err(e)) => {
+            warn!("Unreadable TTL in the {} row {} ({}), using {}", record_type, value, e, default_ttl);
+            default_ttl
+        }
+    };
+
+    Some(DnsRecord {
+        record_type,
+        value,
+        ttl,
+        weight,
+        inserted_at: Some(now_secs()),
+    })
+}
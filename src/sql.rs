@@ -0,0 +1,567 @@
+use super::*;
+
+// Statement parameters by name, in the order `?` placeholders take them; None is NULL
+pub(crate) type SqlParams<'a> = [(&'a str, Option<&'a str>)];
+
+// A result row as text, whichever database it came from; NULL is None
+pub(crate) struct DbRow {
+    pub(crate) columns: Arc<[String]>,
+    pub(crate) values: Vec<Option<String>>,
+}
+
+impl DbRow {
+    pub(crate) fn get(&self, index: usize) -> Option<&str> {
+        self.values.get(index)?.as_deref()
+    }
+
+    pub(crate) fn column(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|column| column == name)
+    }
+}
+
+// An SQL database: MySQL/MariaDB, PostgreSQL or SQLite, by the scheme of db_settings.
+// Statements take their parameters as `?` in order or as `:name`, whichever the database
+pub(crate) trait SqlDatabase: Send + Sync {
+    // Rows of one statement, run on a pooled connection
+    fn query<'a>(&'a self, sql: &'a str, params: &'a SqlParams<'a>) -> DbFuture<'a, Vec<DbRow>>;
+
+    // A transaction on a connection of its own, rolled back if dropped before commit
+    fn begin(&self) -> DbFuture<'_, Box<dyn DbTransaction + '_>>;
+}
+
+pub(crate) trait DbTransaction: Send {
+    fn query<'a>(&'a mut self, sql: &'a str, params: &'a SqlParams<'a>) -> DbFuture<'a, Vec<DbRow>>;
+
+    fn commit(&mut self) -> DbFuture<'_, ()>;
+}
+
+// The database a db_settings URL points at
+pub(crate) fn open_database(url: &str, config: &Config) -> Result<Box<dyn SqlDatabase>, Box<dyn std::error::Error>> {
+    let mysql = !["postgres://", "postgresql://", "sqlite://"].iter().any(|scheme| url.starts_with(scheme));
+    if !mysql && config.db_tls.as_ref().is_some_and(|tls| tls.enabled) {
+        return Err("db_tls is only supported for MySQL and MariaDB".into());
+    }
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        let postgres = postgres_config(url)?;
+        info!("Using PostgreSQL database {}", redact_url(url));
+        return Ok(Box::new(PostgresDatabase::new(postgres, config.max_db_concurrency)));
+    }
+    if let Some(path) = url.strip_prefix("sqlite://") {
+        return open_sqlite(path, config.init_schema);
+    }
+    Ok(Box::new(MySqlDatabase { pool: mysql_pool(url, config)? }))
+}
+
+// A database URL for logs, without the user and password
+pub(crate) fn redact_url(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else { return url.to_string() };
+    let authority = &rest[..rest.find('/').unwrap_or(rest.len())];
+    match authority.rfind('@') {
+        Some(at) => format!("{}://{}", scheme, &rest[at + 1..]),
+        None => url.to_string(),
+    }
+}
+
+// The MySQL pool for a db_settings URL with db_tls applied. The TLS files are only read when a
+// connection is made, so they are checked here: a missing or unusable one stops the startup
+// instead of failing lookups later
+pub(crate) fn mysql_pool(url: &str, config: &Config) -> Result<Pool, Box<dyn std::error::Error>> {
+    let opts = Opts::from_url(url)?;
+    let Some(tls) = config.db_tls.as_ref().filter(|tls| tls.enabled) else { return Ok(Pool::new(opts)) };
+
+    let mut ssl_opts = SslOpts::default().with_danger_skip_domain_validation(!tls.verify_hostname);
+    if let Some(path) = &tls.ca_cert {
+        let pem = fs::read(path).map_err(|e| format!("db_tls ca_cert {}: {}", path, e))?;
+        let certificates = CertificateDer::pem_slice_iter(&pem).filter(Result::is_ok).count();
+        // DER is a single certificate, which starts with a SEQUENCE tag
+        if certificates == 0 && pem.first() != Some(&0x30) {
+            return Err(format!("db_tls ca_cert {} holds no PEM or DER certificate", path).into());
+        }
+        ssl_opts = ssl_opts.with_root_cert_path(Some(std::path::PathBuf::from(path)));
+    }
+    if let Some(path) = &tls.client_pkcs12 {
+        let archive = fs::read(path).map_err(|e| format!("db_tls client_pkcs12 {}: {}", path, e))?;
+        if archive.starts_with(b"-----BEGIN") {
+            return Err(format!("db_tls client_pkcs12 {} is PEM; bundle the certificate and key with openssl pkcs12 -export", path).into());
+        }
+        let mut identity = ClientIdentity::new(std::path::PathBuf::from(path));
+        if let Some(password) = &tls.client_pkcs12_password {
+            identity = identity.with_password(password.clone());
+        }
+        ssl_opts = ssl_opts.with_client_identity(Some(identity));
+    }
+    info!("Connecting to MySQL over TLS{}", if tls.verify_hostname { "" } else { " without checking the host name" });
+    Ok(Pool::new(OptsBuilder::from_opts(opts).ssl_opts(ssl_opts)))
+}
+
+#[cfg(feature = "sqlite")]
+pub(crate) fn open_sqlite(path: &str, init_schema: bool) -> Result<Box<dyn SqlDatabase>, Box<dyn std::error::Error>> {
+    info!("Using SQLite database {}", path);
+    Ok(Box::new(SqliteDatabase::open(path, init_schema)?))
+}
+
+#[cfg(not(feature = "sqlite"))]
+pub(crate) fn open_sqlite(_path: &str, _init_schema: bool) -> Result<Box<dyn SqlDatabase>, Box<dyn std::error::Error>> {
+    Err("this build has no SQLite support; build it with the sqlite feature".into())
+}
+
+// Rewrite a statement's placeholders for a database and pick the values they take: `?` takes the
+// parameters in order and `:name` the one of that name, outside quotes and comments. MySQL gets
+// `?` back and PostgreSQL `$1`, `$2`..., with MySQL's backquoted names double-quoted
+pub(crate) fn bind_params<'a>(sql: &str, params: &SqlParams<'a>, postgres: bool) -> Result<(String, Vec<Option<&'a str>>), DbError> {
+    let mut bound = String::with_capacity(sql.len());
+    let mut values = Vec::new();
+    let mut chars = sql.chars().peekable();
+    let mut positional = params.iter();
+    let mut bind = |bound: &mut String, value: Option<&'a str>| {
+        values.push(value);
+        match postgres {
+            true => bound.push_str(&format!("${}", values.len())),
+            false => bound.push('?'),
+        }
+    };
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' | '`' => {
+                let quote = if c == '`' && postgres { '"' } else { c };
+                bound.push(quote);
+                while let Some(inner) = chars.next() {
+                    if inner == c {
+                        bound.push(quote);
+                        // A doubled quote stands for itself
+                        if chars.next_if_eq(&c).is_none() {
+                            break;
+                        }
+                        bound.push(quote);
+                    } else {
+                        bound.push(inner);
+                        if inner == '\\' && !postgres {
+                            bound.extend(chars.next());
+                        }
+                    }
+                }
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                bound.push(c);
+                bound.extend(chars.by_ref().take_while(|&c| c != '\n'));
+                bound.push('\n');
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                bound.push(c);
+                let mut previous = ' ';
+                for inner in chars.by_ref() {
+                    bound.push(inner);
+                    if previous == '*' && inner == '/' {
+                        break;
+                    }
+                    previous = inner;
+                }
+            }
+            '?' => {
+                let (_, value) = positional.next().ok_or_else(|| DbError::Query(format!("more ? placeholders than parameters in {}", sql)))?;
+                bind(&mut bound, *value);
+            }
+            // PostgreSQL casts, like ::text
+            ':' if chars.peek() == Some(&':') => {
+                bound.push_str("::");
+                chars.next();
+            }
+            ':' if chars.peek().is_some_and(|&c| c.is_ascii_alphabetic() || c == '_') => {
+                let mut name = String::new();
+                while let Some(c) = chars.next_if(|&c| c.is_ascii_alphanumeric() || c == '_') {
+                    name.push(c);
+                }
+                let (_, value) = params
+                    .iter()
+                    .find(|(param, _)| *param == name)
+                    .ok_or_else(|| DbError::Query(format!("no parameter :{} for {}", name, sql)))?;
+                bind(&mut bound, *value);
+            }
+            _ => bound.push(c),
+        }
+    }
+    Ok((bound, values))
+}
+
+// MySQL or MariaDB through mysql_async's pool
+pub(crate) struct MySqlDatabase {
+    pool: Pool,
+}
+
+pub(crate) async fn mysql_query<Q: Queryable>(conn: &mut Q, sql: &str, params: &SqlParams<'_>) -> Result<Vec<DbRow>, DbError> {
+    let (sql, values) = bind_params(sql, params, false)?;
+    let params = match values.is_empty() {
+        true => mysql_async::Params::Empty,
+        false => values.into(),
+    };
+    let rows: Vec<Row> = conn.exec(sql, params).await.map_err(|e| DbError::Query(e.to_string()))?;
+    let Some(first) = rows.first() else { return Ok(Vec::new()) };
+    let columns: Arc<[String]> = first.columns_ref().iter().map(|column| column.name_str().into_owned()).collect();
+    Ok(rows
+        .into_iter()
+        .map(|row| DbRow {
+            columns: columns.clone(),
+            values: row.unwrap().into_iter().map(mysql_text).collect(),
+        })
+        .collect())
+}
+
+pub(crate) fn mysql_text(value: mysql_async::Value) -> Option<String> {
+    use mysql_async::Value as V;
+    match value {
+        V::NULL => None,
+        V::Bytes(bytes) => Some(String::from_utf8_lossy(&bytes).into_owned()),
+        V::Int(n) => Some(n.to_string()),
+        V::UInt(n) => Some(n.to_string()),
+        V::Float(n) => Some(n.to_string()),
+        V::Double(n) => Some(n.to_string()),
+        value => Some(value.as_sql(true).trim_matches('\'').to_string()),
+    }
+}
+
+impl SqlDatabase for MySqlDatabase {
+    fn query<'a>(&'a self, sql: &'a str, params: &'a SqlParams<'a>) -> DbFuture<'a, Vec<DbRow>> {
+        Box::pin(async move {
+            let mut conn = self.pool.get_conn().await.map_err(|e| DbError::Connection(e.to_string()))?;
+            mysql_query(&mut conn, sql, params).await
+        })
+    }
+
+    fn begin(&self) -> DbFuture<'_, Box<dyn DbTransaction + '_>> {
+        Box::pin(async move {
+            let transaction = self.pool.start_transaction(TxOpts::default()).await.map_err(|e| DbError::Connection(e.to_string()))?;
+            Ok(Box::new(MySqlTransaction(Some(transaction))) as Box<dyn DbTransaction>)
+        })
+    }
+}
+
+// mysql_async rolls a transaction back when it is dropped
+pub(crate) struct MySqlTransaction(Option<mysql_async::Transaction<'static>>);
+
+impl DbTransaction for MySqlTransaction {
+    fn query<'a>(&'a mut self, sql: &'a str, params: &'a SqlParams<'a>) -> DbFuture<'a, Vec<DbRow>> {
+        Box::pin(async move {
+            let transaction = self.0.as_mut().ok_or_else(|| DbError::Query("transaction already committed".to_string()))?;
+            mysql_query(transaction, sql, params).await
+        })
+    }
+
+    fn commit(&mut self) -> DbFuture<'_, ()> {
+        Box::pin(async move {
+            let transaction = self.0.take().ok_or_else(|| DbError::Query("transaction already committed".to_string()))?;
+            transaction.commit().await.map_err(|e| DbError::Query(e.to_string()))
+        })
+    }
+}
+
+// Longest wait for a new PostgreSQL connection to be set up, logging in included
+pub(crate) const POSTGRES_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The tokio-postgres settings of a postgres:// URL, in libpq's URL syntax. The user defaults to
+// postgres and the database to the user
+pub(crate) fn postgres_config(url: &str) -> Result<tokio_postgres::Config, Box<dyn std::error::Error>> {
+    let mut config: tokio_postgres::Config = url.parse()?;
+    if config.get_ssl_mode() == tokio_postgres::config::SslMode::Require {
+        return Err("TLS connections to PostgreSQL are not supported".into());
+    }
+    if config.get_user().is_none() {
+        config.user("postgres");
+    }
+    if config.get_application_name().is_none() {
+        config.application_name("FusionDNS");
+    }
+    config.connect_timeout(POSTGRES_CONNECT_TIMEOUT);
+    Ok(config)
+}
+
+// A failure on the connection itself, rather than one the server reported for the statement,
+// makes the database count as unreachable
+pub(crate) fn postgres_error(e: tokio_postgres::Error) -> DbError {
+    match e.is_closed() || std::error::Error::source(&e).is_some_and(|source| source.is::<std::io::Error>()) {
+        true => DbError::Connection(postgres_message(&e)),
+        false => DbError::Query(postgres_message(&e)),
+    }
+}
+
+// tokio-postgres leaves the cause, such as the server's message, out of its errors' text
+pub(crate) fn postgres_message(e: &tokio_postgres::Error) -> String {
+    match std::error::Error::source(e) {
+        Some(source) => format!("{}: {}", e, source),
+        None => e.to_string(),
+    }
+}
+
+// A column as PostgreSQL's text output would show it. Results come back in binary, so only the
+// common types can be read; others have to be cast to text in the statement
+pub(crate) struct PostgresText(Option<String>);
+
+impl<'a> tokio_postgres::types::FromSql<'a> for PostgresText {
+    fn from_sql(ty: &tokio_postgres::types::Type, raw: &'a [u8]) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        use tokio_postgres::types::{FromSql, Type};
+        let text = match *ty {
+            Type::BOOL => if bool::from_sql(ty, raw)? { "t" } else { "f" }.to_string(),
+            Type::INT2 => i16::from_sql(ty, raw)?.to_string(),
+            Type::INT4 => i32::from_sql(ty, raw)?.to_string(),
+            Type::INT8 => i64::from_sql(ty, raw)?.to_string(),
+            Type::OID => u32::from_sql(ty, raw)?.to_string(),
+            Type::FLOAT4 => f32::from_sql(ty, raw)?.to_string(),
+            Type::FLOAT8 => f64::from_sql(ty, raw)?.to_string(),
+            Type::INET => std::net::IpAddr::from_sql(ty, raw)?.to_string(),
+            _ if <&str as FromSql>::accepts(ty) => <&str>::from_sql(ty, raw)?.to_string(),
+            _ => return Err(format!("can't read a {} column, cast it to text", ty).into()),
+        };
+        Ok(PostgresText(Some(text)))
+    }
+
+    fn from_sql_null(_ty: &tokio_postgres::types::Type) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        Ok(PostgresText(None))
+    }
+
+    fn accepts(_ty: &tokio_postgres::types::Type) -> bool {
+        true
+    }
+}
+
+// Run one statement with every parameter sent as text, in a single round trip
+pub(crate) async fn postgres_query(client: &tokio_postgres::Client, sql: &str, params: &SqlParams<'_>) -> Result<Vec<DbRow>, DbError> {
+    let (sql, values) = bind_params(sql, params, true)?;
+    let typed: Vec<_> = values.iter().map(|value| (value as &(dyn tokio_postgres::types::ToSql + Sync), tokio_postgres::types::Type::TEXT)).collect();
+    let rows = client.query_typed(&sql, &typed).await.map_err(postgres_error)?;
+    let Some(first) = rows.first() else { return Ok(Vec::new()) };
+    let columns: Arc<[String]> = first.columns().iter().map(|column| column.name().to_string()).collect();
+    rows.iter()
+        .map(|row| {
+            let values = (0..row.len())
+                .map(|index| row.try_get::<_, PostgresText>(index).map(|text| text.0))
+                .collect::<Result<_, _>>()
+                .map_err(|e| DbError::Query(postgres_message(&e)))?;
+            Ok(DbRow { columns: columns.clone(), values })
+        })
+        .collect()
+}
+
+// PostgreSQL as the data source. Connections are opened as needed and kept for reuse, up to
+// max_db_concurrency of them since lookups hold a database permit while they use one. A statement
+// runs once: if its connection has failed, the lookup fails as it would on a new one
+pub(crate) struct PostgresDatabase {
+    config: tokio_postgres::Config,
+    idle: std::sync::Mutex<Vec<tokio_postgres::Client>>,
+    max_idle: usize,
+}
+
+impl PostgresDatabase {
+    fn new(config: tokio_postgres::Config, max_idle: usize) -> Self {
+        PostgresDatabase { config, idle: std::sync::Mutex::new(Vec::new()), max_idle: max_idle.max(1) }
+    }
+
+    // An idle connection the server hasn't closed, or a new one
+    async fn connection(&self) -> Result<tokio_postgres::Client, DbError> {
+        loop {
+            let idle = self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+            match idle {
+                Some(client) if client.is_closed() => continue,
+                Some(client) => return Ok(client),
+                None => break,
+            }
+        }
+        let connect = self.config.connect(tokio_postgres::NoTls);
+        let (client, connection) = match tokio::time::timeout(POSTGRES_CONNECT_TIMEOUT, connect).await {
+            Ok(result) => result.map_err(|e| DbError::Connection(postgres_message(&e)))?,
+            Err(_) => return Err(DbError::Connection(format!("no connection to PostgreSQL within {:?}", POSTGRES_CONNECT_TIMEOUT))),
+        };
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("PostgreSQL connection closed: {}", postgres_message(&e));
+            }
+        });
+        Ok(client)
+    }
+
+    fn give_back(&self, client: tokio_postgres::Client) {
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        if idle.len() < self.max_idle && !client.is_closed() {
+            idle.push(client);
+        }
+    }
+}
+
+impl SqlDatabase for PostgresDatabase {
+    fn query<'a>(&'a self, sql: &'a str, params: &'a SqlParams<'a>) -> DbFuture<'a, Vec<DbRow>> {
+        Box::pin(async move {
+            let client = self.connection().await?;
+            let rows = postgres_query(&client, sql, params).await;
+            self.give_back(client);
+            rows
+        })
+    }
+
+    fn begin(&self) -> DbFuture<'_, Box<dyn DbTransaction + '_>> {
+        Box::pin(async move {
+            let client = self.connection().await?;
+            client.batch_execute("BEGIN").await.map_err(postgres_error)?;
+            Ok(Box::new(PostgresTransaction { source: self, client: Some(client) }) as Box<dyn DbTransaction>)
+        })
+    }
+}
+
+// Dropped before commit, its connection is closed, which rolls the transaction back
+pub(crate) struct PostgresTransaction<'a> {
+    source: &'a PostgresDatabase,
+    client: Option<tokio_postgres::Client>,
+}
+
+impl DbTransaction for PostgresTransaction<'_> {
+    fn query<'a>(&'a mut self, sql: &'a str, params: &'a SqlParams<'a>) -> DbFuture<'a, Vec<DbRow>> {
+        Box::pin(async move {
+            let client = self.client.as_ref().ok_or_else(|| DbError::Query("transaction is over".to_string()))?;
+            let rows = postgres_query(client, sql, params).await;
+            // A failed statement aborts the transaction
+            if rows.is_err() {
+                self.client = None;
+            }
+            rows
+        })
+    }
+
+    fn commit(&mut self) -> DbFuture<'_, ()> {
+        Box::pin(async move {
+            let client = self.client.take().ok_or_else(|| DbError::Query("transaction is over".to_string()))?;
+            client.batch_execute("COMMIT").await.map_err(postgres_error)?;
+            self.source.give_back(client);
+            Ok(())
+        })
+    }
+}
+
+// Table init_schema creates in a new SQLite database, matching the default SQL settings
+#[cfg(feature = "sqlite")]
+pub(crate) const SQLITE_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS dns_override (
+    id INTEGER PRIMARY KEY,
+    address TEXT NOT NULL,
+    type TEXT NOT NULL DEFAULT 'A',
+    value TEXT NOT NULL,
+    ttl INTEGER
+);
+CREATE INDEX IF NOT EXISTS dns_override_address ON dns_override (address);";
+
+// A local SQLite file as the data source, for setups without a database server. SQLite runs
+// one statement at a time here, each on a blocking thread, and a transaction keeps the
+// connection to itself until it ends
+#[cfg(feature = "sqlite")]
+pub(crate) struct SqliteDatabase {
+    connection: Arc<Mutex<rusqlite::Connection>>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteDatabase {
+    // Open the file, creating it if it doesn't exist
+    fn open(path: &str, init_schema: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        let connection = rusqlite::Connection::open(path).map_err(|e| format!("opening SQLite database {} failed: {}", path, e))?;
+        connection.busy_timeout(Duration::from_secs(5))?;
+        if init_schema {
+            connection.execute_batch(SQLITE_SCHEMA)?;
+            info!("Set up the dns_override table in {}", path);
+        }
+        Ok(SqliteDatabase { connection: Arc::new(Mutex::new(connection)) })
+    }
+}
+
+// The rows of one statement, every column as text
+#[cfg(feature = "sqlite")]
+pub(crate) fn sqlite_rows(connection: &rusqlite::Connection, sql: &str, values: &[Option<String>]) -> rusqlite::Result<Vec<DbRow>> {
+    use rusqlite::types::ValueRef;
+    let mut statement = connection.prepare(sql)?;
+    let columns: Arc<[String]> = statement.column_names().into_iter().map(str::to_string).collect();
+    let mut rows = statement.query(rusqlite::params_from_iter(values))?;
+    let mut read = Vec::new();
+    while let Some(row) = rows.next()? {
+        let values = (0..columns.len())
+            .map(|column| {
+                Ok(match row.get_ref(column)? {
+                    ValueRef::Null => None,
+                    ValueRef::Integer(n) => Some(n.to_string()),
+                    ValueRef::Real(n) => Some(n.to_string()),
+                    ValueRef::Text(text) | ValueRef::Blob(text) => Some(String::from_utf8_lossy(text).into_owned()),
+                })
+            })
+            .collect::<rusqlite::Result<_>>()?;
+        read.push(DbRow { columns: columns.clone(), values });
+    }
+    Ok(read)
+}
+
+// Run a statement on the locked connection on a blocking thread, handing the lock back after,
+// unless the thread panicked with it
+#[cfg(feature = "sqlite")]
+pub(crate) async fn sqlite_query(
+    connection: tokio::sync::OwnedMutexGuard<rusqlite::Connection>,
+    sql: &str,
+    params: &SqlParams<'_>,
+) -> (Option<tokio::sync::OwnedMutexGuard<rusqlite::Connection>>, Result<Vec<DbRow>, DbError>) {
+    let (sql, values) = match bind_params(sql, params, false) {
+        Ok(bound) => bound,
+        Err(e) => return (Some(connection), Err(e)),
+    };
+    let values: Vec<Option<String>> = values.into_iter().map(|value| value.map(str::to_string)).collect();
+    let run = tokio::task::spawn_blocking(move || {
+        let rows = sqlite_rows(&connection, &sql, &values).map_err(|e| DbError::Query(e.to_string()));
+        (Some(connection), rows)
+    });
+    run.await.unwrap_or_else(|e| (None, Err(DbError::Query(format!("SQLite query failed: {}", e)))))
+}
+
+#[cfg(feature = "sqlite")]
+impl SqlDatabase for SqliteDatabase {
+    fn query<'a>(&'a self, sql: &'a str, params: &'a SqlParams<'a>) -> DbFuture<'a, Vec<DbRow>> {
+        Box::pin(async move { sqlite_query(self.connection.clone().lock_owned().await, sql, params).await.1 })
+    }
+
+    fn begin(&self) -> DbFuture<'_, Box<dyn DbTransaction + '_>> {
+        Box::pin(async move {
+            let (connection, begun) = sqlite_query(self.connection.clone().lock_owned().await, "BEGIN", &[]).await;
+            begun?;
+            Ok(Box::new(SqliteTransaction { connection }) as Box<dyn DbTransaction>)
+        })
+    }
+}
+
+// Dropped before commit, it is rolled back on a blocking thread, which keeps the connection
+// locked until then
+#[cfg(feature = "sqlite")]
+pub(crate) struct SqliteTransaction {
+    connection: Option<tokio::sync::OwnedMutexGuard<rusqlite::Connection>>,
+}
+
+#[cfg(feature = "sqlite")]
+impl DbTransaction for SqliteTransaction {
+    fn query<'a>(&'a mut self, sql: &'a str, params: &'a SqlParams<'a>) -> DbFuture<'a, Vec<DbRow>> {
+        Box::pin(async move {
+            let connection = self.connection.take().ok_or_else(|| DbError::Query("transaction is over".to_string()))?;
+            let (connection, rows) = sqlite_query(connection, sql, params).await;
+            self.connection = connection;
+            rows
+        })
+    }
+
+    fn commit(&mut self) -> DbFuture<'_, ()> {
+        Box::pin(async move {
+            self.query("COMMIT", &[]).await?;
+            self.connection = None;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Drop for SqliteTransaction {
+    fn drop(&mut self) {
+        let Some(connection) = self.connection.take() else { return };
+        // Without a runtime the server is shutting down, and SQLite rolls back when it's closed
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else { return };
+        runtime.spawn_blocking(move || {
+            if let Err(e) = connection.execute_batch("ROLLBACK") {
+                warn!("Rolling back an SQLite transaction failed: {}", e);
+            }
+        });
+    }
+}
@@ -0,0 +1,1116 @@
+use super::*;
+
+// Most per-query upstream sockets open at once; beyond this the shared socket is used
+pub(crate) const MAX_UPSTREAM_SOCKETS: usize = 512;
+
+// Resolve a single query against the upstream DNS server on a fresh socket
+pub(crate) async fn resolve_upstream(query: Query, ctx: &LookupContext<'_>) -> Vec<Record> {
+    let qtype = query.query_type();
+    resolve_upstream_chain(query, ctx)
+        .await
+        .into_iter()
+        .filter(|record| record.record_type() == qtype)
+        .collect()
+}
+
+// Like resolve_upstream, but keeping the CNAME records that lead to the answer
+pub(crate) async fn resolve_upstream_chain(query: Query, ctx: &LookupContext<'_>) -> Vec<Record> {
+    let exchange = upstream_query(query.clone(), ctx, false);
+
+    match tokio::time::timeout(ctx.upstream_timeout, exchange).await {
+        Ok(Ok(response)) => response
+            .answers()
+            .iter()
+            .filter(|record| record.record_type() == query.query_type() || record.record_type() == RecordType::CNAME)
+            .cloned()
+            .collect(),
+        Ok(Err(e)) => {
+            warn!("Upstream lookup for {} failed: {}", query.name(), e);
+            Vec::new()
+        }
+        Err(_) => {
+            ctx.upstream_timed_out(&format!("lookup for {}", query.name()));
+            Vec::new()
+        }
+    }
+}
+
+// Ask the upstream server a question of our own; `dnssec_ok` requests signatures and sets CD so
+// the records come back unvalidated
+pub(crate) async fn upstream_query(query: Query, ctx: &LookupContext<'_>, dnssec_ok: bool) -> Result<Message, Box<dyn std::error::Error>> {
+    let mut request = Message::new();
+    request.set_id(rand::random());
+    request.set_message_type(MessageType::Query);
+    request.set_op_code(OpCode::Query);
+    request.set_recursion_desired(true);
+    request.add_query(query);
+    if dnssec_ok {
+        let mut edns = Edns::new();
+        edns.set_max_payload(EDNS_PAYLOAD_SIZE);
+        edns.set_dnssec_ok(true);
+        request.set_edns(edns);
+        request.set_checking_disabled(true);
+    }
+
+    upstream_exchange(&request, ctx).await
+}
+
+// Send a query of our own to the upstream servers
+pub(crate) async fn upstream_exchange(request: &Message, ctx: &LookupContext<'_>) -> Result<Message, Box<dyn std::error::Error>> {
+    match ask_upstreams(ctx, request, |upstream| upstream.exchange(request, ctx)).await {
+        Some((_, response)) => Ok(response),
+        None => Err("no upstream DNS server answered".into()),
+    }
+}
+
+// Run `exchange` for `request` against the upstream servers and return the first answer with
+// the server that gave it, or None when no server answered. A query for a name under one of the
+// forward_zones goes to that zone's server only; anything else follows upstream_strategy. With
+// the fastest strategy the slower exchanges are dropped, so their late answers are never relayed.
+pub(crate) async fn ask_upstreams<'c, T, F, Fut>(ctx: &'c LookupContext<'_>, request: &Message, exchange: F) -> Option<(&'c Upstream, T)>
+where
+    F: Fn(&'c Upstream) -> Fut,
+    Fut: Future<Output = Result<T, Box<dyn std::error::Error>>>,
+{
+    ctx.metrics.count(&ctx.metrics.upstream_forwards);
+    let routed = request.queries().first().and_then(|query| Some((query.name(), ctx.forward_zone(query.name())?)));
+    if let Some((name, (zone, upstream))) = routed {
+        info!("Routing {} to upstream DNS {} for forward zone {}", name, upstream.name, zone);
+        let started = Instant::now();
+        let failure = match exchange(upstream).await {
+            Ok(answer) => {
+                upstream.succeeded(started.elapsed());
+                return Some((upstream, answer));
+            }
+            Err(e) => e.to_string(),
+        };
+        upstream.failed(&failure);
+        return None;
+    }
+
+    match ctx.upstream_strategy {
+        UpstreamStrategy::Failover => {
+            for (index, upstream) in ctx.upstreams_in_order() {
+                let started = Instant::now();
+                // Only the message is kept, so the future stays Send across the next await
+                let failure = match exchange(upstream).await {
+                    Ok(answer) => {
+                        ctx.upstream_succeeded(index, started.elapsed());
+                        return Some((upstream, answer));
+                    }
+                    Err(e) => e.to_string(),
+                };
+                ctx.upstream_failed(index, &failure);
+            }
+        }
+        UpstreamStrategy::Fastest => {
+            let started = Instant::now();
+            let mut racing: FuturesUnordered<_> = ctx
+                .upstreams_in_order()
+                .take(ctx.upstream_race_count)
+                .map(|(index, upstream)| {
+                    let exchange = exchange(upstream);
+                    async move { (index, exchange.await.map_err(|e| e.to_string())) }
+                })
+                .collect();
+            while let Some((index, result)) = racing.next().await {
+                match result {
+                    Ok(answer) => {
+                        ctx.upstream_succeeded(index, started.elapsed());
+                        return Some((&ctx.upstreams[index], answer));
+                    }
+                    Err(failure) => ctx.upstream_failed(index, &failure),
+                }
+            }
+        }
+    }
+    None
+}
+
+// A configured upstream server, with counters for the statistics log
+pub(crate) struct Upstream {
+    // As configured, for logs
+    pub(crate) name: String,
+    addr: SocketAddr,
+    encrypted: Option<EncryptedUpstream>,
+    // Connections for retrying plain DNS queries over TCP
+    tcp: StreamPool,
+    successes: AtomicU64,
+    failures: AtomicU64,
+    // Total time spent waiting for successful answers
+    latency_ms: AtomicU64,
+}
+
+impl Upstream {
+    // `upstream_dns` is "ip:port" for plain DNS, "tls://ip:port#hostname" for DNS-over-TLS or
+    // "quic://ip:port#hostname" for DNS-over-QUIC with the certificate checked against the
+    // hostname, or an https:// URL for DNS-over-HTTPS
+    pub(crate) fn parse(upstream_dns: &str, config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let (addr, encrypted) = if let Some(upstream) = upstream_dns.strip_prefix("tls://") {
+            let (addr, server_name) = upstream
+                .split_once('#')
+                .ok_or("tls:// upstream_dns needs a #hostname to verify the certificate against")?;
+            let addr: SocketAddr = addr.parse()?;
+            let tls = StreamPool::tls(addr, server_name, config.upstream_tls_insecure)?;
+            (addr, Some(EncryptedUpstream::Tls(Box::new(tls))))
+        } else if let Some(upstream) = upstream_dns.strip_prefix("quic://") {
+            let (addr, server_name) = upstream
+                .split_once('#')
+                .ok_or("quic:// upstream_dns needs a #hostname to verify the certificate against")?;
+            let addr: SocketAddr = addr.parse()?;
+            let quic = QuicUpstream::new(addr, server_name, config.upstream_tls_insecure)?;
+            (addr, Some(EncryptedUpstream::Quic(quic)))
+        } else if upstream_dns.starts_with("https://") {
+            let https = HttpsUpstream::new(upstream_dns, config.upstream_bootstrap.as_deref(), config.upstream_tls_insecure)?;
+            (https.addr, Some(EncryptedUpstream::Https(https)))
+        } else {
+            (upstream_dns.parse()?, None)
+        };
+
+        Ok(Upstream {
+            name: upstream_dns.to_string(),
+            addr,
+            encrypted,
+            tcp: StreamPool::tcp(addr),
+            successes: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            latency_ms: AtomicU64::new(0),
+        })
+    }
+
+    // Send a query to this server: over its encrypted transport if it has one, otherwise over UDP
+    // from a fresh socket, repeated over TCP when the answer is truncated (unless
+    // upstream_tcp_retry is off) or, with 0x20 enabled, doesn't echo the mixed-case query name
+    async fn exchange(&self, request: &Message, ctx: &LookupContext<'_>) -> Result<Message, Box<dyn std::error::Error>> {
+        if let Some(encrypted) = &self.encrypted {
+            let exchange = tokio::time::timeout(ctx.upstream_timeout, encrypted.exchange(&request.to_vec()?, ctx.upstream_timeout)).await;
+            return Ok(Message::from_vec(&exchange.map_err(|_| "timed out")??)?);
+        }
+        let mut upstream_request = request.clone();
+        if ctx.upstream_0x20 {
+            randomize_case(&mut upstream_request);
+        }
+        let request_bytes = upstream_request.to_vec()?;
+        let mut response = Message::from_vec(&exchange_udp(&request_bytes, self.addr, ctx.upstream_timeout).await?)?;
+        let spoofable = ctx.upstream_0x20 && !echoes_case(&upstream_request, &response);
+        if spoofable {
+            warn!("Upstream answer did not echo the query name case, retrying over TCP");
+        }
+        if (response.truncated() && ctx.upstream_tcp_retry) || spoofable {
+            response = Message::from_vec(&self.tcp.exchange(&request_bytes, ctx.upstream_timeout).await?)?;
+        }
+        if ctx.upstream_0x20 {
+            restore_case(&mut response, request.queries());
+        }
+        Ok(response)
+    }
+
+    pub(crate) fn succeeded(&self, elapsed: Duration) {
+        self.successes.fetch_add(1, Ordering::Relaxed);
+        self.latency_ms.fetch_add(elapsed.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn failed(&self, reason: &str) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        warn!("Upstream DNS {} failed: {}", self.name, reason);
+    }
+
+    fn log_stats(&self) {
+        let successes = self.successes.load(Ordering::Relaxed);
+        let failures = self.failures.load(Ordering::Relaxed);
+        let average_ms = self.latency_ms.load(Ordering::Relaxed).checked_div(successes).unwrap_or(0);
+        info!("Upstream DNS {}: {} answered, {} failed, {} ms on average", self.name, successes, failures, average_ms);
+    }
+}
+
+// Every upstream_probe_interval seconds, log each upstream server's counters and the database
+// permits in use, and ask the servers
+// ahead of the current one whether they answer again, switching back to the first one that does
+pub(crate) async fn probe_upstreams(config: &Config, ctx: &LookupContext<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.upstream_probe_interval.max(1)));
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        for upstream in ctx.upstreams.iter().chain(ctx.forward_zones.iter().map(|(_, upstream)| upstream)) {
+            upstream.log_stats();
+        }
+        info!(
+            "Database: {} of {} permits in use, {} lookups gave up waiting, {} names prefetched",
+            ctx.db_in_use(),
+            ctx.max_db_concurrency,
+            ctx.metrics.db_busy.load(Ordering::Relaxed),
+            ctx.metrics.prefetches.load(Ordering::Relaxed)
+        );
+
+        let current = ctx.current_upstream.load(Ordering::Relaxed);
+        for (index, upstream) in ctx.upstreams.iter().enumerate().take(current) {
+            let mut probe = Message::new();
+            probe.set_id(rand::random());
+            probe.set_message_type(MessageType::Query);
+            probe.set_op_code(OpCode::Query);
+            probe.set_recursion_desired(true);
+            probe.add_query(Query::query(Name::root(), RecordType::NS));
+            if upstream.exchange(&probe, ctx).await.is_ok() {
+                info!("Upstream DNS {} answers again, switching back to it", upstream.name);
+                ctx.current_upstream.store(index, Ordering::Relaxed);
+                break;
+            }
+        }
+    }
+}
+
+// 0x20: randomize the case of the letters in the query names, so a spoofed answer has to guess
+// it on top of the message ID and source port
+pub(crate) fn randomize_case(request: &mut Message) {
+    let mut rng = rand::thread_rng();
+    let queries: Vec<Query> = request
+        .take_queries()
+        .into_iter()
+        .map(|mut query| {
+            let labels = query.name().iter().map(|label| {
+                label
+                    .iter()
+                    .map(|byte| if rng.gen() { byte.to_ascii_uppercase() } else { byte.to_ascii_lowercase() })
+                    .collect::<Vec<u8>>()
+            });
+            if let Ok(mut name) = Name::from_labels(labels) {
+                name.set_fqdn(query.name().is_fqdn());
+                query.set_name(name);
+            }
+            query
+        })
+        .collect();
+    request.add_queries(queries);
+}
+
+// Whether the response repeats the query names byte for byte; Name equality ignores case
+pub(crate) fn echoes_case(request: &Message, response: &Message) -> bool {
+    request.queries().len() == response.queries().len()
+        && request
+            .queries()
+            .iter()
+            .zip(response.queries())
+            .all(|(asked, echoed)| asked.name().iter().eq(echoed.name().iter()))
+}
+
+// Put the client's spelling of the query names back into an upstream answer
+pub(crate) fn restore_case(response: &mut Message, queries: &[Query]) {
+    let restore = |mut record: Record| {
+        if let Some(query) = queries.iter().find(|query| query.name() == record.name()) {
+            record.set_name(query.name().clone());
+        }
+        record
+    };
+    let answers: Vec<Record> = response.take_answers().into_iter().map(restore).collect();
+    response.add_answers(answers);
+    let name_servers: Vec<Record> = response.take_name_servers().into_iter().map(restore).collect();
+    response.add_name_servers(name_servers);
+    let additionals: Vec<Record> = response.take_additionals().into_iter().map(restore).collect();
+    response.add_additionals(additionals);
+    response.take_queries();
+    response.add_queries(queries.iter().cloned());
+}
+
+// A socket bound for a single upstream query, counted against MAX_UPSTREAM_SOCKETS while open
+pub(crate) struct UpstreamSocket<'a> {
+    socket: UdpSocket,
+    open: &'a AtomicUsize,
+}
+
+impl Drop for UpstreamSocket<'_> {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Bind a socket on a fresh ephemeral port for one upstream query, so the source port can't be
+// learned from earlier queries. None at the cap or when binding fails (e.g. out of file
+// descriptors), and the caller falls back to the shared socket
+pub(crate) async fn bind_upstream_socket<'a>(ctx: &'a LookupContext<'_>, upstream_addr: SocketAddr) -> Option<UpstreamSocket<'a>> {
+    if ctx.upstream_sockets.fetch_add(1, Ordering::Relaxed) >= MAX_UPSTREAM_SOCKETS {
+        ctx.upstream_sockets.fetch_sub(1, Ordering::Relaxed);
+        return None;
+    }
+    let bind_addr = if upstream_addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    match UdpSocket::bind(bind_addr).await {
+        Ok(socket) => Some(UpstreamSocket { socket, open: &ctx.upstream_sockets }),
+        Err(e) => {
+            ctx.upstream_sockets.fetch_sub(1, Ordering::Relaxed);
+            warn!("Could not bind an upstream socket, using the shared one: {}", e);
+            None
+        }
+    }
+}
+
+// A query forwarded on an upstream socket that is waiting for its answer
+pub(crate) struct PendingQuery {
+    client_id: u16,
+    queries: Vec<Query>,
+}
+
+// Forgets an outstanding query however its exchange ends, including when a faster upstream wins
+// the race and the exchange is dropped
+pub(crate) struct OutstandingQuery<'a> {
+    outstanding: &'a std::sync::Mutex<HashMap<u16, PendingQuery>>,
+    id: u16,
+}
+
+impl Drop for OutstandingQuery<'_> {
+    fn drop(&mut self) {
+        self.outstanding.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.id);
+    }
+}
+
+// Pick an upstream message ID no other outstanding query uses and remember the client's query under it
+pub(crate) fn register_upstream_query(ctx: &LookupContext<'_>, message: &Message) -> u16 {
+    let mut outstanding = ctx.outstanding.lock().unwrap_or_else(|e| e.into_inner());
+    let id = loop {
+        let id: u16 = rand::random();
+        if !outstanding.contains_key(&id) {
+            break id;
+        }
+    };
+    outstanding.insert(id, PendingQuery { client_id: message.id(), queries: message.queries().to_vec() });
+    id
+}
+
+// Read an upstream socket until the answer for `id` from `upstream_addr` arrives. Datagrams from other
+// addresses, answers to IDs nobody waits for (late or duplicate ones) and answers to a different
+// question are logged and dropped
+pub(crate) async fn receive_upstream(
+    socket: &UdpSocket,
+    ctx: &LookupContext<'_>,
+    upstream_addr: SocketAddr,
+    id: u16,
+) -> Result<(PendingQuery, Vec<u8>), Box<dyn std::error::Error>> {
+    // Every datagram that isn't the answer may be a spoofing attempt, so they are counted
+    let drop_datagram = |reason: String| {
+        let dropped = ctx.metrics.spoof_dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!("Dropping {} ({} upstream datagrams dropped so far)", reason, dropped);
+    };
+
+    let mut buf = [0u8; 4096];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        if from != upstream_addr {
+            drop_datagram(format!("datagram from {} on the upstream socket", from));
+            continue;
+        }
+        let response = match Message::from_vec(&buf[..len]) {
+            Ok(response) => response,
+            Err(e) => {
+                drop_datagram(format!("malformed upstream answer: {}", e));
+                continue;
+            }
+        };
+        if response.id() != id {
+            drop_datagram(format!("unexpected or duplicate upstream answer with ID {}", response.id()));
+            continue;
+        }
+        let mut outstanding = ctx.outstanding.lock().unwrap_or_else(|e| e.into_inner());
+        match outstanding.get(&id) {
+            Some(pending) if pending.queries == response.queries() => {
+                let pending = outstanding.remove(&id).ok_or("outstanding query vanished")?;
+                return Ok((pending, buf[..len].to_vec()));
+            }
+            Some(_) => drop_datagram(format!("upstream answer with ID {} for a different question", id)),
+            None => drop_datagram(format!("duplicate upstream answer with ID {}", id)),
+        }
+    }
+}
+
+// One DNS exchange over UDP from a fresh socket, accepting only a reply from `upstream_addr`
+// with the query's ID and question, as receive_upstream does for forwarded queries
+pub(crate) async fn exchange_udp(request: &[u8], upstream_addr: SocketAddr, timeout: Duration) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let queries = Message::from_vec(request)?.take_queries();
+    let bind_addr = if upstream_addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.send_to(request, upstream_addr).await?;
+
+    let exchange = async {
+        let mut buf = [0u8; 4096];
+        loop {
+            let (len, src) = socket.recv_from(&mut buf).await?;
+            if src != upstream_addr || buf.get(..2) != request.get(..2) {
+                continue;
+            }
+            match Message::from_vec(&buf[..len]) {
+                Ok(response) if response.queries() == queries => return Ok::<_, std::io::Error>(buf[..len].to_vec()),
+                Ok(_) => warn!("Dropping an answer from {} for a different question", upstream_addr),
+                Err(e) => warn!("Dropping a malformed answer from {}: {}", upstream_addr, e),
+            }
+        }
+    };
+    Ok(tokio::time::timeout(timeout, exchange).await??)
+}
+
+// Upstream servers reached over an encrypted transport instead of plain UDP
+pub(crate) enum EncryptedUpstream {
+    Tls(Box<StreamPool>),
+    Https(HttpsUpstream),
+    Quic(QuicUpstream),
+}
+
+impl EncryptedUpstream {
+    // `timeout` is upstream_timeout_ms, for connecting as well as for the answer
+    async fn exchange(&self, request: &[u8], timeout: Duration) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        match self {
+            EncryptedUpstream::Tls(tls) => tls.exchange(request, timeout).await,
+            EncryptedUpstream::Https(https) => https.exchange(request, timeout).await,
+            EncryptedUpstream::Quic(quic) => quic.exchange(request, timeout).await,
+        }
+    }
+}
+
+// DNS-over-HTTPS (RFC 8484) upstream: queries are POSTed as application/dns-message, and the
+// client keeps HTTP/2 connections open between queries
+pub(crate) struct HttpsUpstream {
+    url: reqwest::Url,
+    addr: SocketAddr,
+    client: reqwest::Client,
+}
+
+impl HttpsUpstream {
+    // A host name in the URL is resolved through `bootstrap` (a plain IP) rather than DNS,
+    // which would otherwise have to go through this very upstream
+    fn new(url: &str, bootstrap: Option<&str>, insecure: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        let url = reqwest::Url::parse(url)?;
+        let host = url.host_str().ok_or("https:// upstream_dns needs a host")?;
+        let port = url.port_or_known_default().unwrap_or(443);
+        let mut builder = reqwest::Client::builder().use_rustls_tls().danger_accept_invalid_certs(insecure);
+
+        let ip = match host.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => {
+                let ip: IpAddr = bootstrap
+                    .ok_or("https:// upstream_dns with a host name needs upstream_bootstrap")?
+                    .parse()?;
+                builder = builder.resolve(host, SocketAddr::new(ip, port));
+                ip
+            }
+        };
+        if insecure {
+            warn!("Certificate verification for DNS-over-HTTPS upstream {} is disabled", url);
+        }
+
+        Ok(HttpsUpstream {
+            addr: SocketAddr::new(ip, port),
+            client: builder.build()?,
+            url,
+        })
+    }
+
+    async fn exchange(&self, request: &[u8], timeout: Duration) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let response = self
+            .client
+            .post(self.url.clone())
+            .timeout(timeout)
+            .header(reqwest::header::CONTENT_TYPE, "application/dns-message")
+            .header(reqwest::header::ACCEPT, "application/dns-message")
+            .body(request.to_vec())
+            .send()
+            .await?;
+        if response.status() != reqwest::StatusCode::OK {
+            return Err(format!("DNS-over-HTTPS upstream answered HTTP {}", response.status()).into());
+        }
+        Ok(response.bytes().await?.to_vec())
+    }
+}
+
+// Most connections kept open to one upstream server over TCP or DNS-over-TLS
+pub(crate) const MAX_STREAM_CONNECTIONS: usize = 4;
+
+// Queries waiting on one connection before another one is opened
+pub(crate) const MAX_STREAM_OUTSTANDING: usize = 64;
+
+// Queries sent on a connection before it is retired for a fresh one
+pub(crate) const MAX_STREAM_QUERIES: usize = 1000;
+
+// A connection with nothing to read for this long and no query waiting is closed
+pub(crate) const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub(crate) trait DnsStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> DnsStream for S {}
+
+// Connections to one upstream server over TCP or DNS-over-TLS, kept open between queries and
+// shared by them (RFC 7766 pipelining). Each query goes out under an ID unique on its
+// connection, and a reader task per connection hands the answers back by that ID.
+pub(crate) struct StreamPool {
+    addr: SocketAddr,
+    // Set for DNS-over-TLS
+    tls: Option<(TlsConnector, ServerName<'static>)>,
+    connections: std::sync::Mutex<Vec<Arc<StreamConnection>>>,
+    health: std::sync::Mutex<StreamHealth>,
+}
+
+// Failed connection attempts in a row, and when the next one may be made
+#[derive(Default)]
+pub(crate) struct StreamHealth {
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl StreamPool {
+    fn tcp(addr: SocketAddr) -> Self {
+        StreamPool {
+            addr,
+            tls: None,
+            connections: std::sync::Mutex::new(Vec::new()),
+            health: std::sync::Mutex::new(StreamHealth::default()),
+        }
+    }
+
+    fn tls(addr: SocketAddr, server_name: &str, insecure: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        if insecure {
+            warn!("Certificate verification for DNS-over-TLS upstream {} is disabled", addr);
+        }
+        let tls_config = client_tls_config(insecure)?;
+        let connector = TlsConnector::from(Arc::new(tls_config));
+        Ok(StreamPool { tls: Some((connector, ServerName::try_from(server_name.to_string())?)), ..StreamPool::tcp(addr) })
+    }
+
+    fn kind(&self) -> &'static str {
+        if self.tls.is_some() {
+            "DNS-over-TLS"
+        } else {
+            "TCP"
+        }
+    }
+
+    async fn connect(&self, timeout: Duration) -> Result<Box<dyn DnsStream>, Box<dyn std::error::Error>> {
+        let connect = async {
+            let tcp = TcpStream::connect(self.addr).await?;
+            let stream: Box<dyn DnsStream> = match &self.tls {
+                Some((connector, server_name)) => Box::new(connector.connect(server_name.clone(), tcp).await?),
+                None => Box::new(tcp),
+            };
+            Ok::<_, std::io::Error>(stream)
+        };
+        Ok(tokio::time::timeout(timeout, connect).await??)
+    }
+
+    // The least busy open connection, or a new one while there are fewer than
+    // MAX_STREAM_CONNECTIONS and all are busy. True when the connection was already open.
+    async fn connection(&self, timeout: Duration) -> Result<(Arc<StreamConnection>, bool), Box<dyn std::error::Error>> {
+        {
+            let mut connections = self.connections.lock().unwrap();
+            connections.retain(|connection| connection.usable());
+            let least_busy = connections.iter().min_by_key(|connection| connection.outstanding());
+            if let Some(connection) = least_busy {
+                if connection.outstanding() < MAX_STREAM_OUTSTANDING || connections.len() >= MAX_STREAM_CONNECTIONS {
+                    return Ok((connection.clone(), true));
+                }
+            }
+        }
+
+        if self.health.lock().unwrap().retry_at.is_some_and(|retry_at| Instant::now() < retry_at) {
+            return Err(format!("{} upstream {} is backing off after failures", self.kind(), self.addr).into());
+        }
+        match self.connect(timeout).await {
+            Ok(stream) => {
+                info!("Connected to {} upstream {}", self.kind(), self.addr);
+                *self.health.lock().unwrap() = StreamHealth::default();
+                let connection = StreamConnection::start(stream, self.kind());
+                self.connections.lock().unwrap().push(connection.clone());
+                Ok((connection, false))
+            }
+            Err(e) => {
+                let mut health = self.health.lock().unwrap();
+                health.failures += 1;
+                let delay = Duration::from_millis(250 << health.failures.min(7)).min(Duration::from_secs(30));
+                health.retry_at = Some(Instant::now() + delay);
+                Err(e)
+            }
+        }
+    }
+
+    // Send one query and return the answer under the query's ID
+    async fn exchange(&self, request: &[u8], timeout: Duration) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        if request.len() < 2 {
+            return Err("DNS message too short".into());
+        }
+        // A reused connection may have been closed by the server, so the query gets one retry on a fresh one
+        for _ in 0..2 {
+            let (connection, reused) = self.connection(timeout).await?;
+            match connection.exchange(request, timeout).await {
+                Ok(response) => return Ok(response),
+                Err(e) if reused && e.kind() != std::io::ErrorKind::TimedOut => {
+                    debug!("{} upstream {} connection failed, retrying on another: {}", self.kind(), self.addr, e);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(format!("{} upstream {} closed the connection", self.kind(), self.addr).into())
+    }
+}
+
+// One open connection of a StreamPool
+pub(crate) struct StreamConnection {
+    writer: Mutex<tokio::io::WriteHalf<Box<dyn DnsStream>>>,
+    // Queries waiting for their answer, by the ID they went out under
+    pending: std::sync::Mutex<HashMap<u16, oneshot::Sender<Vec<u8>>>>,
+    sent: AtomicUsize,
+    closed: AtomicBool,
+}
+
+impl StreamConnection {
+    fn start(stream: Box<dyn DnsStream>, kind: &'static str) -> Arc<Self> {
+        let (reader, writer) = tokio::io::split(stream);
+        let connection = Arc::new(StreamConnection {
+            writer: Mutex::new(writer),
+            pending: std::sync::Mutex::new(HashMap::new()),
+            sent: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+        });
+        tokio::spawn(connection.clone().read_answers(reader, kind));
+        connection
+    }
+
+    fn usable(&self) -> bool {
+        !self.closed.load(Ordering::Relaxed) && self.sent.load(Ordering::Relaxed) < MAX_STREAM_QUERIES
+    }
+
+    fn outstanding(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    // Hand each answer to the query waiting for its ID until the connection closes or idles out
+    async fn read_answers(self: Arc<Self>, mut reader: tokio::io::ReadHalf<Box<dyn DnsStream>>, kind: &'static str) {
+        loop {
+            // Only the wait for the first byte times out, as a read cut off halfway would lose the framing
+            let mut len_buf = [0u8; 2];
+            match tokio::time::timeout(STREAM_IDLE_TIMEOUT, reader.read(&mut len_buf[..1])).await {
+                Ok(Ok(1)) => {}
+                Ok(_) => break,
+                Err(_) if self.outstanding() == 0 => break,
+                Err(_) => continue,
+            }
+            let read = async {
+                reader.read_exact(&mut len_buf[1..]).await?;
+                let mut response = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+                reader.read_exact(&mut response).await?;
+                Ok::<_, std::io::Error>(response)
+            };
+            let Ok(response) = read.await else { break };
+            let Some(id) = response.get(..2).map(|id| u16::from_be_bytes([id[0], id[1]])) else { continue };
+            match self.pending.lock().unwrap().remove(&id) {
+                Some(waiting) => {
+                    let _ = waiting.send(response);
+                }
+                None => debug!("Dropping {} upstream answer with unexpected ID {}", kind, id),
+            }
+        }
+        // Queries still waiting fail and are retried on another connection
+        self.closed.store(true, Ordering::Relaxed);
+        self.pending.lock().unwrap().clear();
+    }
+
+    async fn exchange(&self, request: &[u8], timeout: Duration) -> std::io::Result<Vec<u8>> {
+        let (id, answer) = {
+            let mut pending = self.pending.lock().unwrap();
+            let id = loop {
+                let id: u16 = rand::random();
+                if !pending.contains_key(&id) {
+                    break id;
+                }
+            };
+            let (waiting, answer) = oneshot::channel();
+            pending.insert(id, waiting);
+            (id, answer)
+        };
+        self.sent.fetch_add(1, Ordering::Relaxed);
+
+        let mut framed = Vec::with_capacity(request.len() + 2);
+        framed.extend((request.len() as u16).to_be_bytes());
+        framed.extend(id.to_be_bytes());
+        framed.extend(&request[2..]);
+        let started = Instant::now();
+        let exchange = async {
+            // A write cut off halfway would break the framing for every other query
+            let write = async { self.writer.lock().await.write_all(&framed).await };
+            match tokio::time::timeout(timeout, write).await {
+                Ok(written) => written?,
+                Err(_) => {
+                    self.closed.store(true, Ordering::Relaxed);
+                    return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out"));
+                }
+            }
+            let remaining = timeout.saturating_sub(started.elapsed());
+            match tokio::time::timeout(remaining, answer).await {
+                Ok(Ok(response)) => Ok(response),
+                Ok(Err(_)) => Err(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "connection closed")),
+                Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out")),
+            }
+        };
+        let answered = exchange.await;
+        self.pending.lock().unwrap().remove(&id);
+        let mut response = answered.inspect_err(|e| {
+            if e.kind() != std::io::ErrorKind::TimedOut {
+                self.closed.store(true, Ordering::Relaxed);
+            }
+        })?;
+        response[..2].copy_from_slice(&request[..2]);
+        Ok(response)
+    }
+}
+
+// TLS client settings for encrypted upstreams; `insecure` skips certificate verification
+// (signatures are still checked)
+pub(crate) fn client_tls_config(insecure: bool) -> Result<ClientConfig, Box<dyn std::error::Error>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone()).with_safe_default_protocol_versions()?;
+    if insecure {
+        return Ok(builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoCertificateVerification(provider)))
+            .with_no_client_auth());
+    }
+    let roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+    Ok(builder.with_root_certificates(roots).with_no_client_auth())
+}
+
+// DNS-over-QUIC (RFC 9250) upstream: each query gets its own bidirectional stream on a shared
+// connection. When the handshake fails, queries fall back to plain UDP on port 53 of the same server
+pub(crate) struct QuicUpstream {
+    addr: SocketAddr,
+    server_name: String,
+    endpoint: quinn::Endpoint,
+    connection: Mutex<Option<quinn::Connection>>,
+}
+
+impl QuicUpstream {
+    fn new(addr: SocketAddr, server_name: &str, insecure: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        if insecure {
+            warn!("Certificate verification for DNS-over-QUIC upstream {} is disabled", addr);
+        }
+        let mut tls_config = client_tls_config(insecure)?;
+        tls_config.alpn_protocols = vec![b"doq".to_vec()];
+        tls_config.enable_early_data = true;
+        let quic_config = quinn::crypto::rustls::QuicClientConfig::try_from(tls_config)?;
+
+        let bind_addr: SocketAddr = if addr.is_ipv4() { "0.0.0.0:0".parse()? } else { "[::]:0".parse()? };
+        let mut endpoint = quinn::Endpoint::client(bind_addr)?;
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(quic_config)));
+
+        Ok(QuicUpstream {
+            addr,
+            server_name: server_name.to_string(),
+            endpoint,
+            connection: Mutex::new(None),
+        })
+    }
+
+    // The open connection, or a new one; resumed sessions send their query as 0-RTT data
+    async fn connection(&self, timeout: Duration) -> Result<quinn::Connection, Box<dyn std::error::Error>> {
+        let mut connection = self.connection.lock().await;
+        if let Some(open) = connection.as_ref().filter(|open| open.close_reason().is_none()) {
+            return Ok(open.clone());
+        }
+
+        let connecting = self.endpoint.connect(self.addr, &self.server_name)?;
+        let open = match connecting.into_0rtt() {
+            Ok((open, _)) => open,
+            Err(connecting) => tokio::time::timeout(timeout, connecting).await??,
+        };
+        info!("Connected to DNS-over-QUIC upstream {}", self.addr);
+        *connection = Some(open.clone());
+        Ok(open)
+    }
+
+    async fn exchange(&self, request: &[u8], timeout: Duration) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let fallback = SocketAddr::new(self.addr.ip(), 53);
+        let connection = match self.connection(timeout).await {
+            Ok(connection) => Some(connection),
+            Err(e) => {
+                warn!("DNS-over-QUIC handshake with {} failed, falling back to UDP {}: {}", self.addr, fallback, e);
+                None
+            }
+        };
+        let Some(connection) = connection else {
+            return exchange_udp(request, fallback, timeout).await;
+        };
+
+        // RFC 9250 requires message ID 0 on the wire; the stream already identifies the query
+        let mut query = request.to_vec();
+        let id = [query[0], query[1]];
+        query[..2].copy_from_slice(&[0, 0]);
+
+        let exchange = async {
+            let (mut send, mut recv) = connection.open_bi().await?;
+            send.write_all(&(query.len() as u16).to_be_bytes()).await?;
+            send.write_all(&query).await?;
+            send.finish()?;
+            let framed = recv.read_to_end(u16::MAX as usize + 2).await?;
+            Ok::<_, Box<dyn std::error::Error>>(framed)
+        };
+        let framed = tokio::time::timeout(timeout, exchange).await??;
+
+        let mut response = framed.get(2..).filter(|r| r.len() >= 2).ok_or("short DNS-over-QUIC response")?.to_vec();
+        response[..2].copy_from_slice(&id);
+        Ok(response)
+    }
+}
+
+// Certificate verifier for `upstream_tls_insecure`: accepts any certificate
+#[derive(Debug)]
+pub(crate) struct NoCertificateVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// Answers from upstream servers, kept in memory until their TTL runs out, by the query sent
+// upstream and the client's payload size. Unlike the database cache they are not saved to a file
+pub(crate) struct UpstreamCache {
+    answers: std::sync::Mutex<HashMap<(Vec<u8>, u16), CachedAnswer>>,
+    max_entries: usize,
+    min_ttl: u32,
+    max_ttl: u32,
+    // Lowest TTL answered, however little is left
+    min_answer_ttl: u32,
+}
+
+pub(crate) struct CachedAnswer {
+    response: Message,
+    stored: Instant,
+    ttl: u32,
+    hits: AtomicU64,
+}
+
+impl UpstreamCache {
+    pub(crate) fn new(config: &Config) -> Self {
+        UpstreamCache {
+            answers: std::sync::Mutex::new(HashMap::new()),
+            max_entries: config.upstream_cache_size,
+            min_ttl: config.upstream_cache_min_ttl,
+            max_ttl: config.upstream_cache_max_ttl.max(config.upstream_cache_min_ttl),
+            min_answer_ttl: config.min_answer_ttl,
+        }
+    }
+
+    // Cache key of a forwarded query with a single question: the request as sent upstream with ID
+    // 0 and the name in lowercase, so clients that spell a name differently share the answer
+    pub(crate) fn key(&self, upstream_request: &Message, payload_size: u16) -> Option<(Vec<u8>, u16)> {
+        if self.max_entries == 0 || upstream_request.queries().len() != 1 {
+            return None;
+        }
+        let mut request = upstream_request.clone();
+        request.set_id(0);
+        let queries = request.take_queries();
+        request.add_queries(queries.into_iter().map(|mut query| {
+            query.set_name(query.name().to_lowercase());
+            query
+        }));
+        Some((request.to_vec().ok()?, payload_size))
+    }
+
+    // How long an answer may be kept: the smallest TTL among its records when it has data, and for
+    // NXDOMAIN and NODATA the SOA's TTL capped at its minimum (RFC 2308). Failures, truncated
+    // answers and negative answers without a SOA aren't cached
+    fn ttl(&self, response: &Message) -> Option<u32> {
+        if response.truncated() {
+            return None;
+        }
+        let ttl = match response.response_code() {
+            ResponseCode::NoError if !response.answers().is_empty() => {
+                response.answers().iter().chain(response.name_servers()).map(Record::ttl).min()?
+            }
+            ResponseCode::NoError | ResponseCode::NXDomain => response.name_servers().iter().find_map(|record| match record.data() {
+                Some(RData::SOA(soa)) => Some(record.ttl().min(soa.minimum())),
+                _ => None,
+            })?,
+            _ => return None,
+        };
+        Some(ttl.clamp(self.min_ttl, self.max_ttl))
+    }
+
+    // The cached answer with every TTL lowered by the time it has been cached, down to
+    // min_answer_ttl
+    pub(crate) fn get(&self, key: &(Vec<u8>, u16)) -> Option<Message> {
+        let answers = self.answers.lock().unwrap_or_else(|e| e.into_inner());
+        let cached = answers.get(key)?;
+        let age = u32::try_from(cached.stored.elapsed().as_secs()).unwrap_or(u32::MAX);
+        if age >= cached.ttl {
+            return None;
+        }
+        cached.hits.fetch_add(1, Ordering::Relaxed);
+        let mut response = cached.response.clone();
+        let age_records = |records: &mut Vec<Record>| {
+            for record in records {
+                record.set_ttl(record.ttl().saturating_sub(age).max(self.min_answer_ttl));
+            }
+        };
+        age_records(response.answers_mut());
+        age_records(response.name_servers_mut());
+        age_records(response.additionals_mut());
+        Some(response)
+    }
+
+    // Keep an upstream answer if it may be cached. When the cache is full, expired answers make
+    // room; if all are still live the new one isn't kept
+    pub(crate) fn insert(&self, key: (Vec<u8>, u16), response: &[u8]) {
+        let Ok(response) = Message::from_vec(response) else { return };
+        let Some(ttl) = self.ttl(&response).filter(|&ttl| ttl > 0) else { return };
+        let mut answers = self.answers.lock().unwrap_or_else(|e| e.into_inner());
+        if answers.len() >= self.max_entries && !answers.contains_key(&key) {
+            answers.retain(|_, cached| cached.stored.elapsed().as_secs() < u64::from(cached.ttl));
+            if answers.len() >= self.max_entries {
+                debug!("Upstream cache full with {} live answers, not caching", answers.len());
+                return;
+            }
+        }
+        answers.insert(key, CachedAnswer { response, stored: Instant::now(), ttl, hits: AtomicU64::new(0) });
+    }
+
+    // Every cached answer to a question whose name `dumped` picks, one entry per answer record
+    // (or one for a negative answer), with TTLs counted down
+    pub(crate) fn dump(&self, dumped: impl Fn(&Name) -> bool) -> Vec<DumpedEntry> {
+        let answers = self.answers.lock().unwrap_or_else(|e| e.into_inner());
+        let mut entries = Vec::new();
+        for cached in answers.values() {
+            let Some(query) = cached.response.queries().first().filter(|query| dumped(query.name())) else { continue };
+            let name = query.name().to_lowercase().to_string().trim_end_matches('.').to_string();
+            let age = cached.stored.elapsed().as_secs() as i64;
+            let hits = cached.hits.load(Ordering::Relaxed);
+            let entry = |record_type: String, value: String, ttl: u32| DumpedEntry {
+                name: name.clone(),
+                record_type,
+                value,
+                ttl: i64::from(ttl) - age,
+                hits,
+                source: "upstream".to_string(),
+            };
+            if cached.response.answers().is_empty() {
+                let value = if cached.response.response_code() == ResponseCode::NXDomain { "NXDOMAIN" } else { "NODATA" };
+                entries.push(entry(query.query_type().to_string(), value.to_string(), cached.ttl));
+            }
+            for record in cached.response.answers() {
+                let value = record.data().map(ToString::to_string).unwrap_or_default();
+                entries.push(entry(record.record_type().to_string(), value, record.ttl()));
+            }
+        }
+        entries
+    }
+
+    // Remove the answers to questions whose name `flushed` picks; returns how many went
+    pub(crate) fn remove_matching(&self, flushed: impl Fn(&Name) -> bool) -> usize {
+        let mut answers = self.answers.lock().unwrap_or_else(|e| e.into_inner());
+        let before = answers.len();
+        answers.retain(|_, cached| !cached.response.queries().first().is_some_and(|query| flushed(query.name())));
+        before - answers.len()
+    }
+}
+
+// Relay a client's query through one upstream server and return its answer under the client's
+// ID. Plain UDP queries go out under a fresh random ID, and only the answer to that ID and
+// question is accepted. Err when the server doesn't answer in time or the exchange fails, so
+// the caller can move on to the next server.
+pub(crate) async fn forward_to(
+    upstream: &Upstream,
+    upstream_request: &Message,
+    src: SocketAddr,
+    payload_size: u16,
+    config: &Config,
+    ctx: &LookupContext<'_>,
+    upstream_socket: &UdpSocket,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if let Some(encrypted) = &upstream.encrypted {
+        let exchange = tokio::time::timeout(ctx.upstream_timeout, encrypted.exchange(&upstream_request.to_vec()?, ctx.upstream_timeout)).await;
+        let Ok(upstream_response) = exchange else {
+            ctx.upstream_timed_out(&format!("query from {} to {}", src, upstream.name));
+            return Err("timed out".into());
+        };
+        return Ok(fit_payload(upstream_response?, payload_size, &ctx.buffers)?);
+    }
+
+    // 0x20 only matters over plain UDP; encrypted transports can't be spoofed off-path
+    let queries = upstream_request.queries().to_vec();
+    let mut upstream_request = upstream_request.clone();
+    if ctx.upstream_0x20 {
+        randomize_case(&mut upstream_request);
+    }
+    let upstream_id = register_upstream_query(ctx, &upstream_request);
+    let _outstanding = OutstandingQuery { outstanding: &ctx.outstanding, id: upstream_id };
+    upstream_request.set_id(upstream_id);
+    let forwarded = upstream_request.to_vec()?;
+    // Each query goes out from its own ephemeral port when possible
+    let own_socket = bind_upstream_socket(ctx, upstream.addr).await;
+    // Queries on the shared socket take turns, or they would read each other's answers
+    let _shared_turn = match own_socket {
+        Some(_) => None,
+        None => Some(ctx.shared_upstream.lock().await),
+    };
+    let upstream_socket = own_socket.as_ref().map_or(upstream_socket, |own| &own.socket);
+    let received = match upstream_socket.send_to(&forwarded, upstream.addr).await {
+        Ok(_) => {
+            info!("Forwarded query to upstream DNS: {}", upstream.name);
+            tokio::time::timeout(ctx.upstream_timeout, receive_upstream(upstream_socket, ctx, upstream.addr, upstream_id)).await
+        }
+        Err(e) => Ok(Err(e.into())),
+    };
+    let (pending, mut upstream_response) = match received {
+        Ok(received) => received?,
+        Err(_) => {
+            ctx.upstream_timed_out(&format!("query from {} to {}", src, upstream.name));
+            return Err("timed out".into());
+        }
+    };
+
+    // An answer that doesn't echo our mixed-case name may be spoofed: drop it and ask over TCP.
+    // A truncated answer is fetched again over TCP as well. Either way the TCP answer is cut down
+    // again if a UDP client can't take all of it
+    let truncated = Message::from_vec(&upstream_response).is_ok_and(|m| m.truncated());
+    if ctx.upstream_0x20 && !Message::from_vec(&upstream_response).is_ok_and(|m| echoes_case(&upstream_request, &m)) {
+        warn!("Upstream answer for {} did not echo the query name case, retrying over TCP", src);
+        upstream_response = fit_payload(upstream.tcp.exchange(&forwarded, ctx.upstream_timeout).await?, payload_size, &ctx.buffers)?;
+    } else if truncated && config.upstream_tcp_retry {
+        info!("Upstream response for {} was truncated, retrying over TCP", src);
+        match upstream.tcp.exchange(&forwarded, ctx.upstream_timeout).await {
+            Ok(full) => upstream_response = full,
+            Err(e) => warn!("TCP retry to upstream DNS failed: {}", e),
+        }
+        upstream_response = fit_payload(upstream_response, payload_size, &ctx.buffers)?;
+    }
+    if ctx.upstream_0x20 {
+        if let Ok(mut restored) = Message::from_vec(&upstream_response) {
+            restore_case(&mut restored, &queries);
+            ctx.buffers.give(std::mem::replace(&mut upstream_response, ctx.buffers.encode(&restored)?));
+        }
+    }
+    if upstream_response.len() >= 2 {
+        upstream_response[..2].copy_from_slice(&pending.client_id.to_be_bytes());
+    }
+    Ok(upstream_response)
+}
@@ -0,0 +1,606 @@
+// End-to-end tests: a server on a free local port answering from records held in memory
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, TcpStream, UdpSocket};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use fusiondns::{Config, DataSource, DbError, DbFuture, DnsRecord, Prerequisite, Server, UpdateOperation};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::{json, Value};
+use trust_dns_proto::op::{Edns, Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns_proto::rr::dnssec::rdata::{DNSSECRData, NSEC, RRSIG};
+use trust_dns_proto::rr::dnssec::Algorithm;
+use trust_dns_proto::rr::rdata::A;
+use trust_dns_proto::rr::{DNSClass, Name, RData, Record, RecordType};
+
+// Records by lowercase name without the trailing dot
+#[derive(Default)]
+struct MemorySource {
+    records: Mutex<HashMap<String, Vec<DnsRecord>>>,
+}
+
+impl MemorySource {
+    fn with(self, name: &str, record_type: &str, value: &str) -> Self {
+        self.with_ttl(name, record_type, value, 300)
+    }
+
+    fn with_ttl(self, name: &str, record_type: &str, value: &str, ttl: u32) -> Self {
+        self.records.lock().unwrap().entry(name.to_string()).or_default().push(DnsRecord::new(record_type, value, ttl));
+        self
+    }
+}
+
+impl DataSource for MemorySource {
+    fn lookup<'a>(&'a self, name: &'a str, _record_type: Option<&'a str>) -> DbFuture<'a, Vec<DnsRecord>> {
+        Box::pin(async move { Ok(self.records.lock().unwrap().get(name).cloned().unwrap_or_default()) })
+    }
+
+    fn update<'a>(&'a self, prerequisites: &'a [Prerequisite], operations: &'a [UpdateOperation]) -> DbFuture<'a, Vec<String>> {
+        Box::pin(async move {
+            let mut records = self.records.lock().unwrap();
+            for (index, prerequisite) in prerequisites.iter().enumerate() {
+                if !prerequisite.holds(records.get(prerequisite.name()).map(Vec::as_slice).unwrap_or_default()) {
+                    return Err(DbError::Prerequisite(index));
+                }
+            }
+            let mut touched = Vec::new();
+            for operation in operations {
+                match operation {
+                    UpdateOperation::Add { name, record_type, value } => {
+                        records.entry(name.clone()).or_default().push(DnsRecord::new(record_type, value, 300));
+                        touched.push(name.clone());
+                    }
+                    UpdateOperation::Delete { name, .. } => {
+                        records.remove(name);
+                        touched.push(name.clone());
+                    }
+                }
+            }
+            Ok(touched)
+        })
+    }
+}
+
+// A MemorySource that takes `delay` to look up names starting with "slow"
+struct SlowSource {
+    records: MemorySource,
+    delay: Duration,
+}
+
+impl DataSource for SlowSource {
+    fn lookup<'a>(&'a self, name: &'a str, record_type: Option<&'a str>) -> DbFuture<'a, Vec<DnsRecord>> {
+        Box::pin(async move {
+            if name.starts_with("slow") {
+                tokio::time::sleep(self.delay).await;
+            }
+            self.records.lookup(name, record_type).await
+        })
+    }
+}
+
+// A MemorySource whose lookups fail the way the name says: names starting with "down" can't
+// reach the database, "broken" ones hit a query error and "stuck" ones never get an answer.
+// While `down` is set, every lookup fails to connect
+#[derive(Default)]
+struct FailingSource {
+    records: MemorySource,
+    down: Arc<AtomicBool>,
+}
+
+impl DataSource for FailingSource {
+    fn lookup<'a>(&'a self, name: &'a str, record_type: Option<&'a str>) -> DbFuture<'a, Vec<DnsRecord>> {
+        Box::pin(async move {
+            if name.starts_with("down") || self.down.load(Ordering::Relaxed) {
+                Err(DbError::Connection("connection refused".to_string()))
+            } else if name.starts_with("broken") {
+                Err(DbError::Query("syntax error".to_string()))
+            } else if name.starts_with("stuck") {
+                std::future::pending().await
+            } else {
+                self.records.lookup(name, record_type).await
+            }
+        })
+    }
+}
+
+// A port free for both UDP and TCP on 127.0.0.1
+fn free_port() -> u16 {
+    loop {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = udp.local_addr().unwrap().port();
+        if std::net::TcpListener::bind(("127.0.0.1", port)).is_ok() {
+            return port;
+        }
+    }
+}
+
+// Serve `source` with `settings` over a memory cache and one worker, on a thread and runtime of
+// its own; returns the address once it answers
+fn start_server(source: impl DataSource + 'static, settings: Value) -> SocketAddr {
+    let port = free_port();
+    let mut config = json!({
+        "log_level": "off",
+        "upstream_dns": "127.0.0.1:9",
+        "bind_address": "127.0.0.1",
+        "port": port,
+        "cache_backend": "memory",
+        "workers": 1,
+    });
+    for (key, value) in settings.as_object().unwrap() {
+        config[key] = value.clone();
+    }
+    let config: Config = serde_json::from_value(config).unwrap();
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        let cache_file = std::env::temp_dir().join(format!("fusiondns-test-{}.json", port));
+        let server = Server::with_source(config, cache_file.to_str().unwrap(), Box::new(source)).unwrap();
+        runtime.block_on(server.run()).unwrap();
+    });
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    // A query without a question is answered with FORMERR right away
+    let probe = Message::new();
+    for _ in 0..50 {
+        if try_ask(addr, &probe, Duration::from_millis(100)).is_some() {
+            return addr;
+        }
+    }
+    panic!("server on {} didn't start", addr);
+}
+
+// A stand-in upstream server on a free port, on a thread of its own, answering each query with
+// what `answer` makes of it; None leaves the query unanswered
+fn start_upstream(answer: impl Fn(&Message) -> Option<Message> + Send + 'static) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; 65535];
+        loop {
+            let Ok((len, src)) = socket.recv_from(&mut buffer) else { continue };
+            let Ok(request) = Message::from_vec(&buffer[..len]) else { continue };
+            if let Some(response) = answer(&request) {
+                let _ = socket.send_to(&response.to_vec().unwrap(), src);
+            }
+        }
+    });
+    addr
+}
+
+// The start of an upstream answer to `request`: its ID, flags and question
+fn reply_to(request: &Message) -> Message {
+    let mut response = Message::new();
+    response.set_id(request.id());
+    response.set_message_type(MessageType::Response);
+    response.set_recursion_desired(request.recursion_desired());
+    response.set_recursion_available(true);
+    response.add_queries(request.queries().iter().cloned());
+    response
+}
+
+// An address for every name asked about
+fn answer_with_address(request: &Message) -> Option<Message> {
+    let mut response = reply_to(request);
+    let name = request.queries().first()?.name().clone();
+    response.add_answer(Record::from_rdata(name, 300, RData::A(A(Ipv4Addr::new(198, 51, 100, 1)))));
+    Some(response)
+}
+
+// A recursive query for one name and type
+fn query(name: &str, record_type: RecordType) -> Message {
+    let mut message = Message::new();
+    message.set_id(rand::random());
+    message.set_recursion_desired(true);
+    message.add_query(Query::query(Name::from_str(name).unwrap(), record_type));
+    message
+}
+
+fn try_ask(server: SocketAddr, request: &Message, timeout: Duration) -> Option<Message> {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(timeout)).unwrap();
+    socket.send_to(&request.to_vec().unwrap(), server).unwrap();
+    let mut buffer = [0u8; 65535];
+    let len = socket.recv(&mut buffer).ok()?;
+    Some(Message::from_vec(&buffer[..len]).unwrap())
+}
+
+// The raw response to `request` over UDP
+fn ask_wire(server: SocketAddr, request: &[u8]) -> Vec<u8> {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    socket.send_to(request, server).unwrap();
+    let mut buffer = [0u8; 65535];
+    let len = socket.recv(&mut buffer).expect("no response");
+    buffer[..len].to_vec()
+}
+
+// The response to `request` over UDP
+fn ask(server: SocketAddr, request: &Message) -> Message {
+    try_ask(server, request, Duration::from_secs(5)).expect("no response")
+}
+
+// The same over TCP, with the two-byte length in front of each message
+fn ask_tcp(server: SocketAddr, request: &Message) -> Message {
+    use std::io::{Read, Write};
+    let mut stream = TcpStream::connect(server).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let bytes = request.to_vec().unwrap();
+    stream.write_all(&(bytes.len() as u16).to_be_bytes()).unwrap();
+    stream.write_all(&bytes).unwrap();
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).unwrap();
+    let mut response = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut response).unwrap();
+    Message::from_vec(&response).unwrap()
+}
+
+// The answer records as "name TYPE data", in order
+fn answers(response: &Message) -> Vec<String> {
+    response.answers().iter().map(describe).collect()
+}
+
+fn describe(record: &Record) -> String {
+    let data = record.data().map(|data| data.to_string()).unwrap_or_default();
+    format!("{} {} {}", record.name(), record.record_type(), data)
+}
+
+#[test]
+fn cached_cname_and_target_are_answered_once() {
+    let source = MemorySource::default().with("www.test", "CNAME", "host.test").with("host.test", "A", "192.0.2.1");
+    let server = start_server(source, json!({ "multiple_questions": "answer" }));
+
+    // Both names are cached first; then the alias chain and the target each bring the target's
+    // address, which is answered once
+    ask(server, &query("www.test.", RecordType::A));
+    ask(server, &query("host.test.", RecordType::A));
+    let mut request = query("www.test.", RecordType::A);
+    request.add_query(Query::query(Name::from_str("host.test.").unwrap(), RecordType::A));
+    assert_eq!(answers(&ask(server, &request)), vec!["www.test. CNAME host.test.", "host.test. A 192.0.2.1"]);
+}
+
+// An update of zone test. adding an address to host.test, with one prerequisite on host.test
+fn address_update(prerequisite_class: DNSClass, prerequisite_type: RecordType) -> Message {
+    let host = Name::from_str("host.test.").unwrap();
+    let mut message = Message::new();
+    message.set_id(rand::random());
+    message.set_op_code(OpCode::Update);
+    message.add_query(Query::query(Name::from_str("test.").unwrap(), RecordType::SOA));
+    let mut prerequisite = Record::with(host.clone(), prerequisite_type, 0);
+    prerequisite.set_dns_class(prerequisite_class);
+    message.add_answer(prerequisite);
+    message.add_name_server(Record::from_rdata(host, 300, RData::A(A(Ipv4Addr::new(192, 0, 2, 2)))));
+    message
+}
+
+#[test]
+fn update_applies_only_when_its_prerequisites_hold() {
+    let source = MemorySource::default().with("host.test", "A", "192.0.2.1");
+    let settings = json!({
+        "zones": [{ "origin": "test", "mname": "ns.test", "rname": "hostmaster.test" }],
+        "update_allowed": ["127.0.0.1"],
+    });
+    let server = start_server(source, settings);
+    assert_eq!(answers(&ask(server, &query("host.test.", RecordType::A))), vec!["host.test. A 192.0.2.1"]);
+
+    // "host.test must not exist" fails, and nothing changes
+    let response = ask(server, &address_update(DNSClass::NONE, RecordType::ANY));
+    assert_eq!(response.response_code(), ResponseCode::YXDomain);
+    assert_eq!(answers(&ask(server, &query("host.test.", RecordType::A))), vec!["host.test. A 192.0.2.1"]);
+
+    // "host.test has addresses" holds, and the new address is answered at once
+    let response = ask(server, &address_update(DNSClass::ANY, RecordType::A));
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert_eq!(answers(&ask(server, &query("host.test.", RecordType::A))), vec!["host.test. A 192.0.2.1", "host.test. A 192.0.2.2"]);
+}
+
+#[test]
+fn authoritative_bit_is_set_only_on_local_answers_in_authoritative_zones() {
+    let upstream = start_upstream(answer_with_address);
+    let source = MemorySource::default().with("host.test", "A", "192.0.2.1").with("host.other", "A", "192.0.2.2");
+    let server = start_server(source, json!({ "upstream_dns": upstream.to_string(), "authoritative_zones": ["test"] }));
+
+    assert!(ask(server, &query("host.test.", RecordType::A)).authoritative());
+    assert!(!ask(server, &query("host.other.", RecordType::A)).authoritative());
+    // Forwarded, then answered from the upstream cache
+    for _ in 0..2 {
+        let response = ask(server, &query("www.example.", RecordType::A));
+        assert_eq!(answers(&response), vec!["www.example. A 198.51.100.1"]);
+        assert!(!response.authoritative());
+    }
+}
+
+#[test]
+fn question_is_echoed_as_asked() {
+    let upstream = start_upstream(answer_with_address);
+    let source = MemorySource::default().with("host.test", "A", "192.0.2.1");
+    let server = start_server(source, json!({ "upstream_dns": upstream.to_string(), "authoritative_zones": ["test"] }));
+
+    // Answered locally, forwarded, and NXDOMAIN; the question goes back byte for byte, case included
+    for name in ["HoSt.TeSt.", "WwW.ExAmPlE.", "MiSsInG.tEsT."] {
+        let request = query(name, RecordType::A).to_vec().unwrap();
+        let response = ask_wire(server, &request);
+        assert_eq!(&response[4..6], &[0, 1], "question count for {}", name);
+        assert_eq!(&response[12..request.len()], &request[12..], "question for {}", name);
+    }
+    let response = ask(server, &query("MiSsInG.tEsT.", RecordType::A));
+    assert_eq!(response.response_code(), ResponseCode::NXDomain);
+}
+
+// RD, CD, RA and AD of a response, in that order
+fn flags(response: &Message) -> [bool; 4] {
+    [response.recursion_desired(), response.checking_disabled(), response.recursion_available(), response.authentic_data()]
+}
+
+#[test]
+fn header_bits_follow_the_request() {
+    // The upstream server echoes RD and CD, so forwarded answers show what reached it
+    let upstream = start_upstream(|request| {
+        let mut response = answer_with_address(request)?;
+        response.set_checking_disabled(request.checking_disabled());
+        Some(response)
+    });
+    let source = MemorySource::default().with("host.test", "A", "192.0.2.1");
+    let server = start_server(source, json!({ "upstream_dns": upstream.to_string() }));
+
+    // Answered locally, forwarded, and then from the upstream cache, each with RD and CD set,
+    // cleared and mixed; RA is always set and AD never, since nothing is validated
+    for name in ["host.test.", "www.example.", "www.example."] {
+        for (recursion_desired, checking_disabled) in [(true, false), (false, true), (false, false), (true, true)] {
+            let mut request = query(name, RecordType::A);
+            request.set_recursion_desired(recursion_desired);
+            request.set_checking_disabled(checking_disabled);
+            request.set_authentic_data(true);
+            let response = ask(server, &request);
+            assert_eq!(answers(&response).len(), 1, "{}", name);
+            assert_eq!(flags(&response), [recursion_desired, checking_disabled, true, false], "{} RD {} CD {}", name, recursion_desired, checking_disabled);
+        }
+    }
+}
+
+// The signed upstream answer for www.example. A: the address with its RRSIG in the answer, and an
+// NSEC record with its RRSIG in the authority section
+fn signed_answer(request: &Message) -> Message {
+    let name = Name::from_str("www.example.").unwrap();
+    let signature = |type_covered| {
+        let signer = Name::from_str("example.").unwrap();
+        RRSIG::new(type_covered, Algorithm::ECDSAP256SHA256, 2, 300, 2_000_000_000, 1_700_000_000, 12345, signer, vec![7; 64])
+    };
+    let mut response = reply_to(request);
+    response.add_answer(Record::from_rdata(name.clone(), 300, RData::A(A(Ipv4Addr::new(198, 51, 100, 1)))));
+    response.add_answer(Record::from_rdata(name.clone(), 300, RData::DNSSEC(DNSSECRData::RRSIG(signature(RecordType::A)))));
+    let nsec = NSEC::new(Name::from_str("xyz.example.").unwrap(), vec![RecordType::A, RecordType::RRSIG, RecordType::NSEC]);
+    response.add_name_server(Record::from_rdata(name.clone(), 300, RData::DNSSEC(DNSSECRData::NSEC(nsec))));
+    response.add_name_server(Record::from_rdata(name, 300, RData::DNSSEC(DNSSECRData::RRSIG(signature(RecordType::NSEC)))));
+    let mut edns = Edns::new();
+    edns.set_dnssec_ok(true);
+    response.set_edns(edns);
+    response
+}
+
+// The record types and data of a section, in order
+fn record_data(records: &[Record]) -> Vec<(RecordType, Option<RData>)> {
+    records.iter().map(|record| (record.record_type(), record.data().cloned())).collect()
+}
+
+#[test]
+fn dnssec_records_are_relayed_to_clients_that_ask_for_them() {
+    // Signatures only go to a query with DO, and CD reaches the upstream server too
+    let upstream = start_upstream(|request| {
+        let dnssec_ok = request.extensions().as_ref().is_some_and(|edns| edns.dnssec_ok());
+        match dnssec_ok && request.checking_disabled() {
+            true => Some(signed_answer(request)),
+            false => answer_with_address(request),
+        }
+    });
+    let server = start_server(MemorySource::default(), json!({ "upstream_dns": upstream.to_string() }));
+
+    let mut request = query("www.example.", RecordType::A);
+    request.set_checking_disabled(true);
+    let mut edns = Edns::new();
+    edns.set_dnssec_ok(true);
+    request.set_edns(edns);
+    let expected = signed_answer(&request);
+    // Forwarded, then answered from the upstream cache
+    for _ in 0..2 {
+        let response = ask(server, &request);
+        assert_eq!(record_data(response.answers()), record_data(expected.answers()));
+        assert_eq!(record_data(response.name_servers()), record_data(expected.name_servers()));
+        assert!(response.extensions().as_ref().is_some_and(|edns| edns.dnssec_ok()));
+    }
+
+    // Without DO, the same question gets the plain answer
+    let response = ask(server, &query("www.example.", RecordType::A));
+    assert_eq!(answers(&response), vec!["www.example. A 198.51.100.1"]);
+    assert!(response.name_servers().is_empty());
+}
+
+#[test]
+fn random_bytes_get_formerr_and_the_server_keeps_answering() {
+    let server = start_server(MemorySource::default().with("host.test", "A", "192.0.2.1"), json!({}));
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    let mut random = StdRng::seed_from_u64(47);
+    let mut buffer = [0u8; 65535];
+    for _ in 0..200 {
+        let mut packet = vec![0u8; random.gen_range(12..600)];
+        random.fill(&mut packet[..]);
+        // Queries, not responses, which are dropped
+        packet[2] &= 0x7f;
+        if Message::from_vec(&packet).is_ok() {
+            continue;
+        }
+        socket.send_to(&packet, server).unwrap();
+        let len = socket.recv(&mut buffer).expect("no FORMERR");
+        let response = Message::from_vec(&buffer[..len]).unwrap();
+        assert_eq!(response.id(), u16::from_be_bytes([packet[0], packet[1]]));
+        assert_eq!(response.response_code(), ResponseCode::FormErr);
+    }
+
+    // Too short for a header, or a response: dropped without an answer
+    for packet in [vec![0x12], vec![0x12, 0x34, 0x01, 0x00, 0x00], vec![0xab; 12]] {
+        socket.send_to(&packet, server).unwrap();
+        assert!(socket.recv(&mut buffer).is_err(), "answered {:?}", packet);
+    }
+    assert_eq!(answers(&ask(server, &query("host.test.", RecordType::A))), vec!["host.test. A 192.0.2.1"]);
+}
+
+#[test]
+fn cname_is_followed_for_addresses_and_answered_alone_for_cname_first_and_cached() {
+    let source = MemorySource::default()
+        .with("a.test", "CNAME", "host.test")
+        .with("b.test", "CNAME", "host.test")
+        .with("c.test", "CNAME", "host.test")
+        .with("host.test", "A", "192.0.2.1")
+        .with("host.test", "AAAA", "2001:db8::1");
+    let server = start_server(source, json!({}));
+
+    // Each alias is asked twice for one type: from the database first, then from the cache
+    for _ in 0..2 {
+        assert_eq!(answers(&ask(server, &query("a.test.", RecordType::A))), vec!["a.test. CNAME host.test.", "host.test. A 192.0.2.1"]);
+        assert_eq!(answers(&ask(server, &query("b.test.", RecordType::AAAA))), vec!["b.test. CNAME host.test.", "host.test. AAAA 2001:db8::1"]);
+        assert_eq!(answers(&ask(server, &query("c.test.", RecordType::CNAME))), vec!["c.test. CNAME host.test."]);
+    }
+}
+
+#[test]
+fn fast_query_is_answered_while_a_slow_one_waits_on_the_database() {
+    let records = MemorySource::default().with("slow.test", "A", "192.0.2.1").with("fast.test", "A", "192.0.2.2");
+    let server = start_server(SlowSource { records, delay: Duration::from_millis(300) }, json!({}));
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    client.send_to(&query("slow.test.", RecordType::A).to_vec().unwrap(), server).unwrap();
+    client.send_to(&query("fast.test.", RecordType::A).to_vec().unwrap(), server).unwrap();
+    let mut buffer = [0u8; 4096];
+    let mut arrived = Vec::new();
+    for _ in 0..2 {
+        let len = client.recv(&mut buffer).unwrap();
+        arrived.extend(answers(&Message::from_vec(&buffer[..len]).unwrap()));
+    }
+    assert_eq!(arrived, vec!["fast.test. A 192.0.2.2", "slow.test. A 192.0.2.1"]);
+}
+
+
+#[test]
+fn database_and_upstream_failures_are_answered_and_the_server_keeps_serving() {
+    let upstream = start_upstream(|request| {
+        let name = request.queries().first()?.name().to_string();
+        // Names starting with "silent" are never answered upstream
+        if name.starts_with("silent") {
+            return None;
+        }
+        answer_with_address(request)
+    });
+    let server = start_server(FailingSource::default(), json!({ "upstream_dns": upstream.to_string(), "db_timeout_ms": 200, "upstream_timeout_ms": 300 }));
+
+    // Without the database, names without a cached answer go upstream
+    for name in ["down.test.", "broken.test.", "stuck.test."] {
+        assert_eq!(answers(&ask(server, &query(name, RecordType::A))), vec![format!("{} A 198.51.100.1", name)], "{}", name);
+    }
+
+    // An upstream that doesn't answer gives SERVFAIL
+    let response = ask(server, &query("silent.test.", RecordType::A));
+    assert_eq!(response.response_code(), ResponseCode::ServFail);
+
+    // A TCP client that resets its connection halfway through a query
+    let mut client = TcpStream::connect(server).unwrap();
+    std::io::Write::write_all(&mut client, &[0, 40, 0x12]).unwrap();
+    socket2::SockRef::from(&client).set_linger(Some(Duration::ZERO)).unwrap();
+    drop(client);
+
+    assert_eq!(answers(&ask_tcp(server, &query("after.test.", RecordType::A))), vec!["after.test. A 198.51.100.1"]);
+    assert_eq!(answers(&ask(server, &query("after.test.", RecordType::A))), vec!["after.test. A 198.51.100.1"]);
+}
+
+#[test]
+fn a_and_txt_overrides_of_one_name_are_cached_side_by_side() {
+    let source = MemorySource::default().with("both.test", "A", "192.0.2.1").with("both.test", "TXT", "hello");
+    let server = start_server(source, json!({}));
+
+    // Whichever type is asked first, the other keeps its own answer, from the database and then the cache
+    for _ in 0..2 {
+        assert_eq!(answers(&ask(server, &query("both.test.", RecordType::A))), vec!["both.test. A 192.0.2.1"]);
+        assert_eq!(answers(&ask(server, &query("both.test.", RecordType::TXT))), vec!["both.test. TXT hello"]);
+    }
+    assert_eq!(answers(&ask(server, &query("both.test.", RecordType::A))), vec!["both.test. A 192.0.2.1"]);
+}
+
+#[test]
+fn cname_targets_are_never_resolved_upstream_after_a_database_failure() {
+    let upstream = start_upstream(answer_with_address);
+    let records = MemorySource::default()
+        .with("alias.test", "CNAME", "host.test")
+        .with_ttl("host.test", "A", "192.0.2.1", 1)
+        .with("other.test", "CNAME", "down.test");
+    let source = FailingSource { records, ..FailingSource::default() };
+    let down = source.down.clone();
+    let server = start_server(source, json!({ "upstream_dns": upstream.to_string() }));
+
+    // A target the database can't be asked about, with nothing cached, ends the chain
+    let response = ask(server, &query("other.test.", RecordType::A));
+    assert_eq!(response.response_code(), ResponseCode::ServFail);
+    assert_eq!(answers(&response), vec!["other.test. CNAME down.test."]);
+
+    // Once the target's entry has expired, an outage answers it from the expired entry
+    assert_eq!(answers(&ask(server, &query("alias.test.", RecordType::A))), vec!["alias.test. CNAME host.test.", "host.test. A 192.0.2.1"]);
+    std::thread::sleep(Duration::from_millis(2100));
+    down.store(true, Ordering::Relaxed);
+    let response = ask(server, &query("alias.test.", RecordType::A));
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert_eq!(answers(&response), vec!["alias.test. CNAME host.test.", "host.test. A 192.0.2.1"]);
+}
+
+#[test]
+fn answers_too_big_for_the_client_are_truncated_over_udp_only() {
+    let source = (1..=60).fold(MemorySource::default(), |source, last| source.with("many.test", "A", &format!("192.0.2.{}", last)));
+    let server = start_server(source, json!({}));
+
+    // Without EDNS the limit is 512 bytes: TC and no records, so the client asks again over TCP
+    let response = ask(server, &query("many.test.", RecordType::A));
+    assert!(response.truncated());
+    assert!(response.answers().is_empty());
+    assert_eq!(ask_tcp(server, &query("many.test.", RecordType::A)).answers().len(), 60);
+
+    // With a 4096 byte EDNS payload every record fits
+    let mut request = query("many.test.", RecordType::A);
+    let mut edns = Edns::new();
+    edns.set_max_payload(4096);
+    request.set_edns(edns);
+    let response = ask(server, &request);
+    assert!(!response.truncated());
+    assert_eq!(response.answers().len(), 60);
+}
+
+#[test]
+fn truncated_upstream_answers_pass_through_when_tcp_retry_is_off() {
+    // The stand-in upstream has no TCP listener, so a retry over TCP could only fail
+    let upstream = start_upstream(|request| {
+        let mut response = reply_to(request);
+        response.set_truncated(true);
+        Some(response)
+    });
+    for validation in [false, true] {
+        let settings = json!({ "upstream_dns": upstream.to_string(), "upstream_tcp_retry": false, "dnssec_validation": validation });
+        let server = start_server(MemorySource::default(), settings);
+        let response = ask(server, &query("large.example.", RecordType::TXT));
+        assert_eq!(response.response_code(), ResponseCode::NoError, "dnssec_validation {}", validation);
+        assert!(response.truncated(), "dnssec_validation {}", validation);
+    }
+}
+
+#[test]
+fn transfers_of_zones_that_arent_configured_are_refused() {
+    let server = start_server(MemorySource::default().with("host.test", "A", "192.0.2.1"), json!({}));
+    let response = ask_tcp(server, &query("test.", RecordType::AXFR));
+    assert_eq!(response.response_code(), ResponseCode::Refused);
+    assert!(response.answers().is_empty());
+}
+
+#[test]
+fn servers_without_a_data_source_name_what_is_missing() {
+    let config = || serde_json::from_value::<Config>(json!({ "log_level": "off", "upstream_dns": "127.0.0.1:9", "bind_address": "127.0.0.1", "port": 0, "sql_query": "SELECT 1" })).unwrap();
+    let error = |server: Result<Server, Box<dyn std::error::Error>>| server.err().map(|e| e.to_string());
+    assert_eq!(error(Server::new(config(), "unused.json")).as_deref(), Some("db_settings needs at least one database"));
+    assert_eq!(error(Server::with_sources(config(), "unused.json", Vec::new())).as_deref(), Some("with_sources needs at least one data source in sources"));
+}